@@ -0,0 +1,65 @@
+#![allow(mixed_script_confusables)]
+
+// Overlapping Allan deviation of a captured signal.
+//
+// Usage: adev <capture> <frequency> [--rate=<samples per sec>]
+//
+// The capture is the same byte stream as for mapband.  The signal at
+// <frequency> Hz is downshifted to zero, and its phase unwound, giving the
+// phase in cycles relative to the nominal frequency.  Dividing by the nominal
+// frequency gives the time error x(t), from which we compute σ_y(τ) for τ at
+// octave multiples of the phase sample interval.  Output is CSV, τ,σ_y(τ).
+
+use std::fs::File;
+use memmap2::Mmap;
+
+#[allow(unused)]
+mod util;
+use util::*;
+
+/// Overlapping Allan deviation at τ = m·τ₀, from time error samples `x` at
+/// interval τ₀.
+fn overlapping_adev(x: &[f64], tau0: f64, m: usize) -> f64 {
+    let n = x.len() - 2 * m;
+    let total: f64 = (0 .. n).map(|i| {
+        let d = x[i + 2 * m] - 2.0 * x[i + m] + x[i];
+        d * d
+    }).sum();
+    let τ = m as f64 * tau0;
+    (total / (2.0 * τ * τ * n as f64)).sqrt()
+}
+
+pub fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+    let rate = take_rate_arg(&mut args);
+
+    let file = File::open(&args[1]).unwrap();
+    let frequency: f64 = args[2].parse().unwrap();
+
+    let mmap = unsafe {Mmap::map(&file).unwrap()};
+    let len = mmap.len();
+    let Some(usable) = len.checked_sub(13_000_000).filter(|&u| u > 0) else {
+        eprintln!("Capture too short: {len} bytes, need over 13000000");
+        std::process::exit(1);
+    };
+    let nice = be_nice_to_fft(usable);
+    eprintln!("FFT nice : {nice} / {len} (lost {})", len - nice);
+    let bytes = &mmap[len - nice ..];
+
+    let mut data = downshift_bytes(bytes, frequency, rate);
+    fft_forward(&mut data);
+
+    let width = (data.len() as f64 * (10e3 / rate)) as usize;
+    let cycles = phase_unwind(&data, 0, width);
+
+    // The unwound phase spans the whole capture.
+    let tau0 = data.len() as f64 / rate / cycles.len() as f64;
+    let x: Vec<f64> = cycles.iter().map(|c| c / frequency).collect();
+    eprintln!("{} phase samples at τ₀ = {tau0}s", x.len());
+
+    let mut m = 1;
+    while 2 * m < x.len() {
+        println!("{},{}", m as f64 * tau0, overlapping_adev(&x, tau0, m));
+        m *= 2;
+    }
+}
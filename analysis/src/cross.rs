@@ -32,7 +32,8 @@ fn get_hilbert(path: &String) -> Vec<Complex64> {
 }
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let mut args: Vec<String> = std::env::args().collect();
+    let rate = take_rate_arg(&mut args);
 
     let p1 = args[1].clone();
     let p2 = args[2].clone();
@@ -48,5 +49,5 @@ fn main() {
     for (p, a) in data1.iter_mut().zip(data2.iter()) {
         *p *= a.conj();
     }
-    spectrum(&mut data1, 0.0);
+    spectrum(&mut data1, 0.0, rate, Format::Text);
 }
\ No newline at end of file
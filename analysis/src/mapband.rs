@@ -8,7 +8,9 @@ mod util;
 use util::*;
 
 pub fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let mut args: Vec<String> = std::env::args().collect();
+    let rate = take_rate_arg(&mut args);
+    let format = take_format_arg(&mut args);
 
     let file = File::open(&args[1]).unwrap();
 
@@ -19,7 +21,7 @@ pub fn main() {
     let bytes = &mmap[len - nice ..];
 
     if args.len() <= 2 {
-        println!("Freq est. {}", freq_estimate(bytes));
+        println!("Freq est. {}", freq_estimate(bytes, rate));
         return;
     }
 
@@ -29,10 +31,10 @@ pub fn main() {
         cycle_lengths(bytes);
     }
     else if false {
-        phases(bytes, frequency);
+        phases(bytes, frequency, rate);
     }
     else {
-        let mut data = downshift_bytes(bytes, frequency);
-        spectrum(&mut data, frequency);
+        let mut data = downshift_bytes(bytes, frequency, rate);
+        spectrum(&mut data, frequency, rate, format);
     }
 }
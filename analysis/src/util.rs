@@ -4,7 +4,21 @@ use rustfft::num_complex::{Complex64, c64};
 
 use std::f64::consts::PI;
 
-const SAMPLES_PER_SEC: f64 = 100e6;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// Default capture sample rate, in samples per second.
+pub const DEFAULT_SAMPLE_RATE: f64 = 100e6;
+
+/// Remove a `--rate=<samples per sec>` option from the command line arguments,
+/// returning the sample rate, or the default if not given.
+pub fn take_rate_arg(args: &mut Vec<String>) -> f64 {
+    let Some(i) = args.iter().position(|a| a.starts_with("--rate=")) else {
+        return DEFAULT_SAMPLE_RATE;
+    };
+    let arg = args.remove(i);
+    arg["--rate=".len() ..].parse().expect("Bad --rate")
+}
 
 pub fn fft_forward(data: &mut [Complex64]) {
     let mut planner = FftPlanner::new();
@@ -53,18 +67,48 @@ pub fn be_nice_to_fft(len: usize) -> usize {
     best
 }
 
-pub fn rotate(i: usize, b: f64, frequency: f64) -> Complex64 {
-    let cycles = i as f64 * frequency * (1.0 / SAMPLES_PER_SEC);
+/// Output format for `spectrum`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    /// The original ad-hoc comma separated lines.
+    Text,
+    /// CSV with a header row, just the frequency, mean and max columns.  The
+    /// fundamental and peak lines still go to stderr, unstructured, as in text
+    /// mode.
+    Csv,
+    /// JSON records, one per line, all on stdout.  The fundamental and peak
+    /// records come first, distinguished by their `fundamental` and `peak`
+    /// keys.
+    Json,
+}
+
+/// Remove a `--format=text|csv|json` option from the command line arguments,
+/// returning the format, or text if not given.
+pub fn take_format_arg(args: &mut Vec<String>) -> Format {
+    let Some(i) = args.iter().position(|a| a.starts_with("--format=")) else {
+        return Format::Text;
+    };
+    match &args.remove(i)["--format=".len() ..] {
+        "text" => Format::Text,
+        "csv"  => Format::Csv,
+        "json" => Format::Json,
+        f => panic!("Unknown format {f}"),
+    }
+}
+
+pub fn rotate(i: usize, b: f64, frequency: f64, rate: f64) -> Complex64 {
+    let cycles = i as f64 * frequency * (1.0 / rate);
     let θ = (cycles.round() - cycles) * (2.0 * PI);
     let (s, c) = θ.sin_cos();
     b * c64(c, -s)
 }
 
-pub fn downshift_bytes(bytes: &[u8], frequency: f64) -> Vec<Complex64> {
+pub fn downshift_bytes(bytes: &[u8], frequency: f64, rate: f64)
+                       -> Vec<Complex64> {
     let total: u64 = bytes.iter().map(|&x| x as u64).sum();
     let mean = total as f64 / bytes.len() as f64;
     bytes.iter().enumerate().map(
-        |(i, &b)| rotate(i, b as f64 - mean, frequency)).collect()
+        |(i, &b)| rotate(i, b as f64 - mean, frequency, rate)).collect()
 }
 
 pub fn complexify_bytes(bytes: &[u8]) -> Vec<Complex64> {
@@ -75,34 +119,65 @@ pub fn complexify_bytes(bytes: &[u8]) -> Vec<Complex64> {
 
 pub fn time_raised_cosine(data: &mut [Complex64]) {
     let ω = 2.0 * PI / data.len() as f64;
-    for (i, d) in data.iter_mut().enumerate() {
+    let window = |(i, d): (usize, &mut Complex64)|
         *d *= 1.0 - (ω * i as f64).cos();
-    }
+    #[cfg(feature = "parallel")]
+    data.par_iter_mut().enumerate().for_each(window);
+    #[cfg(not(feature = "parallel"))]
+    data.iter_mut().enumerate().for_each(window);
 }
 
 pub fn raised_cosine_ends(data: &mut [Complex64], half_width: usize) {
     let ω = PI / half_width as f64;
     let len = data.len();
     data[0] = 0.0f64.into();
-    for i in 1 .. half_width {
-        let scale = 0.5 - (ω * i as f64).cos() * 0.5;
-        data[i] *= scale;
-        data[len - i] *= scale;
+    if half_width == 0 {
+        return;
     }
+    // The head, data[1 .. half_width], and the tail, data[len - half_width + 1
+    // ..] in reverse, get the same scaling.
+    let (head, rest) = data[1 ..].split_at_mut(half_width - 1);
+    let tail = &mut rest[len - 2 * half_width + 1 ..];
+    let window = |(i, (h, t)): (usize, (&mut Complex64, &mut Complex64))| {
+        let scale = 0.5 - (ω * (i + 1) as f64).cos() * 0.5;
+        *h *= scale;
+        *t *= scale;
+    };
+    #[cfg(feature = "parallel")]
+    head.par_iter_mut().zip(tail.par_iter_mut().rev()).enumerate()
+        .for_each(window);
+    #[cfg(not(feature = "parallel"))]
+    head.iter_mut().zip(tail.iter_mut().rev()).enumerate().for_each(window);
 }
 
 //pub fn spectrum(bytes: &[u8], frequency: f64) {
 //    let mut data = downshift_bytes(bytes, frequency);
-pub fn spectrum(data: &mut [Complex64], frequency: f64) {
+pub fn spectrum(data: &mut [Complex64], frequency: f64, rate: f64,
+                format: Format) {
     time_raised_cosine(data);
     fft_forward(data);
 
-    let scale = SAMPLES_PER_SEC / data.len() as f64;
+    let scale = rate / data.len() as f64;
     let height = data[0].norm_sqr();
-    println!("{},1,1,fundamental,{frequency},{height}", 0.5 * scale);
+    match format {
+        Format::Text =>
+            println!("{},1,1,fundamental,{frequency},{height}", 0.5 * scale),
+        Format::Csv => {
+            eprintln!("fundamental,{frequency},{height}");
+            println!("frequency,mean,max");
+        }
+        Format::Json => println!(
+            "{{\"fundamental\":{frequency},\"height\":{height}}}"),
+    }
     for i in 1..10 {
-        eprintln!("peak,{i},{},{}", data[i].norm_sqr() / height,
-                  data[data.len() - i].norm_sqr() / height);
+        let upper = data[i].norm_sqr() / height;
+        let lower = data[data.len() - i].norm_sqr() / height;
+        if format == Format::Json {
+            println!("{{\"peak\":{i},\"upper\":{upper},\"lower\":{lower}}}");
+        }
+        else {
+            eprintln!("peak,{i},{upper},{lower}");
+        }
     }
 
     let mut start = 1;
@@ -122,7 +197,12 @@ pub fn spectrum(data: &mut [Complex64], frequency: f64) {
         }
         let mean = total / (2 * (end - start)) as f64;
         let f = (start + end - 1) as f64 * 0.5 * scale;
-        println!("{f},{mean},{max}");
+        if format == Format::Json {
+            println!("{{\"frequency\":{f},\"mean\":{mean},\"max\":{max}}}");
+        }
+        else {
+            println!("{f},{mean},{max}");
+        }
         start = end;
     }
 }
@@ -179,18 +259,18 @@ pub fn phase_unwind(data: &[Complex64],
     result
 }
 
-pub fn phases(bytes: &[u8], frequency: f64) {
-    let mut data = downshift_bytes(bytes, frequency);
+pub fn phases(bytes: &[u8], frequency: f64, rate: f64) {
+    let mut data = downshift_bytes(bytes, frequency, rate);
 
     fft_forward(&mut data);
 
-    let width = (data.len() as f64 * (10e3 / SAMPLES_PER_SEC)) as usize;
+    let width = (data.len() as f64 * (10e3 / rate)) as usize;
     for cycles in phase_unwind(&data, 0, width) {
         println!("{cycles}");
     }
 }
 
-pub fn freq_estimate(bytes: &[u8]) -> f64 {
+pub fn freq_estimate(bytes: &[u8], rate: f64) -> f64 {
     let total: u64 = bytes.iter().map(|&x| x as u64).sum();
     let mean = total as f64 / bytes.len() as f64;
     let mut data: Vec::<Complex64> = bytes.iter().map(
@@ -209,7 +289,7 @@ pub fn freq_estimate(bytes: &[u8]) -> f64 {
         }
     }
 
-    let frequency = maxi as f64 * SAMPLES_PER_SEC / data.len() as f64;
+    let frequency = maxi as f64 * rate / data.len() as f64;
     eprintln!("Max at {maxi}, frequency {frequency}");
     let phases = phase_unwind(&data, maxi, 10000);
 
@@ -231,7 +311,7 @@ pub fn freq_estimate(bytes: &[u8]) -> f64 {
     let total_change = sigma_iθ / sigma_ii * phases.len() as f64;
     eprintln!("Total change {total_change}");
     let frequency = frequency
-        + total_change * SAMPLES_PER_SEC / data.len() as f64;
+        + total_change * rate / data.len() as f64;
 
     eprintln!("Adjusted frequency {frequency}");
 
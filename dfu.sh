@@ -14,5 +14,6 @@ cargo b
 OUT=./target/thumbv8m.main-none-eabihf/release
 
 arm-none-eabi-objcopy -O binary "$OUT"/freak.elf "$OUT"/freak.bin
+./py/seal_image.py "$OUT"/freak.bin
 
 sudo dfu-util -d 0483:df11 -a 0 -s 0x08000000:leave -R -D "$OUT"/freak.bin
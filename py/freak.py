@@ -106,6 +106,11 @@ def do_info(device: Device) -> None:
     pv = message.retrieve(dev, message.GET_PROTOCOL_VERSION)
     print('Protocol Vers :', struct.unpack('<I', pv.payload)[0])
 
+    try:
+        print('Build ID      :', message.get_build_id(dev))
+    except message.RequestFailed:
+        pass
+
 if len(sys.argv) < 2:
     argp.print_help()
     sys.exit(1)
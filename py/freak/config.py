@@ -102,38 +102,6 @@ CRC_EMPTY_CONFIG = 0xfe8baafc
 def test_crc_empty_config() -> None:
     assert CRC_EMPTY_CONFIG == crc32.crc32(b'\xff' * 2048)
 
-def config_is_empty(dev: USBDevice, h: Config) -> bool:
-    '''Check if a config is empty.  First check the header, if that's ok, CRC
-    the block, and then read the entire block.'''
-    E = 0xffffffff
-    return h.magic == E and h.version == E and \
-        h.generation == E and h.length == E \
-        and message.crc(dev, h.address, 2048) == CRC_EMPTY_CONFIG \
-        and memoryview(message.peek(dev, h.address, 2048)) == memoryview(b'\xff' * 2048)
-
-def next_header(dev: USBDevice, headers: Configs,
-                current: Config|None) -> Config:
-    '''Get the index of the next header to write.  Erase a flash sector if
-    necessary.'''
-    # Prefer entries in the same sector as the current config, if it is in the
-    # second bank.
-    if current is not None and current.address >= 0x0801e000:
-        scan = [12, 13, 14, 15, 8, 9, 10, 11]
-    else:
-        scan = list(range(8, 16))
-
-    test_crc_empty_config()
-    for i in scan:
-        h = headers[i]
-        if config_is_empty(dev, h):
-            return h
-
-    erase_base = headers[scan[4]]
-    assert erase_base.address in (0x0801c000, 0x0801e000)
-    #assert False, f'Would erase {erase_base.address:#010x}'
-    message.flash_erase(dev, erase_base.address)
-    return erase_base
-
 def compare_config(dev: USBDevice, h: Config, new: ByteString) -> bool:
     magic, version, _generation, length = struct.unpack('<IIII', new[:16])
     if magic != h.magic or version != h.version or length != h.length:
@@ -232,6 +200,12 @@ def parse_config(dev: USBDevice, h: Config | None) \
             assert ckB == msg[-1]
             yield 'UBX', msg
             continue
+        # Conditional section header is CE A6.  The section contents are
+        # returned as normal messages.
+        if first == 0xce and end - done >= 6 and data[done + 1] == 0xa6:
+            yield 'COND', data[done : done + 6]
+            done += 6
+            continue
         # Freak magic is CE 93
         assert first == 0xce, 'Unknown message'
         assert end - done >= 8
@@ -250,6 +224,8 @@ def parse_config(dev: USBDevice, h: Config | None) \
             yield 'UBX', msg
         elif msg[2] == message.GET_SET_NAME:
             yield 'NAME', msg
+        elif msg[2] == message.USB_SERIAL_SOURCE:
+            yield 'SERIAL', msg
         else:
             yield 'UNKNOWN', msg
 
@@ -263,6 +239,9 @@ def make_config(device: Device, headers: Configs, active: Config | None,
 
     config = bytearray(struct.pack('<IIII', MAGIC, VERSION, generation, 0))
 
+    assert not any(typ == 'COND' for typ, _ in parse_config(dev, active)), \
+        'Cannot preserve a config with conditional sections'
+
     if save_lmk:
         print('Add LMK05318b configuration.')
         add_live_lmk05318b(dev, config)
@@ -301,6 +280,10 @@ def make_config(device: Device, headers: Configs, active: Config | None,
     if name != '' and name != message.get_serial_number(dev):
         message.set_name(config, name)
 
+    # Save the USB serial number source if it is not the default.
+    if message.get_usb_serial_from_cpu(dev):
+        message.set_usb_serial_from_cpu(config, True)
+
     config[12:16] = struct.pack('<I', len(config) + 4)
     config += struct.pack('>I', crc32.crc32(config))
     assert crc32.crc32(config) == crc32.VERIFY_MAGIC
@@ -316,10 +299,8 @@ def make_config(device: Device, headers: Configs, active: Config | None,
     #print('Changed!')
     return config
 
-def write_config(dev: USBDevice, headers: Configs, active: Config | None,
-                 config: ByteString) -> None:
-    where = next_header(dev, headers, active)
-    message.poke(dev, where.address, config)
+def write_config(dev: USBDevice, config: ByteString) -> None:
+    message.config_upload(dev, config)
 
 def save_config(device: Device, save_ubx: bool, save_lmk: bool,
                 dry_run: bool = False) -> bool:
@@ -336,7 +317,7 @@ def save_config(device: Device, save_ubx: bool, save_lmk: bool,
         print('Dry run, not writing config.')
     else:
         print('Writing config to flash.')
-        write_config(dev, headers, active, cfg)
+        write_config(dev, cfg)
     return True
 
 def do_name(device: Device, name: str | None):
@@ -346,6 +327,25 @@ def do_name(device: Device, name: str | None):
     else:
         print(message.get_name(dev))
 
+def do_usb_serial(device: Device, source: str | None):
+    dev = device.get_usb()
+    if source:
+        message.set_usb_serial_from_cpu(dev, source == 'cpu')
+    else:
+        from_cpu = message.get_usb_serial_from_cpu(dev)
+        print('cpu' if from_cpu else 'name')
+
+def do_list(device: Device):
+    dev = device.get_usb()
+    for slot in message.config_list(dev):
+        address = ADDRESSES[slot.index]
+        if not slot.magic_ok:
+            print(f'{slot.index:2} {address:#010x} -')
+            continue
+        state = 'active' if slot.active else 'valid' if slot.valid else 'BAD'
+        print(f'{slot.index:2} {address:#010x} v{slot.version}'
+              f' gen {slot.generation} {state}')
+
 def do_clear(device: Device):
     dev = device.get_usb()
     print('Retrieving saved configuration state.')
@@ -361,7 +361,7 @@ def do_clear(device: Device):
     assert crc32.crc32(config) == crc32.VERIFY_MAGIC
     config += b'\xff' * (31 & -len(config))
     print('Writing config to flash')
-    write_config(dev, headers, active, config)
+    write_config(dev, config)
 
 def do_manufacture(device: Device, tics: str | None):
     import freak.lmk05318b_util as lmk05318b_util
@@ -417,6 +417,13 @@ def add_to_argparse(argp: argparse.ArgumentParser) -> None:
     name = subp.add_parser('name', help='Assign the device name')
     name.add_argument('NAME', nargs='?', help='Device name, or omit to print')
 
+    serial = subp.add_parser(
+        'usb-serial', help='Select the USB serial number source',
+        description='''Select whether the USB serial number is the device name
+        or the CPU serial number.  Save the config to make this persistent.''')
+    serial.add_argument('SOURCE', nargs='?', choices=('name', 'cpu'),
+                        help='Serial number source, or omit to print')
+
     man = subp.add_parser(
         'manufacture', help='Initial set-up of device',
         description='''This loads typical configurations for GPS and clock
@@ -424,6 +431,10 @@ def add_to_argparse(argp: argparse.ArgumentParser) -> None:
         'freak config save' to do that.''')
     man.add_argument('-t', '--tics', help='TICS .tcs file to base config on')
 
+    subp.add_parser('list', help='List the stored configs',
+                    description='''List the config slots in flash, with
+                    their version, generation and validity.''')
+
     subp.add_parser('clear', help='Save an empty device config',
                     description='''Save an empty device configuration.  Note
                     that old configs are hidden but not erased.  The running
@@ -436,6 +447,12 @@ def run_command(args: argparse.Namespace, device: Device, command: str) -> None:
     elif command == 'name':
         do_name(device, args.NAME)
 
+    elif command == 'usb-serial':
+        do_usb_serial(device, args.SOURCE)
+
+    elif command == 'list':
+        do_list(device)
+
     elif command == 'clear':
         do_clear(device)
 
@@ -29,3 +29,29 @@ def test_crc():
     data = b'This is a test string 123456789'
     data += struct.pack('>I', crc32(data))
     assert crc32(data) == VERIFY_MAGIC
+
+# Firmware image layout, see src/flash.rs.  The image is followed by its
+# big-endian CRC32, and the little-endian length of image plus CRC is stored
+# in the last word of the image area.
+IMAGE_MAX = 0xc000
+IMAGE_LENGTH = IMAGE_MAX - 4
+
+def seal_image(image: ByteString) -> bytes:
+    '''Append the CRC and length word to a raw firmware image, padding with
+    erased flash (0xff) in between.'''
+    length = len(image) + 4
+    if length > IMAGE_LENGTH:
+        raise ValueError(f'Image too large, {len(image)} bytes')
+    result = bytearray(image)
+    result += struct.pack('>I', crc32(image))
+    result += b'\xff' * (IMAGE_LENGTH - length)
+    result += struct.pack('<I', length)
+    return bytes(result)
+
+def test_seal_image():
+    data = b'Firmware image'
+    sealed = seal_image(data)
+    assert len(sealed) == IMAGE_MAX
+    length, = struct.unpack('<I', sealed[IMAGE_LENGTH:])
+    assert length == len(data) + 4
+    assert crc32(sealed[:length]) == VERIFY_MAGIC
@@ -16,32 +16,101 @@ ACK=0x80
 NACK=0x81
 
 PING=0x00
+PING_STRESS=0x01
 GET_PROTOCOL_VERSION=0x02
 GET_SERIAL_NUMBER=0x03
 GET_SET_NAME=0x04
+GET_RESET_CAUSE=0x05
+GET_BUILD_ID=0x06
+GET_BOARD_VARIANT=0x07
+GET_UPTIME=0x08
+GET_UNIQUE_ID=0x09
+GET_CLOCK_CONFIG=0x0a
+DMA_ERRORS=0x0b
+SELF_TEST=0x0c
+CRC_SELF_TEST=0x0d
+WATCHDOG_STATUS=0x0e
+LOG_LEVEL=0x0f
 
 CPU_REBOOT=0x10
 GPS_RESET=0x11
 LMK05318B_PDN=0x12
+TRIGGER_DFU=0x13
+HSI48=0x14
+TICK_RATE=0x15
+BOR_LEVEL=0x16
+LMK05318B_REAPPLY=0x17
+LED_TIMING=0x18
+GPIO_UPDATE=0x19
+LMK05318B_POLL_RATE=0x1a
 
 SERIAL_SYNC=0x1e
 GET_SET_BAUD=0x1f
 
+GPS_RX_ERRORS=0x20
+GPS_LINK_STATS=0x21
+GPS_LOOPBACK=0x22
+GPS_DMA_TUNING=0x23
+GPS_ACTUAL_BAUD=0x24
+UBX_POLL=0x25
+GPS_FRAMING=0x26
+GPS_FLUSH=0x27
+DTR_RESET=0x28
+HOST_BAUD=0x29
+
+USB_MODE=0x30
+USB_ERRATA_DELAY=0x31
+USB_STRING=0x32
+USB_SERIAL_SOURCE=0x33
+USB_SOF_STATS=0x34
+USB_SETUP_LOG=0x35
+USB_STALL=0x36
+USB_STRING_RAW=0x37
+
+DEFERRED_POLL=0x50
+DEFERRED_START=0x51
+
 LMK05318B_WRITE=0x60
 LMK05318B_READ=0x61
+LMK05318B_FREQUENCIES=0x67
 LMK05318B_STATUS=0x68
+LMK05318B_RAW_STATUS=0x69
+I2C_RECOVER=0x6a
+I2C_SPEED=0x6b
 
 TMP117_WRITE=0x62
 TMP117_READ=0x63
+TMP117_TEMPERATURE=0x66
+TMP117_READ_REGS=0x6c
+LMK05318B_NVM_READ=0x6d
+LMK05318B_NVM_COMMIT=0x6e
 
+CRC_CHUNK=0x70
 PEEK=0x71
 POKE=0x72
 GET_CRC=0x73
 FLASH_ERASE=0x74
+FILL=0x75
+CONFIG_UPLOAD=0x76
+CONFIG_LIST=0x77
+FLASH_ERASE_BANK=0x79
+BANK_CRC=0x7a
+BANK_SWAP=0x7b
+APPLIED_CONFIG=0x7c
+PEEK_UNCHECKED=0x7d
+POKE_UNCHECKED=0x7e
+REGISTER_ACCESS=0x7f
 
 class RequestFailed(RuntimeError):
     pass
 
+'''NACK error codes, matching the firmware Error enumeration.'''
+ERRORS = {1: 'Failed', 2: 'FramingError', 3: 'UnknownMessage',
+          4: 'BadFormat', 5: 'BadParameter', 7: 'I2cNack', 8: 'I2cArbLost',
+          9: 'I2cTimeout', 10: 'FlashNotErased', 11: 'FlashVerify',
+          12: 'FlashWriteProtect', 13: 'FlashSequence', 14: 'FlashStrobe',
+          15: 'FlashInconsistent', 16: 'GpsTimeout'}
+
 @dataclass
 class Message:
     # Magic is implicit.
@@ -105,6 +174,10 @@ def command(dev: Recipient, code: int, payload: ByteString,
     dev.write(0x03, frame(code, payload)) # pyright: ignore
     result = deframe(bytes(dev.read(0x83, 64, 10000))) # pyright: ignore
     if expect != NACK and result.code == NACK:
+        if len(result.payload) == 2:
+            code = struct.unpack('<H', result.payload)[0]
+            raise RequestFailed(
+                f'Result code is NACK {ERRORS.get(code, code)}')
         raise RequestFailed(f'Result code is NACK ' + result.payload.hex(' '))
     if result.code != expect:
         raise RequestFailed(f'Result code is {result.code:#04x}')
@@ -114,10 +187,46 @@ def retrieve(dev: Device, code: int, payload: bytes = b'') -> Message:
     return command(dev, code, payload, expect = code | 0x80)
 
 def ping(dev: Device, payload: bytes) -> bytes:
+    assert payload, 'An empty ping is a health probe, use ping_health'
     resp = retrieve(dev, PING, payload)
     assert resp.payload == payload
     return resp.payload
 
+def ping_health(dev: Device) -> tuple[int, int]:
+    '''Empty ping, returning the uptime in ms and the reset cause flags.'''
+    return struct.unpack('<QB', retrieve(dev, PING).payload)
+
+def stress_pattern(seed: int, length: int) -> bytes:
+    '''The byte sequence generated by PING_STRESS.'''
+    state = seed or 1
+    result = bytearray()
+    for _ in range(length):
+        state ^= (state << 13) & 0xffffffff
+        state ^= state >> 17
+        state ^= (state << 5) & 0xffffffff
+        result.append(state & 0xff)
+    return bytes(result)
+
+def test_stress_pattern() -> None:
+    assert stress_pattern(1, 8) == bytes.fromhex('2101c54fd1d01ab2')
+    assert stress_pattern(0, 8) == stress_pattern(1, 8)
+
+def ping_stress(dev: Device, seed: int, length: int) -> bytes:
+    '''Request a multi-packet pseudo-random PING response, and check it.'''
+    dev.write(0x03, frame(PING_STRESS, struct.pack('<II', seed, length)))
+    result = bytearray()
+    while True:
+        data = deframe(bytes(dev.read(0x83, 64, 10000)))
+        if data.code != ACK:
+            raise RequestFailed(f'Result code is {data.code:#04x}')
+        if len(data.payload) == 0:
+            break
+        result += data.payload
+    expect = stress_pattern(seed, length)
+    if result != expect:
+        raise RequestFailed('PING stress data mismatch')
+    return bytes(result)
+
 def get_protocol_version(dev: Device) -> int:
     data = retrieve(dev, GET_PROTOCOL_VERSION, b'')
     return struct.unpack('<I', data.payload)[0]
@@ -126,14 +235,103 @@ def get_serial_number(dev: Device) -> str:
     b = retrieve(dev, GET_SERIAL_NUMBER, b'').payload
     return b.decode(errors='replace')
 
+def get_build_id(dev: Device) -> str:
+    b = retrieve(dev, GET_BUILD_ID, b'').payload
+    return b.rstrip(b'\0').decode(errors='replace')
+
 def get_name(dev: Device) -> str:
     return retrieve(dev, GET_SET_NAME, b'').payload.decode(errors='replace')
 
 def set_name(dev: Recipient, name: str) -> None:
     command(dev, GET_SET_NAME, bytes(name, 'UTF-8'), GET_SET_NAME | 0x80)
 
-def serial_sync(dev: Recipient, microseconds: int) -> None:
-    command(dev, SERIAL_SYNC, struct.pack('<I', microseconds))
+def reset_name(dev: Recipient) -> None:
+    '''Restore the device name to the default, the CPU serial number.'''
+    command(dev, GET_SET_NAME, b'\0', GET_SET_NAME | 0x80)
+
+RESET_CAUSES = ('pin', 'brown-out', 'software', 'IWDG', 'WWDG', 'low-power')
+
+def get_reset_cause(dev: Device) -> list[str]:
+    resp = retrieve(dev, GET_RESET_CAUSE)
+    flags = struct.unpack('<I', resp.payload)[0]
+    return [c for i, c in enumerate(RESET_CAUSES) if flags & 1 << i]
+
+def get_board_variant(dev: Device) -> tuple[bool, int]:
+    '''Returns (is_prototype, detection method).'''
+    variant, method = retrieve(dev, GET_BOARD_VARIANT).payload
+    return bool(variant), method
+
+def dma_errors(dev: Device) -> tuple[int, int, int, int, int]:
+    '''Fetch and clear the GPS TX, I²C RX, I²C TX, GPS RX, debug TX DMA error
+    counts.'''
+    return struct.unpack('<IIIII', retrieve(dev, DMA_ERRORS).payload)
+
+def self_test(dev: Device) -> int:
+    '''Bitfield of passed checks: image CRC, TMP117, LMK05318b, flash.'''
+    return struct.unpack('<I', retrieve(dev, SELF_TEST).payload)[0]
+
+def crc_self_test(dev: Device) -> int:
+    return retrieve(dev, CRC_SELF_TEST).payload[0]
+
+def get_unique_id(dev: Device) -> tuple[int, int, int]:
+    '''The raw 96-bit CPU unique ID, as three words.'''
+    return struct.unpack('<III', retrieve(dev, GET_UNIQUE_ID).payload)
+
+@dataclass
+class ClockConfig:
+    sysclk: int
+    pll1cfgr: int
+    pll1divr: int
+    flash_acr: int
+    pwr_vossr: int
+    cfgr1: int
+
+    def pll_m(self) -> int:
+        return self.pll1cfgr >> 8 & 63
+    def pll_n(self) -> int:
+        return (self.pll1divr & 511) + 1
+    def pll_p(self) -> int:
+        return (self.pll1divr >> 9 & 127) + 1
+    def latency(self) -> int:
+        return self.flash_acr & 15
+    def vos(self) -> int:
+        return self.pwr_vossr >> 14 & 3
+    def sws(self) -> int:
+        return self.cfgr1 >> 3 & 3
+
+def get_clock_config(dev: Device) -> ClockConfig:
+    '''The live clock configuration, with the computed system clock in Hz.'''
+    return ClockConfig(*struct.unpack(
+        '<6I', retrieve(dev, GET_CLOCK_CONFIG).payload))
+
+def get_uptime(dev: Device) -> int:
+    '''Milliseconds since boot.'''
+    lo, hi = struct.unpack('<II', retrieve(dev, GET_UPTIME).payload)
+    return lo | hi << 32
+
+def watchdog_status(dev: Device) -> tuple[bool, int]:
+    '''Returns (last reset was by watchdog, timeout in ms).'''
+    resp = retrieve(dev, WATCHDOG_STATUS)
+    reset, timeout = struct.unpack('<II', resp.payload)
+    return bool(reset), timeout
+
+def log_level(dev: Device, level: int|None = None) -> int:
+    '''Get/set the debug log level: 0 error, 1 warn, 2 info, 3 debug.'''
+    payload = b'' if level is None else bytes((level,))
+    return retrieve(dev, LOG_LEVEL, payload).payload[0]
+
+def gpio_update(dev: Device, port: int, set: int, clear: int) -> tuple[int, int]:
+    resp = retrieve(dev, GPIO_UPDATE, struct.pack('<III', port, set, clear))
+    return struct.unpack('<II', resp.payload)
+
+def serial_sync(dev: Recipient, microseconds: int) -> tuple[int, int]|None:
+    '''Wait for GPS TX idle, delay, and wait again.  Returns the µs spent
+    waiting for idle before and after the delay, or None when building a
+    config.'''
+    result = command(dev, SERIAL_SYNC, struct.pack('<I', microseconds))
+    if len(result.payload) != 8:
+        return None
+    return struct.unpack('<II', result.payload)
 
 def set_baud(dev: Recipient, baud: int) -> None:
     command(dev, GET_SET_BAUD, struct.pack('<I', baud), GET_SET_BAUD | 0x80)
@@ -142,27 +340,182 @@ def get_baud(dev: Device) -> int:
     resp = retrieve(dev, GET_SET_BAUD, b'')
     return struct.unpack('<I', resp.payload)[0]
 
-def peek(dev: Device, address: int, length: int) -> bytearray:
+def gps_loopback(dev: Device, loopback: bool|None = None) -> bool:
+    payload = b'' if loopback is None else bytes((loopback,))
+    return retrieve(dev, GPS_LOOPBACK, payload).payload[0] != 0
+
+def dtr_reset(dev: Device, enable: bool|None = None) -> bool:
+    '''Get/set whether asserting DTR on the serial port resets the GPS.'''
+    payload = b'' if enable is None else bytes((enable,))
+    return retrieve(dev, DTR_RESET, payload).payload[0] != 0
+
+def host_baud(dev: Device, policy: int|None = None) -> tuple[int, bool]:
+    '''Get/set the host baud policy: 0 ignore, 1 follow, 2 apply once.
+    Returns (host baud rate, following).'''
+    payload = b'' if policy is None else bytes((policy,))
+    response = retrieve(dev, HOST_BAUD, payload)
+    baud, follow = struct.unpack('<II', response.payload)
+    return baud, follow != 0
+
+def gps_dma_tuning(dev: Device, prio: int|None = None,
+                   tx_fifo: int|None = None) -> tuple[int, int]:
+    payload = b''
+    if prio is not None and tx_fifo is not None:
+        payload = bytes((prio, tx_fifo))
+    resp = retrieve(dev, GPS_DMA_TUNING, payload)
+    return resp.payload[0], resp.payload[1]
+
+def gps_framing(dev: Device, framing: tuple[int, int, int]|None = None) \
+        -> tuple[int, int, int]:
+    '''Get/set the GPS UART (data bits, parity, stop bits).  Parity is 0 for
+    none, 1 for even and 2 for odd.'''
+    payload = b'' if framing is None else bytes(framing)
+    resp = retrieve(dev, GPS_FRAMING, payload)
+    return resp.payload[0], resp.payload[1], resp.payload[2]
+
+def gps_flush(dev: Device, timeout_ms: int|None = None) -> None:
+    '''Wait for the GPS UART TX to go idle, raising on timeout.'''
+    payload = b'' if timeout_ms is None else struct.pack('<I', timeout_ms)
+    command(dev, GPS_FLUSH, payload)
+
+def peek(dev: Device, address: int, length: int,
+         unchecked: bool = False) -> bytearray:
+    code = PEEK_UNCHECKED if unchecked else PEEK
     result = bytearray()
     while len(result) < length:
         todo = min(length - len(result), 48)
         a = address + len(result)
-        data = retrieve(dev, PEEK, struct.pack('<II', a, todo))
+        data = retrieve(dev, code, struct.pack('<II', a, todo))
         aa = struct.unpack('<I', data.payload[:4])[0]
         assert a == aa
         assert len(data.payload) == todo + 4
         result += data.payload[4:]
     return result
 
-def poke(dev: Recipient, address: int, data: ByteString, chunk_size: int = 32) -> None:
+def peek_chunked(dev: Device, address: int, length: int) -> bytearray:
+    '''Read memory using a single chunked peek request.'''
+    if length <= 54:
+        return peek(dev, address, length)
+    dev.write(0x03, frame(PEEK, struct.pack('<II', address, length)))
+    result = bytearray()
+    while True:
+        data = deframe(bytes(dev.read(0x83, 64, 10000)))
+        if data.code != PEEK | 0x80:
+            raise RequestFailed(f'Result code is {data.code:#04x}')
+        aa = struct.unpack('<I', data.payload[:4])[0]
+        assert aa == address + len(result)
+        if len(data.payload) == 4:
+            break
+        result += data.payload[4:]
+    assert len(result) == length
+    return result
+
+def fill(dev: Recipient, address: int, length: int, pattern: int) -> None:
+    command(dev, FILL, struct.pack('<III', address, length, pattern))
+
+def config_upload(dev: Device, config: ByteString) -> int:
+    '''Upload a config, which the device places in a free slot.  Returns the
+    slot address.'''
+    assert len(config) % 32 == 0
+    for offset in range(0, len(config), 32):
+        command(dev, CONFIG_UPLOAD,
+                struct.pack('<I', offset) + config[offset : offset + 32])
+    data = retrieve(dev, CONFIG_UPLOAD)
+    return struct.unpack('<I', data.payload)[0]
+
+@dataclass
+class ConfigSlot:
+    index: int
+    magic_ok: bool
+    valid: bool
+    active: bool
+    version: int
+    generation: int
+
+def config_list(dev: Device) -> list[ConfigSlot]:
+    slots = []
+    for first in range(0, 16, 4):
+        data = retrieve(dev, CONFIG_LIST, bytes((first,))).payload
+        for i in range(0, len(data), 12):
+            info, version, generation = struct.unpack('<III', data[i:i+12])
+            slots.append(ConfigSlot(info & 255, bool(info & 256),
+                                    bool(info & 512), bool(info & 1024),
+                                    version, generation))
+    return slots
+
+def applied_config(dev: Device) -> tuple[int, int, int, int]|None:
+    '''Return the slot index, version, generation and length of the config
+    applied at start-up, or None if there was none.'''
+    slot, version, generation, length = struct.unpack(
+        '<IIII', retrieve(dev, APPLIED_CONFIG).payload)
+    if slot == 0xffffffff:
+        return None
+    return slot, version, generation, length
+
+def poke(dev: Recipient, address: int, data: ByteString, chunk_size: int = 32,
+         unchecked: bool = False) -> None:
+    code = POKE_UNCHECKED if unchecked else POKE
     base = 0
     while base < len(data):
         todo = min(chunk_size, len(data) - base)
         #print(f'POKE @ {address+base:#010x} + {todo}')
-        command(dev, POKE,
+        command(dev, code,
                 struct.pack('<I', address + base) + data[base:base + todo])
         base += todo
 
+def register_read(dev: Device, address: int, width: int = 4) -> int:
+    '''Read a peripheral register with a single access of width bytes.'''
+    data = retrieve(dev, REGISTER_ACCESS, struct.pack('<BI', width, address))
+    a, value = struct.unpack('<II', data.payload)
+    assert a == address
+    return value
+
+def register_write(dev: Recipient, address: int, value: int,
+                   width: int = 4) -> None:
+    '''Write a peripheral register with a single access of width bytes.'''
+    command(dev, REGISTER_ACCESS, struct.pack('<BII', width, address, value))
+
+def crc_chunked(dev: Device, address: int, length: int,
+                chunk: int = 4096) -> int:
+    '''CRC32 of a memory region, computed in steps of at most `chunk` bytes.'''
+    state = 0xffffffff
+    for a in range(address, address + length, chunk):
+        todo = min(chunk, address + length - a)
+        resp = retrieve(dev, CRC_CHUNK, struct.pack('<III', state, a, todo))
+        aa, ll, state = struct.unpack('<III', resp.payload)
+        assert aa == a and ll == todo
+    return state ^ 0xffffffff
+
+def crc_segments(dev: Device, segments: list[tuple[int, int]],
+                 reflected: bool = False) -> int:
+    '''CRC32 over a list of (address, length) segments, taken in order.  If
+    reflected, this is the standard CRC-32 as computed by zlib.'''
+    payload = b''.join(struct.pack('<II', a, l) for a, l in segments)
+    if reflected:
+        payload += b'\x01'
+    data = retrieve(dev, GET_CRC, payload)
+    a, l, crc = struct.unpack('<III', data.payload)
+    assert a == segments[0][0]
+    assert l == sum(l for _, l in segments)
+    return crc
+
+def crc_deferred(dev: Recipient, segments: list[tuple[int, int]],
+                 reflected: bool = False) -> None:
+    '''Start a deferred CRC, as for crc_segments.  Fetch the result with
+    deferred_poll.'''
+    payload = b''.join(struct.pack('<II', a, l) for a, l in segments)
+    if reflected:
+        payload += b'\x01'
+    command(dev, DEFERRED_START, bytes((GET_CRC,)) + payload)
+
+def deferred_poll(dev: Device) -> tuple[int, Message|None]:
+    '''Poll the deferred command.  Returns the state (0 none, 1 running,
+    2 done) and, when done, the deferred command's response.'''
+    data = retrieve(dev, DEFERRED_POLL).payload
+    if data[0] != 2:
+        return data[0], None
+    return data[0], Message(data[1], data[2:])
+
 def crc(dev: Device, address: int, length: int) -> int:
     data = retrieve(dev, GET_CRC, struct.pack('<II', address, length))
     a, l, crc = struct.unpack('<III', data.payload)
@@ -171,13 +524,50 @@ def crc(dev: Device, address: int, length: int) -> int:
     return crc
 
 def flash_erase(dev: Recipient, address: int) -> None:
-    command(dev, FLASH_ERASE, struct.pack('<I', address))
+    command(dev, FLASH_ERASE, struct.pack('<I4s', address, b'ERAS'))
+
+def trigger_dfu(dev: Device) -> None:
+    '''Reboot into the ROM DFU bootloader.  There is no response.'''
+    dev.write(0x03, frame(TRIGGER_DFU, b'ERAS')) # pyright: ignore
+
+def bor_level(dev: Device, level: int|None = None) -> tuple[int, int, bool]:
+    '''Get, or set (persistently), the brown-out reset level, 0 to 3.
+    Returns (current level, programmed level, BOR reset flag).'''
+    payload = b'' if level is None else struct.pack('<B4s', level, b'BOR!')
+    current, programmed, flag = retrieve(dev, BOR_LEVEL, payload).payload
+    return current, programmed, flag != 0
+
+def flash_erase_bank(dev: Recipient) -> None:
+    '''Erase the entire inactive flash bank.'''
+    command(dev, FLASH_ERASE_BANK, b'WIPE')
+
+def lmk05318b_reapply(dev: Recipient) -> None:
+    '''Power cycle the LMK05318b and re-run its writes from the stored config.'''
+    command(dev, LMK05318B_REAPPLY, b'')
 
 def lmk05318b_read(dev: Device, address: int, length: int) -> bytes:
     r = retrieve(dev, LMK05318B_READ, struct.pack('>BH', length, address))
     assert len(r.payload) == length
     return r.payload
 
+def lmk05318b_nvm_read(dev: Device, address: int, length: int) -> bytes:
+    '''Read the LMK05318b on-chip EEPROM.'''
+    result = b''
+    while len(result) < length:
+        a = address + len(result)
+        todo = min(length - len(result), 56)
+        r = retrieve(dev, LMK05318B_NVM_READ, struct.pack('<HB', a, todo))
+        assert struct.unpack('<H', r.payload[:2])[0] == a
+        assert len(r.payload) == todo + 2
+        result += r.payload[2:]
+    return result
+
+def lmk05318b_nvm_commit(dev: Recipient, verify_length: int) -> None:
+    '''Program the LMK05318b EEPROM from the live registers, and verify the
+    first verify_length bytes.'''
+    command(dev, LMK05318B_NVM_COMMIT,
+            b'NVMW' + struct.pack('<I', verify_length))
+
 def lmk05318b_write(dev: Recipient, address: int, *data: ByteString|int) -> None:
     def bb(x: ByteString|int) -> ByteString:
         return bytes((x,)) if isinstance(x, int) else x
@@ -187,6 +577,21 @@ def lmk05318b_write(dev: Recipient, address: int, *data: ByteString|int) -> None
 def lmk05318b_status(dev: Recipient) -> None:
     command(dev, LMK05318B_STATUS, b'')
 
+def i2c_speed(dev: Device, speed: int|None = None) -> int:
+    '''Get/set the I²C bus speed in kHz, 100 or 400.'''
+    payload = b'' if speed is None else struct.pack('<I', speed)
+    return struct.unpack('<I', retrieve(dev, I2C_SPEED, payload).payload)[0]
+
+def lmk05318b_raw_status(dev: Device) -> tuple[int, int, int, int]:
+    '''Return the status, mask, polarity and interrupt words.'''
+    resp = retrieve(dev, LMK05318B_RAW_STATUS)
+    return struct.unpack('<4H', resp.payload)
+
+def lmk05318b_frequencies(dev: Device) -> list[float]:
+    resp = retrieve(dev, LMK05318B_FREQUENCIES)
+    v = struct.unpack('<12I', resp.payload)
+    return [v[i] + v[i + 1] * 1e-6 for i in range(0, 12, 2)]
+
 def tmp117_read(dev: Device, address: int, length: int = 1) -> bytes:
     r = retrieve(dev, TMP117_READ, bytes((length, address)))
     assert len(r.payload) == length
@@ -194,3 +599,159 @@ def tmp117_read(dev: Device, address: int, length: int = 1) -> bytes:
 
 def tmp117_write(dev: Recipient, address: int, data: bytes) -> None:
     command(dev, TMP117_WRITE, bytes((address,)) + data)
+
+def hsi48(dev: Device, enable: bool|None = None) -> tuple[bool, bool, int]:
+    payload = b'' if enable is None else bytes((int(enable),))
+    on, ready, trim = retrieve(dev, HSI48, payload).payload
+    return bool(on), bool(ready), trim
+
+def tmp117_read_regs(dev: Device, reg: int, count: int) -> tuple[int, ...]:
+    '''Read count consecutive 16-bit TMP117 registers, starting at reg.'''
+    resp = retrieve(dev, TMP117_READ_REGS, bytes((reg, count)))
+    return struct.unpack(f'<{count}H', resp.payload)
+
+def tmp117_temperature(dev: Device) -> float:
+    resp = retrieve(dev, TMP117_TEMPERATURE)
+    return struct.unpack('<i', resp.payload)[0] / 100
+
+def get_usb_mode(dev: Device) -> int:
+    return retrieve(dev, USB_MODE).payload[0]
+
+def set_usb_mode(dev: Recipient, mode: int) -> None:
+    # The device re-enumerates on a change, and then does not respond.
+    data = frame(USB_MODE, bytes((mode,)))
+    if isinstance(dev, bytearray):
+        dev += data
+    else:
+        dev.write(0x03, data) # pyright: ignore
+
+def bank_crc(dev: Device, bank: int) -> int:
+    '''CRC32 of a whole flash bank, 0 active or 1 inactive.'''
+    resp = retrieve(dev, BANK_CRC, bytes((bank,)))
+    b, crc = struct.unpack('<II', resp.payload)
+    assert b == bank
+    return crc
+
+def bank_swap(dev: Device) -> None:
+    '''Swap to the image in the inactive flash bank and reboot.  On success,
+    the device reboots and does not respond; if the image is not valid, we get
+    a NAK.'''
+    dev.write(0x03, frame(BANK_SWAP, b'')) # pyright: ignore
+    try:
+        resp = dev.read(0x83, 64, timeout=500) # pyright: ignore
+    except Exception:
+        return                          # Rebooted.
+    raise RequestFailed(f'Bank swap failed: {bytes(resp).hex()}')
+
+def get_usb_errata_delay(dev: Device) -> tuple[int, int, int]:
+    '''Returns (measured CPU cycles, target nanoseconds, CPU frequency).'''
+    return struct.unpack('<III', retrieve(dev, USB_ERRATA_DELAY).payload)
+
+def get_usb_string(dev: Device, index: int) -> tuple[int, str]:
+    '''Returns (number of strings, string).'''
+    b = retrieve(dev, USB_STRING, struct.pack('<B', index)).payload
+    return b[1], b[2:].decode(errors='replace')
+
+def get_usb_strings(dev: Device) -> list[str]:
+    count, s = get_usb_string(dev, 0)
+    return [s] + [get_usb_string(dev, i)[1] for i in range(1, count)]
+
+def get_usb_string_raw(dev: Device, index: int) -> bytes:
+    '''The raw string descriptor, header included, as sent to the host.'''
+    result = b''
+    while True:
+        b = retrieve(dev, USB_STRING_RAW,
+                     struct.pack('<BB', index, len(result))).payload
+        total = b[1]
+        result += b[2:]
+        if len(result) >= total or len(b) == 2:
+            return result
+
+def get_usb_serial_from_cpu(dev: Device) -> bool:
+    return retrieve(dev, USB_SERIAL_SOURCE).payload[0] != 0
+
+def set_usb_serial_from_cpu(dev: Recipient, from_cpu: bool) -> None:
+    command(dev, USB_SERIAL_SOURCE, bytes((from_cpu,)),
+            USB_SERIAL_SOURCE | 0x80)
+
+@dataclass
+class SofStats:
+    count: int
+    total: int
+    min: int
+    max: int
+    cpu_freq: int
+
+    def mean_ns(self) -> float:
+        return self.total / self.count * 1e9 / self.cpu_freq
+
+    def jitter_ns(self) -> float:
+        return (self.max - self.min) * 1e9 / self.cpu_freq
+
+    def ppm(self) -> float:
+        '''Core clock error relative to the USB host, in ppm.'''
+        return (self.total / self.count / self.cpu_freq * 1000 - 1) * 1e6
+
+def usb_sof_stats(dev: Device) -> SofStats:
+    return SofStats(*struct.unpack('<5I', retrieve(dev, USB_SOF_STATS).payload))
+
+@dataclass
+class SetupPacket:
+    request_type: int
+    request: int
+    value: int
+    index: int
+    length: int
+
+def usb_setup_log(dev: Device, enable: bool|None = None) \
+        -> tuple[bool, list[SetupPacket]]:
+    payload = b'' if enable is None else bytes((enable,))
+    data = retrieve(dev, USB_SETUP_LOG, payload).payload
+    num = data[1]
+    setups = [SetupPacket(*struct.unpack('<BBHHH', data[2 + 8 * i: 10 + 8 * i]))
+              for i in range(num)]
+    return data[0] != 0, setups
+
+def usb_stall(dev: Device, endpoint: int) -> None:
+    '''Stall a serial endpoint until the host clears the halt, or for at most
+    one second.'''
+    command(dev, USB_STALL, bytes((endpoint,)))
+
+def tick_rate(dev: Device, rate: int|None = None) -> int:
+    payload = b'' if rate is None else struct.pack('<I', rate)
+    return struct.unpack('<I', retrieve(dev, TICK_RATE, payload).payload)[0]
+
+def lmk05318b_poll_rate(dev: Device, rate: int|None = None) -> int:
+    '''Get/set the LMK05318b status re-check rate, in Hz.'''
+    payload = b'' if rate is None else struct.pack('<I', rate)
+    resp = retrieve(dev, LMK05318B_POLL_RATE, payload)
+    return struct.unpack('<I', resp.payload)[0]
+
+def led_timing(dev: Device, led: int|None = None, on: int = 1000,
+               off: int = 1000) -> tuple[int, int, int, int]:
+    '''Get/set the LED blink on & off times, in units of 100µs.  led is 0 for
+    blue, 1 for red/green.  Returns blue on, blue off, red/green on and
+    red/green off.'''
+    payload = b'' if led is None else struct.pack('<HHH', led, on, off)
+    return struct.unpack('<HHHH', retrieve(dev, LED_TIMING, payload).payload)
+
+def i2c_recover(dev: Device) -> bool:
+    return retrieve(dev, I2C_RECOVER).payload[0] != 0
+
+def gps_rx_errors(dev: Device) -> tuple[int, int, int]:
+    '''Fetch and clear (overrun, framing, noise) error counts.'''
+    return struct.unpack('<III', retrieve(dev, GPS_RX_ERRORS).payload)
+
+def gps_link_stats(dev: Device) -> tuple[int, int, int, int, int]:
+    '''Return cumulative (received, forwarded, TX busy, USB drops, RX ring
+    overrun) counts.'''
+    return struct.unpack('<IIIII', retrieve(dev, GPS_LINK_STATS).payload)
+
+def ubx_poll(dev: Device, frame: bytes) -> bytes:
+    '''Send a complete UBX frame to the GPS, and return the UBX frame received
+    in reply with the same class and id.'''
+    return retrieve(dev, UBX_POLL, frame).payload
+
+def gps_actual_baud(dev: Device) -> tuple[int, int, int]:
+    '''Return (BRR, prescaler division, actual baud) from the hardware.'''
+    return struct.unpack('<III', retrieve(dev, GPS_ACTUAL_BAUD).payload)
@@ -1,11 +1,13 @@
 
 from freak.message import Recipient, command
 
+import struct
+
 from argparse import Namespace
 from typing import Any
 
 # !@#$@# argparse, what is the type of a subparser?
-def add_reset_command(subp: Any, unit: str) -> None:
+def add_reset_command(subp: Any, unit: str, pulse_width: bool = False) -> None:
     reset = subp.add_parser(
         'reset', help=f'Reset the {unit}.',
         description=f'''Reset the {unit} via its reset pin.''',
@@ -19,6 +21,9 @@ def add_reset_command(subp: Any, unit: str) -> None:
                        help='Assert reset line (low).')
     reset.add_argument('-1', '--deassert', action='store_true',
                        help='De-assert reset line (high).')
+    if pulse_width:
+        reset.add_argument('-w', '--width', type=int, metavar='MICROSECONDS',
+                           help='Reset pulse width, default 1000.')
 
 def do_reset_line(dev: Recipient, code: int, args: Namespace|None) -> None:
     payload = b'\x02'
@@ -29,5 +34,8 @@ def do_reset_line(dev: Recipient, code: int, args: Namespace|None) -> None:
             payload = b'\x00'
         elif not assrt and deassert:
             payload = b'\x01'
+        width = getattr(args, 'width', None)
+        if payload == b'\x02' and width is not None:
+            payload += struct.pack('<I', width)
 
     command(dev, code, payload)
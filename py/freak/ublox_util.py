@@ -75,7 +75,7 @@ def add_to_argparse(argp: argparse.ArgumentParser,
     save.add_argument('-n', '--dry-run', action='store_true', default=False,
                       help="Don't actually write to flash.")
 
-    message_util.add_reset_command(subp, 'GPS unit')
+    message_util.add_reset_command(subp, 'GPS unit', pulse_width=True)
 
     changes = subp.add_parser(
         'changes', help='Report changed config items',
@@ -0,0 +1,19 @@
+#!/usr/bin/python3
+
+'''Add the CRC and length word to a raw firmware binary, in place, so that the
+firmware will accept it as a valid image for a bank swap and self-test.'''
+
+from freak.crc32 import seal_image
+
+import argparse
+
+argp = argparse.ArgumentParser(description='Seal a firmware image')
+argp.add_argument('IMAGE', help='Raw binary firmware image')
+
+args = argp.parse_args()
+
+with open(args.IMAGE, 'rb') as f:
+    image = f.read()
+
+with open(args.IMAGE, 'wb') as f:
+    f.write(seal_image(image))
@@ -0,0 +1,58 @@
+#!/usr/bin/python3
+
+'''Check that control transfers whose length is an exact multiple of the 64
+byte packet size, and shorter than requested, are terminated with a zero
+length packet.
+
+We set the device name (which is the USB serial number string) to 31
+characters, giving a string descriptor of exactly 64 bytes, and fetch it with a
+longer wLength.  Without the ZLP the host waits for more data and the transfer
+times out.  The original name is restored afterwards.'''
+
+from freak import message
+from freak.freak_util import Device
+
+import argparse
+import sys
+
+from typing import cast
+
+argp = argparse.ArgumentParser(description='USB control ZLP test')
+argp.add_argument('-n', '--name', metavar='NAME',
+                  help='Name of device to connect to (USB ser. no.)')
+argp.add_argument('-c', '--cpu', metavar='CPU SN',
+                  help='CPU serial number of device to connect to')
+
+args = argp.parse_args()
+
+dev = Device(args).get_usb()
+
+if message.get_usb_serial_from_cpu(dev):
+    print('USB serial number is from the CPU, cannot test', file=sys.stderr)
+    sys.exit(1)
+
+original = message.get_name(dev)
+serial_index = cast(int, dev.iSerialNumber) # pyright: ignore
+
+def fetch(length: int) -> bytes:
+    return bytes(dev.ctrl_transfer(     # pyright: ignore
+        0x80, 6, 0x300 | serial_index, 0x409, length, timeout=500))
+
+failed = False
+try:
+    message.set_name(dev, 'Z' * 31)
+    for length in 64, 65, 255:
+        try:
+            data = fetch(length)
+        except Exception as e:
+            print(f'wLength {length}: FAIL {e}')
+            failed = True
+            continue
+        ok = len(data) == 64 and data[0] == 64
+        failed = failed or not ok
+        print(f'wLength {length}: got {len(data)} bytes',
+              'OK' if ok else 'FAIL')
+finally:
+    message.set_name(dev, original)
+
+sys.exit(1 if failed else 0)
@@ -18,6 +18,12 @@
 //! Over USB, a new request will not be accepted until any previous response
 //! has been read.
 //!
+//! Requests may also be sent to the debug UART (115200 baud), with the same
+//! framing.  Responses are sent back out the debug UART, interleaved with the
+//! debug output, so the host should scan for the magic.  Bytes received while
+//! a request is being processed are dropped.  This is on the prototype only,
+//! as the production boards use the RX pin for the blue LED.
+//!
 //! Note that if the device gets a request code indicating a message from the
 //! device, then it does not respond.  This avoids message loops!
 //!
@@ -38,7 +44,15 @@
 //! Commands (codes are hex):
 //!    00 : PING.  Arbitrary payload.  Response is 80 and echos the payload.
 //!         By sending an arbitrary token, you can check that messages are
-//!         synchronised.
+//!         synchronised.  With an empty payload, the response instead carries
+//!         9 bytes: the uptime in milliseconds as a u64, followed by a u8 with
+//!         the reset cause flags as for command 05 (not cleared).
+//!    01 : PING stress.  Payload is a u32 seed followed by a u32 length (at
+//!         most 4096).  The response is a sequence of 80 messages carrying
+//!         `length` pseudo-random bytes in total, in full 58 byte payloads
+//!         except possibly the last, followed by an empty 80 as terminator.
+//!         Each byte is the low byte of the next xorshift32 state (shifts 13,
+//!         17, 5), starting from the seed; a zero seed is replaced by 1.
 //!    80 : ACK. Generic Acknowledgement.  Payload is generally empty.
 //!         Ping responses echo the payload.  Otherwise if non-empty, then is an
 //!         informational UTF-8 string.
@@ -49,17 +63,237 @@
 //!    02 : Get protocol version.  Response is 82 with u32 payload.
 //!    03 : Get CPU serial number.  Response is 83 with ASCII string payload.
 //!    04 : Get/set device name.  Response is 84 with UTF-8 payload.
-//!         This string is also used as the USB serial number.
+//!         This string is also used as the USB serial number, unless
+//!         command 33 selects the CPU serial number instead.  An empty payload
+//!         just gets the name, and a single 00 byte restores the default,
+//!         the CPU serial number.
+//!    05 : Get reset cause.  Response is 85 with a u32 bitmask: 1 pin reset,
+//!         2 brown-out, 4 software reset, 8 independent watchdog, 16 window
+//!         watchdog, 32 low-power.  The flags are cleared after reading, so
+//!         a second request reports nothing until the next reset.
+//!
+//!    06 : Get firmware build identifier.  Response is 86 with a fixed length
+//!         20 byte ASCII payload, NUL padded.  This is taken from the
+//!         FREAK_BUILD_ID environment variable at compile time (e.g., the git
+//!         short hash and build date), falling back to the package version.
+//!         The same string, untruncated, is the USB configuration string.
+//!
+//!    07 : Get board variant.  Response is 87 with two u8: the variant (0 is
+//!         production, 1 is the prototype), and the detection method (currently
+//!         always 1, matching the CPU unique ID against the known prototype).
+//!
+//!    08 : Get uptime.  Response is 88 with a u64, split into two u32 (low word
+//!         first), the milliseconds since boot.
+//!
+//!    09 : Get raw CPU unique ID.  Response is 89 with the three u32 words of
+//!         the 96-bit unique ID, from 0x08fff800 upwards, unformatted.
+//!
+//!    0a : Get clock configuration.  Response is 8a with six u32: the system
+//!         clock frequency in Hz as computed from the registers (0 if unknown),
+//!         then the raw RCC PLL1CFGR, RCC PLL1DIVR, FLASH ACR, PWR VOSSR and
+//!         RCC CFGR1 register values.
+//!
+//!    0b : Fetch and clear the DMA error counters.  Response is 8b with five
+//!         u32, the number of errored transfers on the GPS UART TX, I²C RX,
+//!         I²C TX, GPS UART RX and debug UART TX DMA channels since the last
+//!         fetch.
+//!
+//!    0c : Self-test.  Response is 8c with a u32 bitfield, a bit set for each
+//!         check that passed: bit 0, CRC-32 of the running firmware image,
+//!         which must have been sealed by `py/seal_image.py` (as `dfu.sh`
+//!         does) with the length of this build; bit 1, TMP117 answers on I²C
+//!         with its device ID; bit 2, LMK05318b answers on I²C with its vendor
+//!         ID; bit 3, a constant in flash reads back as expected.
+//!
+//!    0d : CRC unit self-test.  Runs the hardware CRC-16 and CRC-32 over canned
+//!         vectors.  Response is 8d with a u8 payload, bit 0 set if CRC-16
+//!         passed, bit 1 set if CRC-32 passed.
+//!
+//!    0e : Watchdog status.  Response is 8e with two u32: 1 if the last reset
+//!         was caused by the independent watchdog (otherwise 0), and the
+//!         watchdog timeout in milliseconds.  Unlike 05, this is not cleared by
+//!         reading.
+//!
+//!    0f : Get/Set the debug log level.  Optional u8 payload is the level: 0
+//!         errors, 1 warnings, 2 information (the default), 3 debug.  Response
+//!         is 8f with the u8 level.
 //!
 //!    10 : CPU reboot.  No response.
 //!    11 : GPS reset. u8 payload.
 //!            - 0 assert reset low, 1 deassert reset high, others pulse reset.
+//!         For a pulse, an optional u32 after the u8 gives the pulse width in
+//!         µs, at most 1000000, default 1000.
 //!    12 : Clock gen PDN (reset), u8 payload:
 //!            - 0 power down, 1 power up, ≥2 reset & power back up.
-//!    1e : Serial sync / delay.  Used in provisioning.
+//!    13 : Enter DFU.  u32 payload is the confirmation magic 0x53415245
+//!         ("ERAS" as little endian bytes, as for flash erase).  Reboots into
+//!         the ROM bootloader, as the USB DFU detach request does.  No response
+//!         on success.
+//!    14 : HSI48 control.  Optional u8 payload, 0 to turn the HSI48 off, 1 to
+//!         turn it on.  Turning it off is refused while USB is enumerated, and
+//!         turning it on fails if it is not ready within 1ms.  Response is 94
+//!         with three u8 fields: on, ready and the CRS trim.
+//!    15 : Get/Set the periodic tick rate.  Optional u32 payload is the rate in
+//!         Hz, 2 to 10000 (default 1000).  Response is 95 with the u32 rate.
+//!    16 : Get/Set the brown-out reset level.  The level is 0 (the default BOR
+//!         threshold, BORH_EN clear) or 1 to 3 (BOR_LEV 0 to 2, approx. 2.1V,
+//!         2.4V and 2.7V).  To change it, the payload is the u8 level followed
+//!         by the u32 magic "BOR!"; this programs the option bytes, which
+//!         persist.  Response is 96 with three u8: the current level, the
+//!         programmed level, and 1 if the BOR flag in the reset cause is set
+//!         (not cleared; see command 05), otherwise 0.
+//!    17 : Clock gen reapply.  Power cycles the LMK05318b via PDN, waits for it
+//!         to start, then re-runs the LMK05318b I²C writes (command 60) from
+//!         the stored config (the one that would be chosen at start-up),
+//!         skipping everything else.  Fails without touching the clock gen
+//!         if there is no valid config.
+//!    18 : Get/Set LED blink timing.  Optional payload is three u16: the LED
+//!         (0 = blue, 1 = red/green), and the on and off durations in units of
+//!         100µs, each at most 30000.  The default is 1000 for both.
+//!         Response is 98 with four u16: blue on, blue off, red/green on and
+//!         red/green off.
+//!    19 : Atomic GPIO update.  Payload is three u32: port (0 = GPIOA,
+//!         1 = GPIOB), set mask and clear mask.  Both masks are applied in a
+//!         single BSRR write, set taking priority.  Pins used by the firmware
+//!         (USB, UARTs, I²C, SWD) are refused.  Response is 99 with two u32,
+//!         the resulting IDR and ODR.
+//!    1a : Get/Set the LMK05318b status re-check rate.  Optional u32 payload is
+//!         the rate in Hz, 1 to 100 (default 25), at which the status is
+//!         re-read while it is unsettled.  Response is 9a with the u32 rate.
+//!
+//!    1e : Serial sync / delay.  Used in provisioning.  u32 payload is a delay
+//!         in µs (at most 1000000).  Waits for the GPS UART TX to go idle,
+//!         delays, and waits again.  The ACK payload is two u32, the time in
+//!         µs spent waiting for TX idle before and after the delay.  NACK with
+//!         GpsTimeout if the TX does not go idle in the time to send a USB
+//!         serial packet at the current baud rate.
 //!    1f : Get/Set baud rate, optional u32 payload has baud rate, Response
 //!         is 9f with baud rate.
 //!
+//!    20 : Fetch and clear the GPS UART RX error counters.  Response is a0 with
+//!         three u32: overrun, framing error and noise error counts.
+//!
+//!    21 : GPS link statistics.  Response is a1 with five cumulative u32
+//!         counts: bytes received from the GPS, bytes forwarded to the USB
+//!         serial, TX DMA requests refused as busy, bytes dropped because the
+//!         USB serial TX buffer was full, and bytes received from the GPS but
+//!         lost because the USB serial did not keep up and the RX ring
+//!         overran.
+//!
+//!    22 : Get/Set GPS UART loopback.  Optional u8 payload, non-zero to echo
+//!         data from the USB serial back to USB, and discard data received
+//!         from the GPS.  Response is a2 with the u8 setting.
+//!
+//!    23 : Get/Set GPS UART DMA tuning.  Optional payload is two u8, the TX DMA
+//!         channel priority (0..3) and the UART TX FIFO threshold (TXFTCFG,
+//!         0..5).  The change is refused while a TX DMA is in progress.
+//!         Response is a3 with the current settings.
+//!
+//!    24 : Actual GPS UART baud.  Response is a4 with three u32: the live
+//!         USART2 BRR value, the prescaler division from PRESC, and the baud
+//!         rate derived from these and the CPU clock.  Compare with 1f to see
+//!         the quantization error.
+//!
+//!    25 : UBX poll.  Payload is a complete UBX frame, from the sync bytes
+//!         through the checksum, which is sent to the GPS.  The first valid
+//!         UBX frame received with the same class and id is returned in the
+//!         a5 response, again complete from sync bytes to checksum.  The
+//!         received data is still forwarded to the USB serial as usual.  NACK
+//!         with GpsTimeout if the frame cannot be sent or there is no reply
+//!         within a second, or Failed if the GPS NAKs the message or the reply
+//!         is too long to return.
+//!
+//!    26 : Get/Set GPS UART framing.  Optional payload is three u8: data bits
+//!         (7 or 8), parity (0 none, 1 even, 2 odd) and stop bits (1 or 2).
+//!         Unsupported combinations are NACKed.  With 7 bit data and parity,
+//!         the received parity bit is passed on in the MSB.  Response is a6
+//!         with the current settings.  The default is 8N1.
+//!
+//!    27 : Flush GPS TX.  Waits for the GPS UART TX DMA and transmitter to go
+//!         idle.  Optional u32 payload is the timeout in milliseconds, default
+//!         1000, at most 1500 to stay clear of the watchdog.  ACK when idle,
+//!         NACK with GpsTimeout if the timeout expires first.
+//!
+//!    28 : Get/Set the DTR reset policy.  Optional u8 payload, non-zero to
+//!         pulse the GPS reset (10ms) when the host asserts DTR on the CDC ACM
+//!         serial.  Off by default.  Response is a8 with the u8 setting.
+//!
+//!    29 : Get/Set the host baud policy.  The baud rate that the host sets via
+//!         CDC ACM is normally recorded and reported back, but not applied.
+//!         Optional u8 payload: 0 to ignore the host baud rate (the default), 1
+//!         to apply it to the GPS UART on each change, or 2 to apply the
+//!         current host baud rate once, now, leaving the policy unchanged.
+//!         Response is a9 with two u32: the host baud rate, and 1 if it is
+//!         being applied (otherwise 0).
+//!
+//!    30 : Get/Set USB mode.  Optional u8 payload selects the USB functions
+//!         presented: 0 both CDC ACM serial and device commands (the
+//!         default), 1 serial only, 2 commands only.  The DFU interface is
+//!         always present, numbered after the others.  If the mode changes
+//!         while USB is connected, the device disconnects and re-enumerates,
+//!         and no response is sent.  Otherwise the response is b0 with the u8
+//!         mode.  This is not persistent, but may be placed in the start-up
+//!         config, which runs before USB starts.
+//!
+//!    31 : Get USB errata delay parameters.  Response is b1 with three u32: the
+//!         measured duration of the USB core's errata delay in CPU cycles, the
+//!         target delay in nanoseconds, and the CPU frequency in Hz.
+//!
+//!    32 : Get USB string descriptor.  u8 payload is the string index.
+//!         Response is b2 with the u8 index, the u8 number of strings
+//!         (including the serial number, which is last), and then the string,
+//!         decoded from the descriptor to UTF-8.  Index 0 is the language list.
+//!
+//!    33 : Get/Set USB serial number source.  Optional u8 payload, 0 to use the
+//!         device name (the default), 1 to use the CPU serial number, which
+//!         does not change when the device is renamed.  Response is b3 with
+//!         the u8 setting.  The setting itself is volatile; it is persisted in
+//!         flash by saving the start-up config block (the host config tool
+//!         includes this command when the setting is 1), which is replayed
+//!         before USB starts.  Hosts typically only pick up a change made at
+//!         run time on re-enumeration.
+//!
+//!    34 : Get USB SOF interval statistics.  The interval between USB
+//!         start-of-frame interrupts (nominally 1ms, from the host clock) is
+//!         timed with the CPU cycle counter, over one second windows.  Response
+//!         is b4 with five u32 for the last complete window: interval count
+//!         (zero if none yet), total CPU cycles, minimum and maximum interval
+//!         in cycles, and the nominal CPU frequency.  The total is nominally
+//!         the CPU frequency; a deviation indicates a core clock problem.
+//!
+//!    35 : USB SETUP packet log.  Optional u8 payload, 1 to enable capture
+//!         (clearing the log) or 0 to disable.  Response is b5 with u8 enabled
+//!         flag, u8 number of packets, and then up to seven most recent 8 byte
+//!         SETUP packets received on the control endpoint, oldest first.
+//!
+//!    36 : Stall a USB endpoint, for testing host error recovery.  u8 payload
+//!         is the endpoint address, one of 01 (serial bulk OUT), 81 (serial
+//!         bulk IN) or 82 (serial interrupt IN).  The endpoint returns STALL
+//!         until the host clears the halt, or for at most one second, rather
+//!         than for a single transaction: the USB peripheral gives no
+//!         indication that a STALL handshake was sent.  Only one endpoint may
+//!         be stalled at a time.  Response is ACK.
+//!
+//!    37 : Get raw USB string descriptor.  Payload is the u8 string index and an
+//!         optional u8 byte offset.  Response is b7 with the u8 index, the u8
+//!         total descriptor length, and then up to 56 bytes of the descriptor
+//!         from the offset, exactly as sent to the host, header included.
+//!
+//!    50 : Deferred command poll.  Response is d0 with a u8 state: 0 if there
+//!         is no deferred command, 1 if it is still running, or 2 if it has
+//!         completed.  On completion, the state is followed by the u8 response
+//!         code of the deferred command (e.g., f3, or 81 for a NACK) and its
+//!         payload, and the slot is then freed.
+//!
+//!    51 : Deferred command start.  Payload is a u8 request code followed by
+//!         the payload for that request.  The request is run from the main
+//!         loop rather than the command handler, so that further commands are
+//!         served while it runs; poll for the result with 50.  There is a
+//!         single slot: NACK with Failed if a deferred command is still
+//!         running, and any uncollected result is discarded.  Only 73 (crc)
+//!         may currently be deferred, otherwise NACK with BadParameter.  ACK
+//!         once queued.
+//!
 //!    60 : LMK05318b I²C write.  Payload is sent in a I²C write transaction.
 //!
 //!    61 : LMK05318b I²C read.  First byte of payload is number of bytes,
@@ -71,32 +305,142 @@
 //!
 //!    64, 65 : Reserved for GPS I²C.
 //!
+//!    66 : TMP117 temperature.  Response is e6 with an i32 payload, the
+//!         temperature in hundredths of a degree Celsius.
+//!
+//!    67 : LMK05318b output frequencies.  Response is e7 with six pairs of u32,
+//!         integer Hz and fractional µHz, for the channels OUT0/1, OUT2/3, OUT4,
+//!         OUT5, OUT6 and OUT7, computed from the live PLL and divider
+//!         registers.  Powered down channels report zero.
+//!
 //!    68 : Update LMK05318b status LED.  Use this to make the firmware catch
 //!         up after sending I²C commands that alter the status flag handling.
 //!
+//!    69 : LMK05318b raw status.  Response is e9 with four u16, the status,
+//!         mask, polarity and interrupt flag register pairs (R13/14, R15/16,
+//!         R17/18, R19/20), each with the lower numbered register in the low
+//!         byte.  Unlike 68, this does not clear interrupts or change the
+//!         polarity registers.
+//!
+//!    6a : I²C bus recovery.  Clocks SCL until a device holding SDA low
+//!         releases it, then sends a STOP.  This is also done automatically
+//!         after three consecutive I²C transactions fail with a timeout or bus
+//!         error (a NACK does not count).  Response is ea with a u8, 1 if SDA
+//!         is released.
+//!
+//!    6b : Get/Set I²C bus speed.  Optional u32 payload is the speed in kHz,
+//!         either 100 or 400 (the default).  Response is eb with the u32
+//!         speed.
+//!
+//!    6c : TMP117 multi-register read.  Payload is two u8, the first register
+//!         and the number of registers, at most 29.  Response is ec with a u16
+//!         (little-endian) for each register.
+//!
+//!    6d : LMK05318b EEPROM read.  Payload is a u16 EEPROM address and a u8
+//!         length, at most 56.  Response is ed with the u16 address followed by
+//!         the EEPROM bytes, read via the MEMADR / NVMDAT registers.  NACK with
+//!         Failed if an EEPROM program cycle is in progress.
+//!
+//!    6e : LMK05318b EEPROM commit.  Payload is the u32 confirmation magic
+//!         0x574d564e ("NVMW" as little endian bytes), followed by a u32 verify
+//!         length.  The live registers are copied to the NVM SRAM and the
+//!         EEPROM is erased and programmed from it.  Then the first verify
+//!         length bytes of the EEPROM are compared with the SRAM.  ACK on
+//!         success, NACK with Failed on a mismatch or if the programming does
+//!         not complete.  The EEPROM has a limited number of program cycles!
+//!
+//!    70 : chunked crc.  Payload is three u32: the CRC state to continue from,
+//!         the address and the length.  Response is f0 with the address, length
+//!         and the updated state.  Start with state ffffffff, pass each
+//!         returned state to the next call, and the CRC is the bitwise inverse
+//!         of the final state, as for 73.  This lets the host CRC a large
+//!         region in small steps, keeping the command channel responsive.  The
+//!         address range is checked as for peek.
 //!    71 : peek.  Payload is u32 address followed by u32 length.  Response is
-//!         F1 with address + data payload.
+//!         F1 with address + data payload.  If the length is more than 54
+//!         bytes, then the dump is chunked: a sequence of F1 responses is sent,
+//!         each with the running address and up to 52 data bytes, followed by
+//!         a terminating F1 with just the end address and no data.  The length
+//!         is at most 4096 (BadParameter otherwise).  The host must read all
+//!         the responses before sending another request; if it does not keep
+//!         up, the dump is abandoned after 500ms and the remaining responses
+//!         are dropped.
 //!    72 : poke.  Payload is u32 address followed by data bytes.
-//!         As well as memory writes, flash writes of an aligned 32 byte block
-//!         is supported.
+//!         As well as memory writes, flash writes are supported, of any
+//!         alignment and length, provided that every 32 byte block touched is
+//!         erased.
 //!    73 : crc.  Payload is u32 address followed by u32 length.  Response is
-//!         F3 with a 32 bit CRC payload.
+//!         F3 with the address, length and 32 bit CRC.  Up to seven (address,
+//!         length) segments may be given, and are run through a single CRC
+//!         computation; the response then has the first address and the total
+//!         length.  An optional trailing u8 selects the CRC: 0 (the default)
+//!         for the STM32 bit order used for image checks, 1 for the standard
+//!         reflected CRC-32 as computed by zlib.  Each segment is checked
+//!         against the same windows as peek and poke.
 //!
 //!            Both peek and poke will do 32-bit or 16-bit transfers if address
-//!            and length are both sufficiently aligned.  Neither guard against
-//!            crashing the device or making irreversable changes.
+//!            and length are both sufficiently aligned.  Addresses are checked
+//!            against a coarse map of flash, RAM and peripheral windows, and
+//!            BadParameter returned if outside.  That does not guard against
+//!            making irreversable changes, or against reserved addresses within
+//!            the windows.
+//!
+//!    74 : flash erase.  Erase a flash sector.  Payload is the u32 sector
+//!         address, followed by the u32 confirmation magic 0x53415245 ("ERAS"
+//!         as little endian bytes).  A wrong magic gets BadParameter.
+//!    75 : fill.  Payload is u32 address, u32 length and u32 pattern.  The
+//!         region is filled with the pattern, aligned to the address, using
+//!         the widest access compatible with the address and length.  Flash
+//!         (below 0x20000000) is refused, and the region is checked against
+//!         the same windows as peek and poke.
+//!    76 : config upload.  Payload is u32 offset followed by 32 bytes, which
+//!         are programmed at that offset in the config slot being uploaded.
+//!         Offset 0 starts a new upload, choosing (and if necessary erasing) a
+//!         slot that does not hold the active config.  An empty payload ends
+//!         the upload, checking the config magic, length and CRC; the response
+//!         is f6 with the u32 slot address.  An incomplete upload fails the
+//!         CRC check at start-up, leaving the previous config in use.
+//!    77 : config list.  u8 payload is the first config slot (0..15).
+//!         Response is f7 with up to four entries of three u32: slot index
+//!         plus flags << 8 (1 magic valid, 2 length & CRC valid, 4 active),
+//!         the format version and the generation number.
+//!    79 : flash bank erase.  Erase the entire inactive bank.  Payload is the
+//!         u32 confirmation magic 0x45504957 ("WIPE" as little endian bytes).
+//!
+//!    7a : bank CRC.  u8 payload is the bank, 0 for the active bank at
+//!         0x08000000, or 1 for the inactive bank at 0x08010000.  Response is
+//!         fa with two u32, the bank and the CRC32 of the entire 64kB bank.
+//!
+//!    7b : bank swap.  Checks that the inactive flash bank (at 0x08010000)
+//!         holds an image with a valid length and CRC, see `flash`, then swaps
+//!         the flash banks via the option bytes and reboots into the new
+//!         image.  No response on success.
+//!    7c : applied config.  Response is fc with four u32 describing the config
+//!         applied at start-up: the slot index, format version, generation
+//!         number and length.  If no config was applied, the slot index is
+//!         ffffffff and the other fields are zero.  Note that the config
+//!         list (77) active flag shows the config that would be applied now,
+//!         which differs after an upload until the next reset.
 //!
-//!    74 : flash erase.  Erase a flash sector. u32 payload is the sector
-//!         address.
+//!    7d : unchecked peek.  As 71, but without the address check.  Responses
+//!         are fd.
+//!    7e : unchecked poke.  As 72, but without the address check.
+//!    7f : register access.  Payload is a u8 access width (1, 2 or 4 bytes)
+//!         and a u32 address, optionally followed by a u32 value.  Exactly one
+//!         volatile access of that width is made: a write of the value if
+//!         given, otherwise a read.  The address must be aligned to the width,
+//!         and lie within one of the peripheral windows (see peek), otherwise
+//!         NACK with BadParameter.  A read responds ff with the u32 address
+//!         and the u32 value read (zero extended), a write with an ACK.
 
-use stm_common::utils::nothing;
-use stm_common::vcell::UCell;
+use stm_common::utils::{barrier, nothing};
+use stm_common::vcell::{UCell, VCell};
 
 use crate::cpu::Priority;
-use crate::cpu::interrupt::PRIO_COMMS;
+use crate::cpu::interrupt::{PRIO_APP, PRIO_COMMS};
 use crate::gps_uart::GpsPriority;
-use crate::i2c;
-use crate::utils::vcopy_aligned;
+use crate::{flash, i2c};
+use crate::utils::{vcopy_aligned, vfill_aligned};
 
 mod crc16;
 
@@ -110,6 +454,14 @@ pub const TMP117: u8 = 0x92;
 /// I²C address of the UBlox GPS.
 pub const I2C_UBLOX: u8 = 0x84;
 
+/// GPIOA pins the GPIO update command may not touch: GPS TX (PA8), USB (PA11,
+/// PA12), SWD (PA13, PA14) and debug RX (PA15).
+const GPIOA_RESERVED: u32 = 0xf900;
+
+/// GPIOB pins the GPIO update command may not touch: debug TX (PB3), I²C (PB6,
+/// PB7) and GPS RX (PB15).
+const GPIOB_RESERVED: u32 = 0x80c8;
+
 /// Error codes for Nack responses.
 #[repr(u16)]
 #[derive(Debug, Default, Eq, PartialEq)]
@@ -128,6 +480,26 @@ enum Error {
     /// Special value used to indicate that an ACK (not a NACK) should be sent.
     /// This is never actually sent in a NACK packet.
     Succeeded      = 6,
+    /// I²C device did not acknowledge.
+    I2cNack        = 7,
+    /// I²C arbitration lost or bus error.
+    I2cArbLost     = 8,
+    /// I²C transaction did not complete.
+    I2cTimeout     = 9,
+    /// Flash target is already written.
+    FlashNotErased = 10,
+    /// Flash read back after programming did not match.
+    FlashVerify    = 11,
+    /// Flash write protection error (NSSR WRPERR).
+    FlashWriteProtect = 12,
+    /// Flash programming sequence error (NSSR PGSERR).
+    FlashSequence  = 13,
+    /// Flash strobe error (NSSR STRBERR).
+    FlashStrobe    = 14,
+    /// Flash inconsistency error (NSSR INCERR).
+    FlashInconsistent = 15,
+    /// GPS did not respond in time.
+    GpsTimeout     = 16,
 }
 
 type Result<T = ()> = core::result::Result<T, Error>;
@@ -136,6 +508,32 @@ impl From<()> for Error {
     fn from(_: ()) -> Error {Error::Failed}
 }
 
+impl From<i2c::Failure> for Error {
+    fn from(f: i2c::Failure) -> Error {
+        match f {
+            i2c::Failure::Nack    => Error::I2cNack,
+            i2c::Failure::ArbLost => Error::I2cArbLost,
+            i2c::Failure::Timeout => Error::I2cTimeout,
+        }
+    }
+}
+
+impl From<flash::Failure> for Error {
+    fn from(f: flash::Failure) -> Error {
+        match f {
+            flash::Failure::Range        => Error::BadParameter,
+            flash::Failure::Busy         => Error::Failed,
+            flash::Failure::NotErased    => Error::FlashNotErased,
+            flash::Failure::Verify       => Error::FlashVerify,
+            flash::Failure::WriteProtect => Error::FlashWriteProtect,
+            flash::Failure::Sequence     => Error::FlashSequence,
+            flash::Failure::Strobe       => Error::FlashStrobe,
+            flash::Failure::Inconsistent => Error::FlashInconsistent,
+            flash::Failure::Status(_)    => Error::Failed,
+        }
+    }
+}
+
 const SEND_ACK: Result = Err(Error::Succeeded);
 
 type Ack  = Message<()>;
@@ -176,10 +574,44 @@ pub struct Message<P> {
     crc1   : u8,
 }
 
+const BUILD_ID_LEN: usize = 20;
+
+/// Firmware build identifier string, baked in at compile time.  This is also
+/// a USB string descriptor, used as the configuration name.
+pub const BUILD_ID_STR: &str = match option_env!("FREAK_BUILD_ID") {
+    Some(id) => id,
+    None => env!("CARGO_PKG_VERSION"),
+};
+
+/// Firmware build identifier, as sent in response to command 06.
+const BUILD_ID: [u8; BUILD_ID_LEN] = build_id(BUILD_ID_STR);
+
+/// Truncate or NUL pad the build identifier to a fixed length.
+const fn build_id(id: &str) -> [u8; BUILD_ID_LEN] {
+    let bytes = id.as_bytes();
+    let mut result = [0; BUILD_ID_LEN];
+    let mut i = 0;
+    while i < bytes.len() && i < BUILD_ID_LEN {
+        result[i] = bytes[i];
+        i += 1;
+    }
+    result
+}
+
 /// Assigned device name, as a message.
 static NAME: UCell<MessageBuf> = Default::default();
 /// Assigned device name, in USB format.
-pub static USB_NAME: UCell<[u16; 32]> = UCell::new([0; _]);
+static USB_NAME: UCell<[u16; 32]> = UCell::new([0; _]);
+/// CPU serial number, in USB format.
+static USB_CPU_SERIAL: UCell<[u16; 32]> = UCell::new([0; _]);
+/// If set, the USB serial number is the CPU serial number, not the name.  Set
+/// at start-up from the config block, see command 33.
+static SERIAL_FROM_CPU: VCell<bool> = VCell::new(false);
+
+/// The string to use as the USB serial number, in USB format.
+pub fn usb_serial_number() -> &'static [u16; 32] {
+    if SERIAL_FROM_CPU.read() {USB_CPU_SERIAL.as_ref()} else {USB_NAME.as_ref()}
+}
 
 pub fn init(serial: &str) {
     let name = unsafe {NAME.as_mut()};
@@ -190,6 +622,13 @@ pub fn init(serial: &str) {
     name.len = len as u8;
     name.payload[..len].copy_from_slice(sbytes.as_ref());
     str_to_usb(unsafe {USB_NAME.as_mut()}, serial);
+    str_to_usb(unsafe {USB_CPU_SERIAL.as_mut()}, serial);
+}
+
+/// Does the string fit in a USB string descriptor of `words` u16, including
+/// the header?
+fn fits_usb(s: &str, words: usize) -> bool {
+    s.encode_utf16().count() < words
 }
 
 fn str_to_usb(out: &mut [u16], s: &str) {
@@ -274,6 +713,17 @@ pub fn command_handler(message: &MessageBuf, len: usize, r: Responder) {
     }
 }
 
+/// Copy a received message into `buf`, and then handle it.  The handlers need
+/// a 32-bit aligned, 64 byte, buffer, and copying first means that the source
+/// may be reused, e.g., by a further USB RX, while the command is processed.
+pub fn copy_and_handle(buf: &mut MessageBuf, data: *const u8, len: usize,
+                       r: Responder) {
+    const {assert!(align_of::<MessageBuf>() == 4)};
+    let len = len.min(size_of::<MessageBuf>());
+    unsafe {vcopy_aligned(buf as *mut MessageBuf as *mut u8, data, len)};
+    command_handler(buf, len, r);
+}
+
 fn command_dispatch(message: &MessageBuf, len: usize, r: Responder) -> Result {
     // dbgln!("Command handler dispatch {:x?}",
     //       unsafe {core::slice::from_raw_parts(message as *const _ as *const u8, len)});
@@ -294,17 +744,60 @@ fn command_dispatch(message: &MessageBuf, len: usize, r: Responder) -> Result {
 
     match message.code {
         0x00 => ping(message, r),
+        0x01 => ping_stress(message, r),
         0x02 => get_protocol_version(message, r),
         0x03 => get_serial_number(message, r),
         0x04 => set_get_name(message, r),
+        0x05 => get_reset_cause(message, r),
+        0x06 => get_build_id(message, r),
+        0x07 => get_board_variant(message, r),
+        0x08 => get_uptime(message, r),
+        0x09 => get_unique_id(message, r),
+        0x0a => get_clock_config(message, r),
+        0x0b => dma_errors(message, r),
+        0x0c => self_test(message, r),
+        0x0d => crc_self_test(message, r),
+        0x0e => watchdog_status(message, r),
+        0x0f => log_level(message, r),
 
         0x10 => crate::cpu::reboot(),
         0x11 => gps_reset(message),
         0x12 => lmk_powerdown(message),
+        0x13 => trigger_dfu(message),
+        0x14 => hsi48(message, r),
+        0x15 => tick_rate(message, r),
+        0x16 => bor_level(message, r),
+        0x17 => lmk_reapply(message),
+        0x18 => led_timing(message, r),
+        0x19 => gpio_update(message, r),
+        0x1a => lmk05318b_poll_rate(message, r),
 
-        0x1e => serial_sync(message),
+        0x1e => serial_sync(message, r),
         0x1f => set_get_baud(message, r),
 
+        0x20 => gps_rx_errors(message, r),
+        0x21 => gps_link_stats(message, r),
+        0x22 => gps_loopback(message, r),
+        0x23 => gps_dma_tuning(message, r),
+        0x24 => gps_actual_baud(message, r),
+        0x25 => ubx_poll(message, r),
+        0x26 => gps_framing(message, r),
+        0x27 => gps_flush(message),
+        0x28 => dtr_reset(message, r),
+        0x29 => host_baud(message, r),
+
+        0x30 => set_get_usb_mode(message, r),
+        0x31 => get_usb_errata_delay(message, r),
+        0x32 => get_usb_string(message, r),
+        0x33 => usb_serial_source(message, r),
+        0x34 => usb_sof_stats(message, r),
+        0x35 => usb_setup_log(message, r),
+        0x36 => usb_stall(message),
+        0x37 => get_usb_string_raw(message, r),
+
+        0x50 => deferred_poll(message, r),
+        0x51 => deferred_start(message),
+
         0x60 => i2c_write(crate::lmk05318b::LMK05318 & !1, message),
         0x61 => i2c_read (crate::lmk05318b::LMK05318 |  1, message, r),
         0x62 => i2c_write(TMP117 & !1, message),
@@ -312,13 +805,32 @@ fn command_dispatch(message: &MessageBuf, len: usize, r: Responder) -> Result {
         0x64 => i2c_write(I2C_UBLOX & !1, message),
         0x65 => i2c_read (I2C_UBLOX |  1, message, r),
 
+        0x66 => tmp117_temperature(message, r),
+        0x67 => lmk05318b_frequencies(message, r),
         0x68 => lmk05318b_status(message),
+        0x69 => lmk05318b_raw_status(message, r),
+        0x6a => i2c_recover(message, r),
+        0x6b => i2c_speed(message, r),
+        0x6c => tmp117_read_regs(message, r),
+        0x6d => lmk05318b_nvm_read(message, r),
+        0x6e => lmk05318b_nvm_commit(message),
 
-        0x71 => peek(message, r),
-        0x72 => poke(message),
+        0x70 => crc_chunk(message, r),
+        0x71 => peek(message, r, true),
+        0x72 => poke(message, true),
         0x73 => get_crc(message, r),
         0x74 => flash_erase(message),
+        0x75 => fill(message),
+        0x76 => config_upload(message, r),
+        0x77 => config_list(message, r),
         0x78 => test_gps_write(message),
+        0x79 => flash_erase_bank(message),
+        0x7a => bank_crc(message, r),
+        0x7b => bank_swap(message),
+        0x7c => applied_config(message, r),
+        0x7d => peek(message, r, false),
+        0x7e => poke(message, false),
+        0x7f => register_access(message, r),
 
         _ => Err(Error::UnknownMessage)
     }
@@ -328,11 +840,50 @@ fn ping(message: &MessageBuf, r: Responder) -> Result {
     // Send a generic ACK with the same payload.
     let mut resp = MessageBuf::start(0x80);
     let len = message.len as usize;
+    if len == 0 {
+        // Health probe: uptime and reset cause.
+        resp.len = 9;
+        resp.payload[..8].copy_from_slice(
+            &crate::tick::uptime_ms().to_le_bytes());
+        resp.payload[8] = crate::cpu::peek_reset_cause() as u8;
+        return resp.send(r);
+    }
     resp.len = len as u8;
     resp.payload[..len].copy_from_slice(&message.payload[..len]);
     resp.send(r)
 }
 
+fn ping_stress(message: &MessageBuf, r: Responder) -> Result {
+    let (seed, length) = Message::<(u32, u32)>::from_buf(message)?.payload;
+    if length > 4096 {
+        return Err(Error::BadParameter);
+    }
+    let mut state = if seed == 0 {1} else {seed};
+    let mut remaining = length as usize;
+    while remaining > 0 {
+        let mut resp = MessageBuf::start(0x80);
+        let len = remaining.min(MAX_PAYLOAD);
+        stress_fill(&mut state, &mut resp.payload[..len]);
+        resp.len = len as u8;
+        resp.send(r)?;
+        remaining -= len;
+    }
+    // Terminator.
+    SEND_ACK
+}
+
+/// Fill `buf` with the low bytes of successive xorshift32 states.
+fn stress_fill(state: &mut u32, buf: &mut [u8]) {
+    for b in buf {
+        let mut x = *state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        *state = x;
+        *b = x as u8;
+    }
+}
+
 fn get_protocol_version(message: &MessageBuf, r: Responder) -> Result {
     Message::<()>::from_buf(message)?;
     Message::new(0x82, 1u32).send(r)
@@ -344,6 +895,54 @@ fn get_serial_number(message: &MessageBuf, r: Responder) -> Result {
     Message::new(0x83, *sn).send(r)
 }
 
+fn get_build_id(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    Message::new(0x86, BUILD_ID).send(r)
+}
+
+fn get_unique_id(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    Message::new(0x89, *crate::cpu::UNIQUE_ID.as_ref()).send(r)
+}
+
+fn get_clock_config(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    Message::new(0x8a, crate::cpu::clock_config()).send(r)
+}
+
+fn get_uptime(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    let uptime = crate::tick::uptime_ms();
+    Message::new(0x88, [uptime as u32, (uptime >> 32) as u32]).send(r)
+}
+
+fn watchdog_status(message: &MessageBuf, r: Responder) -> Result {
+    use crate::watchdog::{TIMEOUT_MS, was_watchdog_reset};
+    Message::<()>::from_buf(message)?;
+    Message::new(0x8e, [was_watchdog_reset() as u32, TIMEOUT_MS]).send(r)
+}
+
+fn log_level(message: &MessageBuf, r: Responder) -> Result {
+    if message.len > 0 {
+        let level = Message::<u8>::from_buf(message)?.payload;
+        if !crate::debug::set_level(level) {
+            return Err(Error::BadParameter);
+        }
+    }
+    Message::new(0x8f, crate::debug::get_level()).send(r)
+}
+
+fn get_reset_cause(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    Message::new(0x85, crate::cpu::take_reset_cause()).send(r)
+}
+
+fn get_board_variant(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    let variant = *crate::cpu::IS_PROTOTYPE.as_ref() as u8;
+    Message::new(0x87, [variant, crate::cpu::VARIANT_BY_UID]).send(r)
+}
+
 fn set_get_name(message: &MessageBuf, r: Responder) -> Result {
     let name = unsafe {NAME.as_mut()};
     let len = message.len as usize;
@@ -352,9 +951,19 @@ fn set_get_name(message: &MessageBuf, r: Responder) -> Result {
         return Err(Error::FramingError);
     }
     if len > 0 {
-        let payload = &message.payload[..len];
+        let payload: &[u8] = if len == 1 && message.payload[0] == 0 {
+            // Reset to the default.
+            crate::cpu::SERIAL_NUMBER.as_ref()
+        }
+        else {
+            &message.payload[..len]
+        };
+        let len = payload.len();
         let Ok(utf8) = str::from_utf8(payload)
             else {return Err(Error::BadParameter)};
+        if !fits_usb(utf8, USB_NAME.as_ref().len()) {
+            return Err(Error::BadParameter);
+        }
         // Potentially we are racing with a USB string fetch, so raise the
         // interrupt priority while storing the new string.
         let prio = Priority::<PRIO_COMMS>::default();
@@ -366,20 +975,79 @@ fn set_get_name(message: &MessageBuf, r: Responder) -> Result {
     name.send(r)
 }
 
+fn dma_errors(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    Message::new(0x8b, crate::dma::take_errors()).send(r)
+}
+
+fn self_test(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    let image_ok = crate::flash::active_image_ok();
+
+    let mut id = [0u16];
+    let tmp117_ok = i2c::read_regs16(TMP117, 0x0f, &mut id).is_ok()
+        && id[0] & 0xfff == 0x117;
+
+    let mut vendor = 0u16;
+    let lmk_ok = i2c::write_read(
+        crate::lmk05318b::LMK05318, &0u16.to_be(), &mut vendor).wait().is_ok()
+        && u16::from_be(vendor) == 0x100b;
+
+    /// A pattern that lives in flash, for the readback check.
+    const PATTERN: [u32; 2] = [0x5a5aa5a5, 0x0ff0f00f];
+    static FLASH_PATTERN: [u32; 2] = PATTERN;
+    let flash_ok = unsafe {
+        core::ptr::read_volatile(&FLASH_PATTERN)} == PATTERN;
+
+    let result = image_ok as u32 | (tmp117_ok as u32) << 1
+        | (lmk_ok as u32) << 2 | (flash_ok as u32) << 3;
+    Message::new(0x8c, result).send(r)
+}
+
+fn crc_self_test(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    let result = crc_check(crc16::hw_compute, crate::crc32::hw_compute);
+    Message::new(0x8d, result).send(r)
+}
+
+/// Check CRC implementations against known values.  Bit 0 of the result is
+/// set if the CRC-16 is good, bit 1 if the CRC-32 is good.
+fn crc_check(crc16: fn(&[u8]) -> u16, crc32: fn(*const u8, usize) -> u32)
+    -> u8 {
+    let crc16_ok = crc16(b"123456789") == 0x31c3;
+
+    let mut block = [1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 0, 0, 0, 0];
+    let len = block.len() - 4;
+    let crc = crc32(block.as_ptr(), len);
+    block[len ..].copy_from_slice(&crc.to_be_bytes());
+    let crc32_ok
+        = crc32(block.as_ptr(), block.len()) == crate::crc32::VERIFY_MAGIC;
+
+    crc16_ok as u8 | (crc32_ok as u8) << 1
+}
+
 fn gps_reset(message: &MessageBuf) -> Result {
-    let gpiob = unsafe {&*stm32h503::GPIOB::ptr()};
-    let message = Message::<u8>::from_buf(message)?;
-    if message.payload != 1 {
-        gpiob.BSRR.write(|w| w.BR1().set_bit());
+    let (action, width) = if message.len == 1 {
+        (Message::<u8>::from_buf(message)?.payload, 1000)
     }
-    if message.payload > 1 {
-        // Sleep for approx, 1ms.
-        for _ in 0 .. crate::cpu::CPU_FREQ / 2000 {
+    else {
+        let payload = Message::<[u8; 5]>::from_buf(message)?.payload;
+        (payload[0], u32::from_le_bytes(payload[1..].try_into().unwrap()))
+    };
+    if width > 1000000 {
+        return Err(Error::BadParameter);
+    }
+    if action != 1 {
+        crate::gps_uart::set_reset(true);
+    }
+    if action > 1 {
+        // Sleep for approx, the pulse width.
+        for _ in 0 .. width * (crate::cpu::CPU_FREQ / 2000000) {
             nothing();
         }
     }
-    if message.payload != 0 {
-        gpiob.BSRR.write(|w| w.BS1().set_bit());
+    if action != 0 {
+        crate::gps_uart::set_reset(false);
     }
     SEND_ACK
 }
@@ -402,39 +1070,383 @@ fn lmk_powerdown(message: &MessageBuf) -> Result {
     SEND_ACK
 }
 
-fn serial_sync(message: &MessageBuf) -> Result {
+fn tick_rate(message: &MessageBuf, r: Responder) -> Result {
+    if message.len > 0 {
+        let rate = Message::<u32>::from_buf(message)?.payload;
+        if !crate::tick::set_rate(rate) {
+            return Err(Error::BadParameter);
+        }
+    }
+    Message::new(0x95, crate::tick::get_rate()).send(r)
+}
+
+fn lmk05318b_poll_rate(message: &MessageBuf, r: Responder) -> Result {
+    use crate::lmk05318b::{get_poll_rate, set_poll_rate};
+    if message.len > 0 {
+        let rate = Message::<u32>::from_buf(message)?.payload;
+        // We run at the same priority as the LMK05318b status handling.
+        if !set_poll_rate(rate) {
+            return Err(Error::BadParameter);
+        }
+    }
+    Message::new(0x9a, get_poll_rate()).send(r)
+}
+
+fn led_timing(message: &MessageBuf, r: Responder) -> Result {
+    use crate::led::{BLUE, RED_GREEN};
+    if message.len > 0 {
+        let [led, on, off] = Message::<[u16; 3]>::from_buf(message)?.payload;
+        let led = match led {
+            0 => &BLUE,
+            1 => &RED_GREEN,
+            _ => return Err(Error::BadParameter),
+        };
+        if !led.set_timing(on as i16, off as i16) {
+            return Err(Error::BadParameter);
+        }
+    }
+    let (blue_on, blue_off) = BLUE.timing();
+    let (rg_on, rg_off) = RED_GREEN.timing();
+    Message::new(0x98, [blue_on as u16, blue_off as u16,
+                        rg_on as u16, rg_off as u16]).send(r)
+}
+
+fn lmk_reapply(message: &MessageBuf) -> Result {
+    Message::<()>::from_buf(message)?;
+    if !crate::provision::config_available() {
+        return Err(Error::Failed);
+    }
+    let gpioa = unsafe {&*stm32h503::GPIOA::ptr()};
+    gpioa.BSRR.write(|w| w.BR4().set_bit());
+    // Sleep for approx, 10µs.
+    for _ in 0 .. crate::cpu::CPU_FREQ / 200000 {
+        nothing();
+    }
+    gpioa.BSRR.write(|w| w.BS4().set_bit());
+    // As at start-up, spin for ≈100ms to wait for the clock generator.
+    for _ in 0 .. crate::cpu::CPU_FREQ / 20 {
+        nothing();
+    }
+    crate::provision::reapply_lmk();
+    crate::lmk05318b::update_status();
+    SEND_ACK
+}
+
+fn hsi48(message: &MessageBuf, r: Responder) -> Result {
+    let enable = if message.len == 0 {None}
+        else {Some(Message::<u8>::from_buf(message)?.payload != 0)};
+    let state = crate::usb::hsi48(enable)?;
+    Message::new(0x94, state).send(r)
+}
+
+fn gpio_update(message: &MessageBuf, r: Responder) -> Result {
+    let gpioa = unsafe {&*stm32h503::GPIOA::ptr()};
+    let gpiob = unsafe {&*stm32h503::GPIOB::ptr()};
+    let [port, set, clear] = Message::<[u32; 3]>::from_buf(message)?.payload;
+    let reserved = match port {
+        0 => GPIOA_RESERVED,
+        1 => GPIOB_RESERVED,
+        _ => return Err(Error::BadParameter),
+    };
+    if (set | clear) & (reserved | 0xffff0000) != 0 {
+        return Err(Error::BadParameter);
+    }
+    let bsrr = set | clear << 16;
+    let state = if port == 0 {
+        gpioa.BSRR.write(|w| w.bits(bsrr));
+        [gpioa.IDR().read().bits(), gpioa.ODR().read().bits()]
+    }
+    else {
+        gpiob.BSRR.write(|w| w.bits(bsrr));
+        [gpiob.IDR().read().bits(), gpiob.ODR().read().bits()]
+    };
+    Message::new(0x99, state).send(r)
+}
+
+fn serial_sync(message: &MessageBuf, r: Responder) -> Result {
     let message = Message::<u32>::from_buf(message)?;
     if message.payload > 1000000 {
         return Err(Error::BadParameter);
     }
-    crate::gps_uart::wait_for_tx_idle();
+    use crate::gps_uart::{TX_MAX_LEN, tx_time, wait_for_tx_idle_timeout};
+    let idle_wait = || {
+        let start = crate::tick::cycles();
+        if !wait_for_tx_idle_timeout(tx_time(TX_MAX_LEN)) {
+            return Err(Error::GpsTimeout);
+        }
+        Ok(crate::tick::cycles().wrapping_sub(start)
+           / (crate::cpu::CPU_FREQ / 1000000))
+    };
+    let before = idle_wait()?;
     for _ in 0 .. message.payload * (crate::cpu::CPU_FREQ / 2000000) {
         nothing();
     }
-    crate::gps_uart::wait_for_tx_idle();
-    SEND_ACK
+    let after = idle_wait()?;
+    Message::new(0x80, [before, after]).send(r)
 }
 
 fn set_get_baud(message: &MessageBuf, r: Responder) -> Result {
     let _prio = GpsPriority::default();
     if message.len > 0 {
         let message = Message::<u32>::from_buf(message)?;
-        crate::gps_uart::set_baud_rate(message.payload);
+        if !crate::gps_uart::set_baud_rate(message.payload) {
+            return Err(Error::BadParameter);
+        }
     }
     Message::<u32>::new(0x9f, crate::gps_uart::get_baud_rate()).send(r)
 }
 
-fn i2c_write(address: u8, message: &MessageBuf) -> Result {
-    dbgln!("I2C write {address:#04x} length {}", message.len);
-    // Write.
-    if let Ok(()) = i2c::write(address, message.get_payload()).wait() {
+fn gps_rx_errors(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    Message::new(0xa0, crate::gps_uart::take_rx_errors()).send(r)
+}
+
+fn gps_link_stats(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    Message::new(0xa1, crate::gps_uart::link_stats()).send(r)
+}
+
+fn gps_loopback(message: &MessageBuf, r: Responder) -> Result {
+    if message.len > 0 {
+        let loopback = Message::<u8>::from_buf(message)?.payload != 0;
+        crate::gps_uart::set_loopback(loopback);
+    }
+    Message::new(0xa2, crate::gps_uart::get_loopback() as u8).send(r)
+}
+
+fn gps_dma_tuning(message: &MessageBuf, r: Responder) -> Result {
+    let _prio = GpsPriority::default();
+    if message.len > 0 {
+        let [prio, fifo] = Message::<[u8; 2]>::from_buf(message)?.payload;
+        if prio > 3 || fifo > 5 {
+            return Err(Error::BadParameter);
+        }
+        if !crate::gps_uart::set_dma_tuning(prio, fifo) {
+            return Err(Error::Failed);
+        }
+    }
+    Message::new(0xa3, crate::gps_uart::get_dma_tuning()).send(r)
+}
+
+fn gps_actual_baud(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    Message::new(0xa4, crate::gps_uart::actual_baud()).send(r)
+}
+
+fn ubx_poll(message: &MessageBuf, r: Responder) -> Result {
+    /// How long to wait for the reply, in CPU cycles.
+    const TIMEOUT: u32 = crate::cpu::CPU_FREQ;
+    let frame = &message.payload[.. message.len as usize];
+    if !crate::ubx::frame_ok(frame) {
+        return Err(Error::BadFormat);
+    }
+    let (class, id) = (frame[2], frame[3]);
+
+    let prio = GpsPriority::default();
+    crate::gps_uart::rx_snoop_start();
+    gps_write(&prio, frame)?;
+
+    let mut capture = crate::ubx::FrameCapture::<MAX_PAYLOAD>::new(class, id);
+    let mut acks = crate::ubx::AckParser::default();
+    let start = crate::tick::cycles();
+    let len = loop {
+        let mut result = None;
+        crate::gps_uart::rx_snoop(|b| {
+            if result.is_some() {
+                return;
+            }
+            if let Some(len) = capture.push(b) {
+                result = Some(Ok(len));
+            }
+            if let Some(ack) = acks.push(b)
+                && !ack.ok && ack.class == class && ack.id == id {
+                result = Some(Err(Error::Failed));
+            }
+        });
+        if let Some(result) = result {
+            break result?;
+        }
+        if capture.overflow {
+            return Err(Error::Failed);
+        }
+        if crate::tick::cycles().wrapping_sub(start) > TIMEOUT {
+            return Err(Error::GpsTimeout);
+        }
+        prio.wfe();
+    };
+    drop(prio);
+
+    let mut resp = MessageBuf::start(0xa5);
+    resp.len = len as u8;
+    resp.payload[.. len].copy_from_slice(&capture.buf[.. len]);
+    resp.send(r)
+}
+
+fn gps_framing(message: &MessageBuf, r: Responder) -> Result {
+    let _prio = GpsPriority::default();
+    if message.len > 0 {
+        let [data, parity, stop]
+            = Message::<[u8; 3]>::from_buf(message)?.payload;
+        if !crate::gps_uart::set_framing(data, parity, stop) {
+            return Err(Error::BadParameter);
+        }
+    }
+    Message::new(0xa6, crate::gps_uart::get_framing()).send(r)
+}
+
+fn gps_flush(message: &MessageBuf) -> Result {
+    let timeout_ms = if message.len == 0 {1000}
+        else {Message::<u32>::from_buf(message)?.payload};
+    if timeout_ms > 1500 {
+        return Err(Error::BadParameter);
+    }
+    let timeout = timeout_ms * (crate::cpu::CPU_FREQ / 1000);
+    if !crate::gps_uart::wait_for_tx_idle_timeout(timeout) {
+        return Err(Error::GpsTimeout);
+    }
+    SEND_ACK
+}
+
+fn dtr_reset(message: &MessageBuf, r: Responder) -> Result {
+    if message.len > 0 {
+        let enable = Message::<u8>::from_buf(message)?.payload != 0;
+        crate::usb::serial::set_dtr_reset(enable);
+    }
+    Message::new(0xa8, crate::usb::serial::get_dtr_reset() as u8).send(r)
+}
+
+fn host_baud(message: &MessageBuf, r: Responder) -> Result {
+    use crate::usb::serial::{get_follow_host_baud, host_baud,
+                             set_follow_host_baud};
+    let _prio = GpsPriority::default();
+    if message.len > 0 {
+        match Message::<u8>::from_buf(message)?.payload {
+            0 => set_follow_host_baud(false),
+            1 => set_follow_host_baud(true),
+            2 => if !crate::gps_uart::set_baud_rate(host_baud()) {
+                return Err(Error::BadParameter);
+            }
+            _ => return Err(Error::BadParameter),
+        }
+    }
+    Message::new(0xa9, [host_baud(), get_follow_host_baud() as u32]).send(r)
+}
+
+fn set_get_usb_mode(message: &MessageBuf, r: Responder) -> Result {
+    if message.len > 0 {
+        let mode = Message::<u8>::from_buf(message)?.payload;
+        let Some(mode) = crate::usb::UsbMode::from_u8(mode)
+            else {return Err(Error::BadParameter)};
+        if crate::usb::set_mode(mode) {
+            // We have dropped off the bus, nobody is listening.
+            return Ok(());
+        }
+    }
+    Message::new(0xb0, crate::usb::get_mode() as u8).send(r)
+}
+
+fn get_usb_errata_delay(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    use crate::usb::{ERRATA_DELAY_NS, errata_delay};
+    // Time the delay itself, without the USB interrupt getting in the way.
+    let cycles = {
+        let _prio = Priority::<PRIO_COMMS>::default();
+        let start = crate::tick::cycles();
+        errata_delay();
+        crate::tick::cycles().wrapping_sub(start)
+    };
+    Message::new(0xb1, [cycles, ERRATA_DELAY_NS, crate::cpu::CPU_FREQ]).send(r)
+}
+
+fn get_usb_string(message: &MessageBuf, r: Responder) -> Result {
+    use crate::usb::{IDX_SERIAL_NUMBER, string_utf8};
+    let idx = Message::<u8>::from_buf(message)?.payload;
+    let mut resp = MessageBuf::start(0xb2);
+    resp.payload[0] = idx;
+    resp.payload[1] = IDX_SERIAL_NUMBER + 1;
+    // The serial number string may be changed by a concurrent name update.
+    let prio = Priority::<PRIO_COMMS>::default();
+    let len = string_utf8(idx, &mut resp.payload[2..MAX_PAYLOAD]);
+    drop(prio);
+    let Some(len) = len else {return Err(Error::BadParameter)};
+    resp.len = 2 + len as u8;
+    resp.send(r)
+}
+
+fn get_usb_string_raw(message: &MessageBuf, r: Responder) -> Result {
+    let (idx, offset) = if message.len == 1 {
+        (Message::<u8>::from_buf(message)?.payload, 0)
+    }
+    else {
+        let [idx, offset] = Message::<[u8; 2]>::from_buf(message)?.payload;
+        (idx, offset as usize)
+    };
+    let mut resp = MessageBuf::start(0xb7);
+    resp.payload[0] = idx;
+    // The serial number string may be changed by a concurrent name update.
+    let _prio = Priority::<PRIO_COMMS>::default();
+    let Some(data) = crate::usb::string_raw(idx) else {
+        return Err(Error::BadParameter)};
+    if offset > data.len() {
+        return Err(Error::BadParameter);
+    }
+    let len = (data.len() - offset).min(MAX_PAYLOAD - 2);
+    resp.payload[1] = data.len() as u8;
+    resp.payload[2 .. 2 + len].copy_from_slice(&data[offset .. offset + len]);
+    resp.len = 2 + len as u8;
+    resp.send(r)
+}
+
+fn usb_serial_source(message: &MessageBuf, r: Responder) -> Result {
+    if message.len > 0 {
+        match Message::<u8>::from_buf(message)?.payload {
+            0 => SERIAL_FROM_CPU.write(false),
+            1 => SERIAL_FROM_CPU.write(true),
+            _ => return Err(Error::BadParameter),
+        }
+    }
+    Message::new(0xb3, SERIAL_FROM_CPU.read() as u8).send(r)
+}
+
+fn usb_sof_stats(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    let [count, total, min, max] = crate::usb::sof::get_stats();
+    Message::new(0xb4, [count, total, min, max, crate::cpu::CPU_FREQ]).send(r)
+}
+
+fn usb_setup_log(message: &MessageBuf, r: Responder) -> Result {
+    use crate::usb::setup_log;
+    if message.len > 0 {
+        match Message::<u8>::from_buf(message)?.payload {
+            0 => setup_log::enable(false),
+            1 => setup_log::enable(true),
+            _ => return Err(Error::BadParameter),
+        }
+    }
+    let mut setups = [[0; 8]; setup_log::LOG_SIZE];
+    let num = setup_log::get(&mut setups);
+    Message::new(0xb5, (setup_log::enabled() as u8, num as u8, setups)).send(r)
+}
+
+fn usb_stall(message: &MessageBuf) -> Result {
+    let address = Message::<u8>::from_buf(message)?.payload;
+    if crate::usb::halt::stall(address) {
         SEND_ACK
     }
     else {
-        Err(Error::Failed)
+        Err(Error::BadParameter)
     }
 }
 
+fn i2c_write(address: u8, message: &MessageBuf) -> Result {
+    dbgln!("I2C write {address:#04x} length {}", message.len);
+    // Write.
+    i2c::clear_failure();
+    let w = i2c::write(address, message.get_payload());
+    i2c::note_result(w.wait())?;
+    SEND_ACK
+}
+
 fn i2c_read(address: u8, message: &MessageBuf, r: Responder) -> Result {
     // Get the length...
     let mlen = message.len as usize;
@@ -448,6 +1460,7 @@ fn i2c_read(address: u8, message: &MessageBuf, r: Responder) -> Result {
     }
     let mut result = MessageBuf::start(message.code | 0x80);
     result.len = rlen as u8;
+    i2c::clear_failure();
     let w;
     if mlen == 1 {
         w = i2c::read(address, &mut result.payload[..rlen]);
@@ -456,12 +1469,23 @@ fn i2c_read(address: u8, message: &MessageBuf, r: Responder) -> Result {
         w = i2c::write_read(address, &message.payload[1..mlen],
                             &mut result.payload[..rlen]);
     }
-    if let Ok(()) = w.wait() {
-        result.send(r)
-    }
-    else {
-        Err(Error::Failed)
+    i2c::note_result(w.wait())?;
+    result.send(r)
+}
+
+fn i2c_recover(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    Message::new(0xea, i2c::bus_recover() as u8).send(r)
+}
+
+fn i2c_speed(message: &MessageBuf, r: Responder) -> Result {
+    if message.len > 0 {
+        let speed = Message::<u32>::from_buf(message)?.payload;
+        if !i2c::set_speed(speed) {
+            return Err(Error::BadParameter);
+        }
     }
+    Message::new(0xeb, i2c::get_speed()).send(r)
 }
 
 fn lmk05318b_status(message: &MessageBuf) -> Result {
@@ -472,14 +1496,172 @@ fn lmk05318b_status(message: &MessageBuf) -> Result {
     SEND_ACK
 }
 
-fn peek(message: &MessageBuf, r: Responder) -> Result {
+fn lmk05318b_raw_status(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    let raw = crate::lmk05318b::raw_status()?;
+    Message::new(0xe9, raw).send(r)
+}
+
+fn tmp117_temperature(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    let mut raw = 0i16;
+    i2c::read_reg(TMP117, 0, &mut raw).wait()?;
+    // The TMP117 result register is big-endian, in units of 1/128 °C.
+    let centidegrees = i16::from_be(raw) as i32 * 100 / 128;
+    Message::new(0xe6, centidegrees).send(r)
+}
+
+fn tmp117_read_regs(message: &MessageBuf, r: Responder) -> Result {
+    let [reg, count] = Message::<[u8; 2]>::from_buf(message)?.payload;
+    let count = count as usize;
+    if count == 0 || count > MAX_PAYLOAD / 2 {
+        return Err(Error::BadParameter);
+    }
+    let mut values = [0u16; MAX_PAYLOAD / 2];
+    i2c::read_regs16(TMP117, reg, &mut values[..count])?;
+    let mut result = MessageBuf::start(0xec);
+    for (i, v) in values[..count].iter().enumerate() {
+        result.payload[i * 2 .. i * 2 + 2].copy_from_slice(&v.to_le_bytes());
+    }
+    result.len = (count * 2) as u8;
+    result.send(r)
+}
+
+fn lmk05318b_nvm_read(message: &MessageBuf, r: Responder) -> Result {
+    let [lo, hi, len] = Message::<[u8; 3]>::from_buf(message)?.payload;
+    let address = u16::from_le_bytes([lo, hi]);
+    let len = len as usize;
+    if len > MAX_PAYLOAD - 2
+        || address as usize + len > crate::lmk05318b::NVM_SIZE {
+        return Err(Error::BadParameter);
+    }
+    let mut result = MessageBuf::start(0xed);
+    result.payload[..2].copy_from_slice(&address.to_le_bytes());
+    crate::lmk05318b::nvm_read(address, &mut result.payload[2 .. 2 + len])?;
+    result.len = 2 + len as u8;
+    result.send(r)
+}
+
+/// Confirmation magic for the LMK05318b EEPROM commit, "NVMW".
+const NVM_COMMIT_MAGIC: u32 = u32::from_le_bytes(*b"NVMW");
+
+fn lmk05318b_nvm_commit(message: &MessageBuf) -> Result {
+    let [magic, verify_len] = Message::<[u32; 2]>::from_buf(message)?.payload;
+    if magic != NVM_COMMIT_MAGIC {
+        return Err(Error::BadParameter);
+    }
+    if verify_len as usize > crate::lmk05318b::NVM_SIZE {
+        return Err(Error::BadParameter);
+    }
+    if !crate::lmk05318b::nvm_commit(verify_len as usize)? {
+        return Err(Error::Failed);
+    }
+    SEND_ACK
+}
+
+fn lmk05318b_frequencies(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    let freqs = crate::lmk05318b::output_frequencies()?;
+    Message::new(0xe7, freqs).send(r)
+}
+
+/// Memory windows, [start, end), that peek and poke accept.
+const MEMORY_WINDOWS: [(u32, u32); 4] = [
+    (0x08000000, 0x08020000),           // Flash.
+    (0x08fff000, 0x09000000),           // OTP and read-only area.
+    (0x0bf80000, 0x0bf88000),           // System memory.
+    (0x20000000, 0x20008000),           // SRAM1 and SRAM2.
+];
+
+/// Peripheral windows, [start, end), that peek and poke, and the register
+/// access, accept.  This is coarse; the windows include reserved addresses.
+const PERIPHERAL_WINDOWS: [(u32, u32); 4] = [
+    (0x40000000, 0x40040000),           // APB1, APB2 and AHB1 peripherals.
+    (0x42020000, 0x420d0000),           // AHB2 peripherals.
+    (0x44000000, 0x44030000),           // APB3 and AHB3 peripherals.
+    (0xe0000000, 0xe0100000),           // Cortex-M private peripherals.
+];
+
+/// Is the range of `length` bytes from `address` within one of the
+/// `MEMORY_WINDOWS` or `PERIPHERAL_WINDOWS`?
+fn address_ok(address: u32, length: usize) -> bool {
+    in_windows(&MEMORY_WINDOWS, address, length)
+        || in_windows(&PERIPHERAL_WINDOWS, address, length)
+}
+
+fn in_windows(windows: &[(u32, u32)], address: u32, length: usize) -> bool {
+    let end = address as u64 + length as u64;
+    windows.iter().any(
+        |&(start, stop)| start <= address && end <= stop as u64)
+}
+
+/// Is `address` an aligned peripheral register address of the `width`?
+fn register_ok(address: u32, width: u8) -> bool {
+    matches!(width, 1 | 2 | 4) && address % width as u32 == 0
+        && in_windows(&PERIPHERAL_WINDOWS, address, width as usize)
+}
+
+fn register_access(message: &MessageBuf, r: Responder) -> Result {
+    use core::ptr::{read_volatile, write_volatile};
+    let payload = message.get_payload();
+    if payload.len() != 5 && payload.len() != 9 {
+        return Err(Error::BadFormat);
+    }
+    let width = payload[0];
+    let address = u32::from_le_bytes(payload[1..5].try_into().unwrap());
+    if !register_ok(address, width) {
+        return Err(Error::BadParameter);
+    }
+    if payload.len() == 9 {
+        let value = u32::from_le_bytes(payload[5..].try_into().unwrap());
+        unsafe {
+            match width {
+                1 => write_volatile(address as *mut u8 , value as u8),
+                2 => write_volatile(address as *mut u16, value as u16),
+                _ => write_volatile(address as *mut u32, value),
+            }
+        }
+        return SEND_ACK;
+    }
+    let value = unsafe {
+        match width {
+            1 => read_volatile(address as *const u8 ) as u32,
+            2 => read_volatile(address as *const u16) as u32,
+            _ => read_volatile(address as *const u32),
+        }
+    };
+    Message::new(0xff, [address, value]).send(r)
+}
+
+/// Maximum length for a chunked peek.
+const MAX_PEEK: usize = 4096;
+
+fn peek(message: &MessageBuf, r: Responder, check: bool) -> Result {
+    let code = message.code | 0x80;
     let message = Message::<(u32, u32)>::from_buf(message)?;
     let (address, length) = message.payload;
     let length = length as usize;
-    if length > MAX_PAYLOAD - 4 {
+    if length > MAX_PEEK || check && !address_ok(address, length) {
         return Err(Error::BadParameter);
     }
-    let mut result = MessageBuf::start(0xf1);
+    if length <= MAX_PAYLOAD - 4 {
+        return peek_chunk(code, address, length, r);
+    }
+    // Chunked mode.  Keep the chunks word aligned, so that register reads
+    // remain at the correct width.
+    const CHUNK: usize = (MAX_PAYLOAD - 4) & !3;
+    let mut done = 0;
+    while done < length {
+        let todo = CHUNK.min(length - done);
+        peek_chunk(code, address.wrapping_add(done as u32), todo, r)?;
+        done += todo;
+    }
+    // Terminator.
+    peek_chunk(code, address.wrapping_add(length as u32), 0, r)
+}
+
+fn peek_chunk(code: u8, address: u32, length: usize, r: Responder) -> Result {
+    let mut result = MessageBuf::start(code);
     // Place the address at the start of the response.
     result.len = length as u8 + 4;
     result.payload[..4].copy_from_slice(&address.to_le_bytes());
@@ -488,50 +1670,394 @@ fn peek(message: &MessageBuf, r: Responder) -> Result {
     result.send(r)
 }
 
-fn poke(message: &MessageBuf) -> Result {
+fn poke(message: &MessageBuf, check: bool) -> Result {
     if message.len < 4 {
         return Err(Error::BadParameter);
     }
     let address = unsafe {* (&message.payload as *const _ as *const usize)};
+    let length = message.len as usize - 4;
+    if check && !address_ok(address as u32, length) {
+        return Err(Error::BadParameter);
+    }
     // Special case writes to flash.
     if address < 0x20000000 {
-        let message = Message::<(u32, [u32; 8])>::from_buf(message)?;
-        unsafe {crate::flash::program32(address, &message.payload.1)}?;
+        let data = &message.payload[4 .. message.len as usize];
+        unsafe {crate::flash::program(address, data)}?;
     }
     else {
         unsafe {
             vcopy_aligned(address as *mut u8, &message.payload[4] as *const u8,
-                          message.len as usize - 4)};
+                          length)};
+    }
+    SEND_ACK
+}
+
+fn fill(message: &MessageBuf) -> Result {
+    let message = Message::<(u32, u32, u32)>::from_buf(message)?;
+    let (address, length, pattern) = message.payload;
+    if address < 0x20000000 || !address_ok(address, length as usize) {
+        return Err(Error::BadParameter);
     }
+    unsafe {vfill_aligned(address as *mut u8, pattern, length as usize)};
     SEND_ACK
 }
 
+fn config_upload(message: &MessageBuf, r: Responder) -> Result {
+    use crate::provision::{upload_chunk, upload_finish};
+    if message.len == 0 {
+        return Message::new(0xf6, upload_finish()? as u32).send(r);
+    }
+    let (offset, data) = Message::<(u32, [u32; 8])>::from_buf(message)?.payload;
+    upload_chunk(offset as usize, &data)?;
+    SEND_ACK
+}
+
+fn config_list(message: &MessageBuf, r: Responder) -> Result {
+    use crate::provision::{NUM_SLOTS, slot_info};
+    let first = Message::<u8>::from_buf(message)?.payload;
+    if first >= NUM_SLOTS {
+        return Err(Error::BadParameter);
+    }
+    let last = NUM_SLOTS.min(first + 4);
+    let mut resp = MessageBuf::start(0xf7);
+    for (n, i) in (first .. last).enumerate() {
+        for (j, word) in slot_info(i).iter().enumerate() {
+            let o = n * 12 + j * 4;
+            resp.payload[o .. o + 4].copy_from_slice(&word.to_le_bytes());
+        }
+    }
+    resp.len = (last - first) * 12;
+    resp.send(r)
+}
+
+fn applied_config(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    Message::new(0xfc, crate::provision::applied_info()).send(r)
+}
+
 fn get_crc(message: &MessageBuf, r: Responder) -> Result {
-    let (address, length) = Message::<(u32, u32)>::from_buf(message)?.payload;
-    let crc = crate::crc32::compute(address as *const u8, length as usize);
-    Message::new(0xf3, (address, length, crc)).send(r)
+    Message::new(0xf3, crc_segments(message.get_payload())?).send(r)
+}
+
+/// The CRC for command 73: the first address, total length and CRC.
+fn crc_segments(mut payload: &[u8]) -> Result<[u32; 3]> {
+    use crate::crc32::{INIT, finish, update, update_reflected};
+    let mut update: CrcUpdate = update;
+    if payload.len() % 8 == 1 {
+        let (&mode, rest) = payload.split_last().unwrap();
+        update = match mode {
+            0 => update,
+            1 => update_reflected,
+            _ => return Err(Error::BadParameter),
+        };
+        payload = rest;
+    }
+    if payload.len() == 0 || payload.len() % 8 != 0 {
+        return Err(Error::BadFormat);
+    }
+    let segment = |s: &[u8]| (u32::from_le_bytes(s[..4].try_into().unwrap()),
+                              u32::from_le_bytes(s[4..].try_into().unwrap()));
+    // Check every segment before starting, this may run from the main loop.
+    for (address, length) in payload.chunks_exact(8).map(segment) {
+        if !address_ok(address, length as usize) {
+            return Err(Error::BadParameter);
+        }
+    }
+    let mut state = INIT;
+    let mut total = 0u32;
+    for (address, length) in payload.chunks_exact(8).map(segment) {
+        state = crc_update_chunked(
+            update, state, address as *const u8, length as usize);
+        total = total.wrapping_add(length);
+    }
+    let first = u32::from_le_bytes(payload[..4].try_into().unwrap());
+    Ok([first, total, finish(state)])
+}
+
+type CrcUpdate = fn(u32, *const u8, usize) -> u32;
+
+/// Run a CRC update in chunks, each at PRIO_APP.  The CRC unit is shared with
+/// the command handler (including the framing CRC), so a deferred CRC running
+/// from the main loop must not be interleaved with it mid-chunk.
+fn crc_update_chunked(update: CrcUpdate, mut state: u32, address: *const u8,
+                      length: usize) -> u32 {
+    const CHUNK: usize = 4096;
+    let mut done = 0;
+    while done < length {
+        let todo = CHUNK.min(length - done);
+        let _prio = Priority::<PRIO_APP>::default();
+        state = update(state, address.wrapping_add(done), todo);
+        done += todo;
+    }
+    state
+}
+
+/// Deferred command slot states.
+const DEFERRED_IDLE   : u8 = 0;
+const DEFERRED_RUNNING: u8 = 1;
+const DEFERRED_DONE   : u8 = 2;
+
+static DEFERRED_STATE: VCell<u8> = VCell::new(DEFERRED_IDLE);
+
+/// The deferred request, replaced by its response (without the framing CRC)
+/// once run.  Owned by the main loop while the state is DEFERRED_RUNNING, and
+/// by the command handler otherwise.
+static DEFERRED: UCell<MessageBuf> = Default::default();
+
+fn deferrable(code: u8) -> bool {matches!(code, 0x73)}
+
+fn deferred_start(message: &MessageBuf) -> Result {
+    let Some((&code, payload)) = message.get_payload().split_first()
+        else {return Err(Error::BadFormat)};
+    if !deferrable(code) {
+        return Err(Error::BadParameter);
+    }
+    if DEFERRED_STATE.read() == DEFERRED_RUNNING {
+        return Err(Error::Failed);
+    }
+    let job = unsafe {DEFERRED.as_mut()};
+    *job = MessageBuf::start(code);
+    job.len = payload.len() as u8;
+    job.payload[..payload.len()].copy_from_slice(payload);
+    barrier();
+    DEFERRED_STATE.write(DEFERRED_RUNNING);
+    // The main loop wakes from WFE when we return.
+    SEND_ACK
+}
+
+fn deferred_poll(message: &MessageBuf, r: Responder) -> Result {
+    Message::<()>::from_buf(message)?;
+    let state = DEFERRED_STATE.read();
+    let mut resp = MessageBuf::start(0xd0);
+    resp.payload[0] = state;
+    resp.len = 1;
+    if state == DEFERRED_DONE {
+        barrier();
+        let job = DEFERRED.as_ref();
+        let len = job.len as usize;
+        resp.payload[1] = job.code;
+        resp.payload[2 .. 2 + len].copy_from_slice(job.get_payload());
+        resp.len = 2 + len as u8;
+        DEFERRED_STATE.write(DEFERRED_IDLE);
+    }
+    resp.send(r)
+}
+
+/// Run any deferred command.  Called from the main loop, at thread priority, so
+/// that the command handler may preempt it.
+pub fn run_deferred() {
+    if DEFERRED_STATE.read() != DEFERRED_RUNNING {
+        return;
+    }
+    barrier();
+    let job = unsafe {DEFERRED.as_mut()};
+    let result = match job.code {
+        0x73 => crc_segments(job.get_payload()),
+        _ => Err(Error::UnknownMessage),
+    };
+    match result {
+        Ok(words) => {
+            job.code = 0xf3;
+            job.len = size_of_val(&words) as u8;
+            for (p, w) in job.payload.chunks_exact_mut(4).zip(words) {
+                p.copy_from_slice(&w.to_le_bytes());
+            }
+        }
+        Err(Error::Succeeded) => {
+            job.code = 0x80;
+            job.len = 0;
+        }
+        Err(err) => {
+            job.code = 0x81;
+            job.len = 2;
+            job.payload[..2].copy_from_slice(&(err as u16).to_le_bytes());
+        }
+    }
+    barrier();
+    DEFERRED_STATE.write(DEFERRED_DONE);
+}
+
+fn crc_chunk(message: &MessageBuf, r: Responder) -> Result {
+    let [state, address, length]
+        = Message::<[u32; 3]>::from_buf(message)?.payload;
+    if !address_ok(address, length as usize) {
+        return Err(Error::BadParameter);
+    }
+    let state = crate::crc32::update(
+        state, address as *const u8, length as usize);
+    Message::new(0xf0, [address, length, state]).send(r)
+}
+
+/// Confirmation magic for sector erase, "ERAS".  Also used to enter DFU.
+const ERASE_MAGIC: u32 = u32::from_le_bytes(*b"ERAS");
+/// Confirmation magic for bank erase, "WIPE".
+const ERASE_BANK_MAGIC: u32 = u32::from_le_bytes(*b"WIPE");
+
+/// Confirmation magic for changing the brown-out reset level, "BOR!".
+const BOR_MAGIC: u32 = u32::from_le_bytes(*b"BOR!");
+
+fn trigger_dfu(message: &MessageBuf) -> Result {
+    let magic = Message::<u32>::from_buf(message)?.payload;
+    if magic != ERASE_MAGIC {
+        return Err(Error::BadParameter);
+    }
+    unsafe {crate::cpu::trigger_dfu()}
 }
 
 fn flash_erase(message: &MessageBuf) -> Result {
-    let address = Message::<usize>::from_buf(message)?.payload;
-    crate::flash::erase(address)?;
+    let [address, magic] = Message::<[u32; 2]>::from_buf(message)?.payload;
+    if magic != ERASE_MAGIC {
+        return Err(Error::BadParameter);
+    }
+    crate::flash::erase(address as usize)?;
+    SEND_ACK
+}
+
+fn flash_erase_bank(message: &MessageBuf) -> Result {
+    let magic = Message::<u32>::from_buf(message)?.payload;
+    if magic != ERASE_BANK_MAGIC {
+        return Err(Error::BadParameter);
+    }
+    crate::flash::erase_bank()?;
     SEND_ACK
 }
 
+fn bank_crc(message: &MessageBuf, r: Responder) -> Result {
+    use crate::flash::{ACTIVE_BANK, BANK_SIZE};
+    let bank = Message::<u8>::from_buf(message)?.payload;
+    if bank > 1 {
+        return Err(Error::BadParameter);
+    }
+    let address = ACTIVE_BANK + bank as usize * BANK_SIZE;
+    let crc = crate::crc32::compute(address as *const u8, BANK_SIZE);
+    Message::new(0xfa, [bank as u32, crc]).send(r)
+}
+
+fn bor_level(message: &MessageBuf, r: Responder) -> Result {
+    use crate::cpu::reset_cause::BOR;
+    if message.len > 0 {
+        let payload = Message::<[u8; 5]>::from_buf(message)?.payload;
+        let magic = u32::from_le_bytes(payload[1..].try_into().unwrap());
+        if magic != BOR_MAGIC || payload[0] > 3 {
+            return Err(Error::BadParameter);
+        }
+        crate::flash::set_bor_level(payload[0])?;
+    }
+    let [current, programmed] = crate::flash::bor_level();
+    let bor_reset = crate::cpu::peek_reset_cause() & BOR != 0;
+    Message::new(0x96, [current, programmed, bor_reset as u8]).send(r)
+}
+
+fn bank_swap(message: &MessageBuf) -> Result {
+    use crate::flash::{bank_swapped, inactive_image_ok, set_bank_swap};
+    Message::<()>::from_buf(message)?;
+    if !inactive_image_ok() {
+        return Err(Error::BadParameter);
+    }
+    set_bank_swap(!bank_swapped())?;
+    crate::cpu::reboot();
+}
+
 fn test_gps_write(message: &MessageBuf) -> Result {
     dbgln!("test_gps_write");
     let prio = crate::gps_uart::GpsPriority::default();
-    while !crate::gps_uart::dma_tx(
-            &message.payload as *const u8, message.len as usize) {
+    gps_write(&prio, &message.payload[.. message.len as usize])?;
+    SEND_ACK
+}
+
+/// Send `data` to the GPS, waiting for any transfer in progress, and then for
+/// ours to complete, but not for any serial traffic queued after it.  The
+/// waits are bounded by the time to send at the current baud rate, so that a
+/// stuck UART or DMA gives GpsTimeout rather than a hang.
+fn gps_write(prio: &GpsPriority, data: &[u8]) -> Result {
+    use crate::gps_uart::{TX_MAX_LEN, dma_tx_seq, tx_complete, tx_time};
+    let timeout = tx_time(TX_MAX_LEN + data.len());
+    let start = crate::tick::cycles();
+    let timed_out = || crate::tick::cycles().wrapping_sub(start) > timeout;
+    let seq = loop {
+        if let Some(seq) = dma_tx_seq(data.as_ptr(), data.len()) {
+            break seq;
+        }
+        if timed_out() {
+            return Err(Error::GpsTimeout);
+        }
         prio.wfe();
-    }
-    // FIXME - we should wait just for our TX not all GPS TX!
-    while crate::gps_uart::dma_tx_busy() {
+    };
+    while !tx_complete(seq) {
+        if timed_out() {
+            return Err(Error::GpsTimeout);
+        }
         prio.wfe();
     }
-    SEND_ACK
+    Ok(())
+}
+
+
+#[test]
+fn test_address_ok() {
+    assert!(address_ok(0x08000000, 0x20000));
+    assert!(!address_ok(0x08000000, 0x20001));
+    assert!(address_ok(0x20007ffc, 4));
+    assert!(!address_ok(0x20007ffc, 8));
+    assert!(address_ok(0x08fff800, 12));
+    assert!(address_ok(0xe000ed00, 4));
+    assert!(!address_ok(0, 4));
+    assert!(!address_ok(0x30000000, 0));
+    assert!(!address_ok(0xfffffffc, 8));
+}
+
+#[test]
+fn test_stress_fill() {
+    let mut state = 1;
+    let mut buf = [0u8; 8];
+    stress_fill(&mut state, &mut buf[..3]);
+    stress_fill(&mut state, &mut buf[3..]);
+    assert_eq!(buf, [0x21, 0x01, 0xc5, 0x4f, 0xd1, 0xd0, 0x1a, 0xb2]);
+}
+
+#[test]
+fn test_register_ok() {
+    assert!(register_ok(0x40022000, 4));
+    assert!(register_ok(0x40022002, 2));
+    assert!(register_ok(0x40022003, 1));
+    assert!(!register_ok(0x40022002, 4));
+    assert!(!register_ok(0x40022001, 2));
+    assert!(!register_ok(0x40022000, 3));
+    assert!(!register_ok(0x20000000, 4));
+    assert!(!register_ok(0x08000000, 4));
+    assert!(register_ok(0xe000ed00, 4));
+    assert!(register_ok(0x4003fffc, 4));
+    assert!(!register_ok(0x40040000, 4));
+}
+
+#[test]
+fn test_crc_update_chunked() {
+    use crate::crc32::{INIT, update, update_reflected};
+    let data: Vec<u8> = (0 .. 10000u32).map(|i| (i * 13 + 5) as u8).collect();
+    for f in [update as CrcUpdate, update_reflected] {
+        assert_eq!(crc_update_chunked(f, INIT, data.as_ptr(), data.len()),
+                   f(INIT, data.as_ptr(), data.len()));
+    }
 }
 
+#[test]
+fn test_crc_segments_checked() {
+    let segments = |s: &[(u32, u32)]| -> Vec<u8> {
+        s.iter().flat_map(|&(a, l)| [a.to_le_bytes(), l.to_le_bytes()])
+            .flatten().collect()};
+    // Rejected before any memory is read.
+    assert_eq!(crc_segments(&segments(&[(0x30000000, 4)])),
+               Err(Error::BadParameter));
+    assert_eq!(crc_segments(&segments(&[(0x08000000, 4), (0x20007ffc, 8)])),
+               Err(Error::BadParameter));
+}
+
+#[test]
+fn test_crc_check() {
+    assert_eq!(crc_check(crc16::compute, crate::crc32::compute), 3);
+    assert_eq!(crc_check(|_| 0, crate::crc32::compute), 2);
+    assert_eq!(crc_check(crc16::compute, |_, _| 0), 1);
+}
 
 #[test]
 fn test_utf16() {
@@ -544,5 +2070,39 @@ fn test_utf16() {
         assert_eq!(p0b[0] as usize, utf16.len() * 2 + 2);
         assert_eq!(p0b[1], 3);
         assert_eq!(&place[1..], utf16);
+        assert!(fits_usb(s, utf16.len() + 1));
+        assert!(!fits_usb(s, utf16.len()));
     }
+    // A surrogate pair that does not fit is dropped whole.
+    let mut place = [0u16; 32];
+    str_to_usb(&mut place, "123456789012345678901234567890😷");
+    assert_eq!(place[0], 0x300 + 62);
+    assert_eq!(place[30], '0' as u16);
+}
+
+#[test]
+fn test_usb_build_id() {
+    use crate::usb::{IDX_SERIAL_NUMBER, string_utf8};
+    let mut out = [0u8; 64];
+    assert!((1..IDX_SERIAL_NUMBER).any(|idx| {
+        let len = string_utf8(idx, &mut out).unwrap();
+        &out[..len] == BUILD_ID_STR.as_bytes()
+    }));
+}
+
+#[test]
+fn test_usb_name_astral() {
+    use crate::usb::{IDX_SERIAL_NUMBER, string_raw, string_utf8};
+    let name = "🛰GPS😷Freak🚨";
+    init(name);
+    let utf16: Vec<u16> = name.encode_utf16().collect();
+    let raw = string_raw(IDX_SERIAL_NUMBER).unwrap();
+    assert_eq!(raw.len(), utf16.len() * 2 + 2);
+    assert_eq!(raw[..2], [raw.len() as u8, 3]);
+    let units: Vec<u16> = raw[2..].chunks_exact(2).map(
+        |b| u16::from_le_bytes([b[0], b[1]])).collect();
+    assert_eq!(units, utf16);
+    let mut out = [0u8; 64];
+    let len = string_utf8(IDX_SERIAL_NUMBER, &mut out).unwrap();
+    assert_eq!(&out[..len], name.as_bytes());
 }
@@ -14,12 +14,7 @@ pub fn hw_compute(bytes: &[u8]) -> u16 {
     crc.POL.write(|w| w.bits(POLY16 as u32));
     crc.INIT.write(|w| w.CRC_INIT().bits(0));
     crc.CR.write(|w| w.POLYSIZE().B_0x1().RESET().set_bit());
-    // TODO - word by word!
-    for &b in bytes {
-        // Be careful, we need to write as the correct width.
-        let dr = crc.DR.as_ptr() as *mut u8;
-        unsafe {core::ptr::write_volatile(dr, b)};
-    }
+    crate::crc::hw_feed(crc.DR.as_ptr(), bytes.as_ptr(), bytes.len());
     crc.DR.read().bits() as u16
 }
 
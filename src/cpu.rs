@@ -7,6 +7,13 @@ pub const CPU_FREQ: u32 = 160_000_000;
 const BKPSRAM_BASE: u32 = 0x40036400;
 const DFU_MAGIC: u32 = 0x52434C76;
 
+/// Number of DFU entries allowed without the application completing start-up
+/// in between.  A DFU session that times out (see `watchdog::init_dfu`) comes
+/// back via a watchdog reset with the magic still set, and is retried, up to
+/// this limit.  Beyond this, the DFU magic is ignored, so that we cannot get
+/// stuck cycling into the ROM bootloader.
+const DFU_MAX_ATTEMPTS: u32 = 3;
+
 unsafe extern "C" {
     static mut __bss_start: u8;
     static mut __bss_end: u8;
@@ -26,6 +33,13 @@ pub static SERIAL_NUMBER: UCell<[u8; SERIAL_LEN]> = UCell::new([0; _]);
 
 pub static IS_PROTOTYPE: UCell<bool> = UCell::new(false);
 
+/// The raw 96-bit CPU unique ID, read at start-up.
+pub static UNIQUE_ID: UCell<[u32; 3]> = UCell::new([0; 3]);
+
+/// Board variant detection method: the prototype is recognised by its CPU
+/// unique ID.  There is no strapping pin to distinguish boards.
+pub const VARIANT_BY_UID: u8 = 1;
+
 const PROTO_SN0: u32 = 0x006b0028;
 const PROTO_SN1: u32 = 0x31335105;
 const PROTO_SN2: u32 = 0x30393436;
@@ -146,6 +160,7 @@ pub fn init() {
     // Generate the USB serial number.  ST notes claim that we will hard fault
     // if we do this with ICACHE enabled.
     let sn = unsafe {&*(0x8fff800 as *const [u32; 3])};
+    unsafe {*UNIQUE_ID.as_mut() = *sn};
     if sn[0] == PROTO_SN0 && sn[1] == PROTO_SN1 && sn[2] == PROTO_SN2 {
         unsafe {*IS_PROTOTYPE.as_mut() = true};
     }
@@ -209,7 +224,7 @@ fn bugger() {
     let pcp = fp.wrapping_add(0x20);
     let pc = unsafe {*(pcp as *const u32)};
     // TODO - change this to keeping a log in the backup SRAM.
-    crate::dbgln!("Crash @ {pc:#010x}");
+    crate::debug::fallback_fmt(format_args!("Crash @ {pc:#010x}\n"));
     loop {
         crate::debug::debug_isr();
     }
@@ -235,6 +250,90 @@ pub mod interrupt {
     pub const PRIO_STATUS: u8 = PRIO_APP | 0x10;
 }
 
+/// Reset cause flags, as reported by `take_reset_cause`.
+pub mod reset_cause {
+    pub const PIN : u32 = 1;
+    pub const BOR : u32 = 2;
+    pub const SOFT: u32 = 4;
+    pub const IWDG: u32 = 8;
+    pub const WWDG: u32 = 16;
+    pub const LPWR: u32 = 32;
+}
+
+/// Read the reset cause flags from RCC RSR, and then clear them so that the
+/// next reset is reported afresh.
+pub fn take_reset_cause() -> u32 {
+    let cause = peek_reset_cause();
+    let rcc = unsafe {&*stm32h503::RCC::ptr()};
+    rcc.RSR.modify(|_,w| w.RMVF().set_bit());
+    cause
+}
+
+/// Read the reset cause flags from RCC RSR, without clearing them.
+pub fn peek_reset_cause() -> u32 {
+    use reset_cause::*;
+    let rcc = unsafe {&*stm32h503::RCC::ptr()};
+    let rsr = rcc.RSR.read();
+    let flag = |b: bool, f: u32| if b {f} else {0};
+    flag(rsr.PINRSTF ().bit(), PIN)
+        | flag(rsr.BORRSTF ().bit(), BOR)
+        | flag(rsr.SFTRSTF ().bit(), SOFT)
+        | flag(rsr.IWDGRSTF().bit(), IWDG)
+        | flag(rsr.WWDGRSTF().bit(), WWDG)
+        | flag(rsr.LPWRRSTF().bit(), LPWR)
+}
+
+/// The live clock configuration, read back from the hardware, so that a
+/// regression in `init` shows up.  Register values are raw.
+#[derive(Debug)]
+#[repr(C)]
+pub struct ClockConfig {
+    /// System clock frequency in Hz, computed from the registers below.  Zero
+    /// if it cannot be determined.
+    pub sysclk   : u32,
+    pub pll1cfgr : u32,
+    pub pll1divr : u32,
+    pub flash_acr: u32,
+    pub pwr_vossr: u32,
+    pub cfgr1    : u32,
+}
+
+pub fn clock_config() -> ClockConfig {
+    let flash = unsafe {&*stm32h503::FLASH::ptr()};
+    let pwr   = unsafe {&*stm32h503::PWR  ::ptr()};
+    let rcc   = unsafe {&*stm32h503::RCC  ::ptr()};
+    let cfgr = rcc.PLL1CFGR.read();
+    let divr = rcc.PLL1DIVR.read();
+    let cfgr1 = rcc.CFGR1.read();
+    let sysclk = sysclk_freq(
+        cfgr1.SWS().bits(), cfgr.PLL1SRC().bits(), cfgr.PLL1M().bits(),
+        divr.PLL1N().bits(), divr.PLL1P().bits());
+    ClockConfig{
+        sysclk,
+        pll1cfgr : cfgr.bits(),
+        pll1divr : divr.bits(),
+        flash_acr: flash.ACR.read().bits(),
+        pwr_vossr: pwr.VOSSR().read().bits(),
+        cfgr1    : cfgr1.bits(),
+    }
+}
+
+/// The system clock frequency, from the clock switch status and the PLL1
+/// fields (with N and P as in the register, one less than the divider).  HSE
+/// is not used, so gives zero.
+fn sysclk_freq(sws: u8, pll_src: u8, m: u8, n: u16, p: u8) -> u32 {
+    const HSI_FREQ: u32 = 32_000_000;
+    const CSI_FREQ: u32 = 4_000_000;
+    let pll_in = match pll_src {1 => HSI_FREQ, 2 => CSI_FREQ, _ => 0};
+    match sws {
+        0 => HSI_FREQ,
+        1 => CSI_FREQ,
+        3 if m != 0 => (pll_in as u64 * (n as u64 + 1)
+                        / m as u64 / (p as u64 + 1)) as u32,
+        _ => 0,
+    }
+}
+
 pub fn maybe_enter_dfu() {
     let pwr = unsafe {&*stm32h503::PWR::ptr()};
     let rcc = unsafe {&*stm32h503::RCC::ptr()};
@@ -244,16 +343,44 @@ pub fn maybe_enter_dfu() {
     // Check for magic in the BKPSRAM to reboot into DFU.
     rcc.AHB1ENR.modify(|_,w| w.BKPRAMEN().set_bit());
     let magic: &'static VCell<u32> = unsafe {magic_reboot_config()};
+    let attempts = unsafe {dfu_attempts()};
     if magic.read() == DFU_MAGIC {
-        magic.write(0);
-        // Only do this on a software reboot!
-        if rcc.RSR.read().SFTRSTF().bit() {
+        // Only do this on a software reboot, or the watchdog ending a DFU
+        // session.  The magic is left in place for the latter.
+        let count = dfu_attempt(attempts.read(), peek_reset_cause());
+        attempts.write(count.unwrap_or(0));
+        if count.is_some() {
+            // Clear the reset flags, so that a bootloader exit straight to the
+            // application is not taken as a reset.
+            rcc.RSR.modify(|_,w| w.RMVF().set_bit());
+            crate::watchdog::init_dfu();
             unsafe {goto_sys_flash()};
         }
+        magic.write(0);
     }
     rcc.AHB1ENR.modify(|_,w| w.BKPRAMEN().clear_bit());
 }
 
+/// Decide whether to enter DFU, given the DFU magic is present and the reset
+/// cause flags.  Returns the new attempt count if so, or None to carry on
+/// booting the application.
+fn dfu_attempt(attempts: u32, reset: u32) -> Option<u32> {
+    use reset_cause::*;
+    if reset & (SOFT | IWDG) != 0 && attempts < DFU_MAX_ATTEMPTS {
+        Some(attempts + 1)
+    }
+    else {None}
+}
+
+/// Note that the application has started up successfully, clearing the DFU
+/// attempt count.
+pub fn boot_complete() {
+    let rcc = unsafe {&*stm32h503::RCC::ptr()};
+    rcc.AHB1ENR.modify(|_,w| w.BKPRAMEN().set_bit());
+    unsafe {dfu_attempts()}.write(0);
+    rcc.AHB1ENR.modify(|_,w| w.BKPRAMEN().clear_bit());
+}
+
 pub unsafe fn trigger_dfu() -> ! {
     let rcc = unsafe {&*stm32h503::RCC::ptr()};
 
@@ -301,6 +428,12 @@ unsafe fn magic_reboot_config() -> &'static VCell<u32> {
     unsafe {&*(BKPSRAM_BASE as *const VCell<u32>)}
 }
 
+/// The count of DFU entries since the application last completed start-up,
+/// in the BKPSRAM word after the magic.
+unsafe fn dfu_attempts() -> &'static VCell<u32> {
+    unsafe {&*((BKPSRAM_BASE + 4) as *const VCell<u32>)}
+}
+
 fn format_serial_number(sn: &[u32; 3], text: &mut [u8; SERIAL_LEN]) {
     // Little endian, start from high address.
     // 0x08fff808 :
@@ -344,6 +477,26 @@ unsafe extern "C" {
     fn frameaddress(level: i32) -> *const u8;
 }
 
+#[test]
+fn test_dfu_attempt() {
+    use reset_cause::*;
+    assert_eq!(dfu_attempt(0, SOFT), Some(1));
+    assert_eq!(dfu_attempt(1, IWDG), Some(2));
+    assert_eq!(dfu_attempt(DFU_MAX_ATTEMPTS - 1, IWDG), Some(DFU_MAX_ATTEMPTS));
+    assert_eq!(dfu_attempt(DFU_MAX_ATTEMPTS, SOFT), None);
+    assert_eq!(dfu_attempt(0, 0), None);
+    assert_eq!(dfu_attempt(0, PIN | BOR), None);
+}
+
+#[test]
+fn test_sysclk_freq() {
+    assert_eq!(sysclk_freq(3, 1, 16, 159, 1), CPU_FREQ);
+    assert_eq!(sysclk_freq(0, 1, 16, 159, 1), 32_000_000);
+    assert_eq!(sysclk_freq(3, 0, 16, 159, 1), 0);
+    assert_eq!(sysclk_freq(3, 1, 0, 159, 1), 0);
+    assert_eq!(sysclk_freq(2, 1, 16, 159, 1), 0);
+}
+
 #[test]
 fn test_sn() {
     let sn = [PROTO_SN0, PROTO_SN1, PROTO_SN2];
@@ -1,6 +1,8 @@
 //! Table driven CRC code, only used for unittests.  Which is a bit silly.
+//! Also the data feeding shared by the hardware CRC routines.
 
 use core::ops::{BitAnd, BitXor, Shl};
+use core::ptr::write_volatile;
 
 pub const POLY16: u16 = 0x1021;
 pub const POLY32: u32 = 0x04c11db7;
@@ -41,6 +43,62 @@ pub fn sw_compute<T>(table: &[T; 256], iv: T, bytes: &[u8]) -> T where
     v
 }
 
+/// Table for a reflected (LSB first) CRC-32, with the polynomial given in the
+/// usual MSB first form.
+pub const fn crc_table_reflected(poly: u32) -> [u32; 256] {
+    let poly = poly.reverse_bits();
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut v = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            v = v >> 1 ^ if v & 1 != 0 {poly} else {0};
+            j += 1;
+        }
+        table[i] = v;
+        i += 1;
+    }
+    table
+}
+
+pub fn sw_compute_reflected(table: &[u32; 256], iv: u32, bytes: &[u8]) -> u32 {
+    let mut v = iv;
+    for b in bytes {
+        v = table[(v as u8 ^ *b) as usize] ^ v >> 8;
+    }
+    v
+}
+
+/// Pass `length` bytes at `address` to `byte` and `word`.  Aligned 32-bit words
+/// are used where possible, with single bytes for the unaligned head and tail.
+/// Words are big-endian (first byte most significant), matching the order that
+/// the CRC unit consumes a 32-bit write.
+pub fn feed(address: *const u8, length: usize,
+            mut byte: impl FnMut(u8), mut word: impl FnMut(u32)) {
+    let head = (address as usize).wrapping_neg() & 3;
+    let head = head.min(length);
+    let words = (length - head) / 4;
+    for i in 0 .. head {
+        byte(unsafe {*address.wrapping_add(i)});
+    }
+    let aligned = address.wrapping_add(head) as *const u32;
+    for i in 0 .. words {
+        word(u32::from_be(unsafe {*aligned.wrapping_add(i)}));
+    }
+    for i in head + words * 4 .. length {
+        byte(unsafe {*address.wrapping_add(i)});
+    }
+}
+
+/// Feed data to the hardware CRC data register.
+pub fn hw_feed(dr: *mut u32, address: *const u8, length: usize) {
+    // Be careful, we need to write bytes as bytes.
+    feed(address, length,
+         |b| unsafe {write_volatile(dr as *mut u8, b)},
+         |w| unsafe {write_volatile(dr, w)});
+}
+
 // Unit tests using the CRC16 polynomial.
 
 #[cfg(test)]
@@ -80,3 +138,38 @@ fn basic() {
     assert_eq!(v, u);
     assert_eq!(v, 0x31c3);              // Canned value.
 }
+
+#[test]
+fn reflected() {
+    // The standard CRC-32, as used by zlib and Ethernet.
+    let table = crc_table_reflected(POLY32);
+    assert_eq!(table[1], 0x77073096);
+    assert_eq!(!sw_compute_reflected(&table, !0, b"123456789"), 0xcbf43926);
+}
+
+#[test]
+fn word_feed() {
+    // Cheap LCG for reproducible pseudo-random data.
+    let mut seed = 0x12345678u32;
+    let mut random = || {
+        seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+        seed >> 16
+    };
+    let mut buffer = [0u32; 64];
+    for w in &mut buffer {
+        *w = random() << 16 ^ random();
+    }
+    let bytes = unsafe {core::slice::from_raw_parts(
+        &buffer as *const u32 as *const u8, size_of_val(&buffer))};
+    for _ in 0 .. 1000 {
+        let start = random() as usize % bytes.len();
+        let length = random() as usize % (bytes.len() - start + 1);
+        let slice = &bytes[start .. start + length];
+        let by_byte = sw_compute(&TABLE, 0x5555, slice);
+        let v = core::cell::Cell::new(0x5555);
+        feed(slice.as_ptr(), length,
+             |b| v.set(sw_compute(&TABLE, v.get(), &[b])),
+             |w| v.set(sw_compute(&TABLE, v.get(), &w.to_be_bytes())));
+        assert_eq!(v.get(), by_byte, "{start} {length}");
+    }
+}
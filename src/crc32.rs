@@ -1,36 +1,78 @@
 //! This is similar to the Ethernet CRC, with the only difference being
-//! opposite bit-ordering of each byte.
+//! opposite bit-ordering of each byte.  The `_reflected` variants compute the
+//! standard reflected CRC-32, as computed by zlib and friends, using the same
+//! initial state and `finish`.
 
 use crate::crc::POLY32;
 
 pub const VERIFY_MAGIC: u32 = 0x38fb2284;
 
+/// Initial state for an incremental CRC computation, see `update`.
+pub const INIT: u32 = !0;
+
 pub fn compute(address: *const u8, length: usize) -> u32 {
+    finish(update(INIT, address, length))
+}
+
+/// Accumulate data into a CRC computation.  Start with `INIT`, and pass the
+/// returned state to each subsequent call.  The CRC is then `finish(state)`.
+pub fn update(state: u32, address: *const u8, length: usize) -> u32 {
+    if cfg!(target_os = "none") {
+        hw_update(state, address, length)
+    }
+    else {
+        crate::crc::sw_compute(
+            &TABLE, state, unsafe{core::slice::from_raw_parts(address, length)})
+    }
+}
+
+/// As `update`, but for the reflected CRC-32.
+pub fn update_reflected(state: u32, address: *const u8, length: usize) -> u32 {
     if cfg!(target_os = "none") {
-        hw_compute(address, length)
+        hw_update_reflected(state, address, length)
     }
     else {
-        !crate::crc::sw_compute(
-            &TABLE, !0, unsafe{core::slice::from_raw_parts(address, length)})
+        crate::crc::sw_compute_reflected(
+            &TABLE_REFLECTED, state,
+            unsafe{core::slice::from_raw_parts(address, length)})
     }
 }
 
+pub fn compute_reflected(address: *const u8, length: usize) -> u32 {
+    finish(update_reflected(INIT, address, length))
+}
+
+/// Final CRC value from the computation state.
+pub const fn finish(state: u32) -> u32 {!state}
+
 pub fn hw_compute(address: *const u8, length: usize) -> u32 {
+    finish(hw_update(INIT, address, length))
+}
+
+fn hw_update(state: u32, address: *const u8, length: usize) -> u32 {
     let crc = unsafe {&*stm32h503::CRC::PTR};
     crc.POL.write(|w| w.bits(POLY32));
-    crc.INIT.write(|w| w.CRC_INIT().bits(!0));
+    crc.INIT.write(|w| w.CRC_INIT().bits(state));
     crc.CR.write(|w| w.POLYSIZE().B_0x0().RESET().set_bit());
-    // TODO - word by word!  And share with the other identical code.
-    for i in 0 .. length {
-        // Be careful, we need to write as the correct width.
-        let dr = crc.DR.as_ptr() as *mut u8;
-        let b = unsafe{*address.wrapping_add(i)};
-        unsafe {core::ptr::write_volatile(dr, b)};
-    }
-    !crc.DR.read().bits()
+    crate::crc::hw_feed(crc.DR.as_ptr(), address, length);
+    crc.DR.read().bits()
+}
+
+/// The CRC unit bit-reverses each input byte, and the output.  The INIT
+/// register holds the unreflected state, so reverse the state passed in.
+fn hw_update_reflected(state: u32, address: *const u8, length: usize) -> u32 {
+    let crc = unsafe {&*stm32h503::CRC::PTR};
+    crc.POL.write(|w| w.bits(POLY32));
+    crc.INIT.write(|w| w.CRC_INIT().bits(state.reverse_bits()));
+    crc.CR.write(
+        |w|w.POLYSIZE().B_0x0().REV_IN().B_0x1().REV_OUT().B_0x1()
+            .RESET().set_bit());
+    crate::crc::hw_feed(crc.DR.as_ptr(), address, length);
+    crc.DR.read().bits()
 }
 
 const TABLE: [u32; 256] = crate::crc::crc_table(POLY32, 32);
+const TABLE_REFLECTED: [u32; 256] = crate::crc::crc_table_reflected(POLY32);
 
 #[test]
 fn check_magic() {
@@ -46,3 +88,26 @@ fn check_magic() {
 
     assert_eq!(compute(&more as *const u8, more.len()), VERIFY_MAGIC);
 }
+
+#[test]
+fn check_update() {
+    let data: [u8; 37] = core::array::from_fn(|i| (i * 7 + 3) as u8);
+    let whole = compute(data.as_ptr(), data.len());
+    for split in [0, 1, 5, 16, 37] {
+        let state = update(INIT, data.as_ptr(), split);
+        let state = update(state, data[split..].as_ptr(), data.len() - split);
+        assert_eq!(finish(state), whole);
+    }
+}
+
+#[test]
+fn check_reflected() {
+    let data = b"123456789";
+    assert_eq!(compute_reflected(data.as_ptr(), data.len()), 0xcbf43926);
+    for split in [0, 4, 9] {
+        let state = update_reflected(INIT, data.as_ptr(), split);
+        let state = update_reflected(
+            state, data[split..].as_ptr(), data.len() - split);
+        assert_eq!(finish(state), 0xcbf43926);
+    }
+}
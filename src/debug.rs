@@ -1,21 +1,307 @@
 
-// RX on pin 25. PA15, USART3 RX.
+// RX on pin 25. PA15, USART3 RX, prototype only.
 // TX on pin 26. PB3, USART3 TX
+//
+// Debug output is queued in a ring buffer, and sent to the UART by GPDMA1
+// channel 4.  Each transfer covers the contiguous data up to the buffer wrap,
+// and the next is started from the DMA completion interrupt.  The panic and
+// crash paths, where interrupts may be disabled, instead use the
+// `stm_common::debug` FIFO path via `fallback_fmt`.
+//
+// Commands may also be sent to the debug UART RX, using the same framing as
+// over USB.  This gives a wired fallback when USB is unavailable.  On the
+// production boards PA15 drives the blue LED, so the RX is only enabled on the
+// prototype, and elsewhere the debug UART is output only.  Received
+// frames are run from the main loop, at the same priority as USB commands,
+// and the responses are sent out the debug UART, interleaved with the debug
+// output.  Bytes received while a command is pending are dropped.
 
-use crate::cpu::interrupt::PRIO_DEBUG;
+use crate::command::MessageBuf;
+use crate::cpu::Priority;
+use crate::cpu::interrupt::{PRIO_APP, PRIO_COMMS, PRIO_DEBUG};
 
 use stm_common::debug;
+use stm_common::dma::DMA_Channel;
+use stm_common::vcell::{UCell, VCell};
 use debug::{Debug, Meta};
 
+use stm32h503::GPDMA1 as DMA;
 use stm32h503::Interrupt::USART3 as INTERRUPT;
+use stm32h503::Interrupt::GPDMA1_CH4 as DMA_INTERRUPT;
+
+/// GPDMA1 channel for debug TX.
+const DMA_CHANNEL: usize = 4;
+
+/// USART3 DMA TX.
+const TX_DMA_REQ: u8 = 26;
+
+/// Size of the debug TX ring buffer.
+const TX_RING_LEN: usize = 1024;
 
 pub const BAUD: u32 = 115200;
 const BRR: u32 = (crate::cpu::CPU_FREQ + BAUD/2) / BAUD;
 const _: () = assert!(BRR < 65536);
 
+/// Log levels for the levelled debug macros, `errorln!`, `warnln!`, `infoln!`
+/// and `debugln!`.  A message is output if its level is at most the runtime
+/// level.
+pub mod level {
+    pub const ERROR: u8 = 0;
+    pub const WARN : u8 = 1;
+    pub const INFO : u8 = 2;
+    pub const DEBUG: u8 = 3;
+}
+
+/// The runtime log level.
+static LEVEL: VCell<u8> = VCell::new(level::INFO);
+
+/// Set the runtime log level.  Returns false if the level is not valid.
+pub fn set_level(l: u8) -> bool {
+    if l > level::DEBUG {
+        return false;
+    }
+    LEVEL.write(l);
+    true
+}
+
+pub fn get_level() -> u8 {LEVEL.read()}
+
+/// Is output at `level` enabled?
+pub fn enabled(level: u8) -> bool {level <= LEVEL.read()}
+
+#[macro_export]
+macro_rules!logln {($level:expr, $($tt:tt)*) => {
+    if $crate::debug::enabled($level) {$crate::dbgln!($($tt)*)}};}
+#[macro_export]
+macro_rules!errorln {($($tt:tt)*) => {
+    $crate::logln!($crate::debug::level::ERROR, $($tt)*)};}
+#[macro_export]
+macro_rules!warnln {($($tt:tt)*) => {
+    $crate::logln!($crate::debug::level::WARN, $($tt)*)};}
+#[macro_export]
+macro_rules!infoln {($($tt:tt)*) => {
+    $crate::logln!($crate::debug::level::INFO, $($tt)*)};}
+#[macro_export]
+macro_rules!debugln {($($tt:tt)*) => {
+    $crate::logln!($crate::debug::level::DEBUG, $($tt)*)};}
+
 /// State for debug logging.
 pub static DEBUG: Debug<DebugMeta> = Debug::default();
 
+/// Debug output waiting for the UART.  Only accessed at PRIO_COMMS.
+struct TxRing {
+    data: [u8; TX_RING_LEN],
+    start: usize,
+    len: usize,
+    /// Length of the DMA transfer in flight, from `start`.
+    active: usize,
+}
+
+static TX_RING: UCell<TxRing> = UCell::new(
+    TxRing{data: [0; _], start: 0, len: 0, active: 0});
+
+impl core::fmt::Write for TxRing {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl TxRing {
+    /// Append data, dropping anything that doesn't fit.
+    fn push(&mut self, data: &[u8]) {
+        for &b in data {
+            if self.len >= TX_RING_LEN {
+                break;
+            }
+            self.data[(self.start + self.len) % TX_RING_LEN] = b;
+            self.len += 1;
+        }
+    }
+    /// Start a DMA transfer of the data up to the buffer wrap, if idle.
+    fn kick(&mut self) {
+        if self.active != 0 || self.len == 0 {
+            return;
+        }
+        let dma = unsafe {&*DMA::ptr()};
+        self.active = self.len.min(TX_RING_LEN - self.start);
+        let data = self.data[self.start..].as_ptr();
+        dma.C[DMA_CHANNEL].write(data as usize, self.active, 0);
+    }
+    /// A transfer completed (or errored), release it and start the next.
+    fn done(&mut self) {
+        self.start = (self.start + self.active) % TX_RING_LEN;
+        self.len -= self.active;
+        self.active = 0;
+        self.kick();
+    }
+}
+
+/// Queue debug output for DMA to the UART.  Call at PRIO_COMMS.
+pub fn write_fmt(fmt: core::fmt::Arguments) {
+    let ring = unsafe {TX_RING.as_mut()};
+    let _ = core::fmt::write(ring, fmt);
+    ring.kick();
+}
+
+/// Write debug output using the interrupt-free `stm_common` path, for panics
+/// and crashes.  Stops the DMA first, so that output is not interleaved.
+pub fn fallback_fmt(fmt: core::fmt::Arguments) {
+    if crate::DEBUG_ENABLE {
+        let dma = unsafe {&*DMA::ptr()};
+        DebugMeta.uart().CR3.modify(|_,w| w.DMAT().clear_bit());
+        dma.C[DMA_CHANNEL].CR.modify(|_,w| w.EN().clear_bit());
+        debug::debug_fmt::<DebugMeta>(fmt);
+    }
+}
+
+fn dma_isr() {
+    let dma = unsafe {&*DMA::ptr()};
+    let ch = &dma.C[DMA_CHANNEL];
+
+    let sr = ch.SR.read();
+    ch.FCR.write(|w| w.bits(sr.bits()));      // Clear the interrupts.
+    crate::dma::note_status(DMA_CHANNEL, sr.bits());
+
+    if !ch.CR.read().EN().bit() && sr.bits() & 0x7f00 != 0 {
+        unsafe {TX_RING.as_mut()}.done();
+    }
+}
+
+/// Assembles command frames from the UART RX bytes.
+#[derive_const(Default)]
+struct RxFramer {
+    /// Bytes of the current frame received so far.
+    count: usize,
+}
+
+impl RxFramer {
+    /// Add a received byte to the frame in `buf`.  Returns the length of the
+    /// frame once it is complete.  Anything not starting with the magic
+    /// is skipped.
+    fn push(&mut self, buf: &mut [u8], b: u8) -> Option<usize> {
+        match self.count {
+            0 if b != 0xce => return None,
+            1 if b != 0x93 => {
+                self.count = if b == 0xce {1} else {0};
+                return None;
+            }
+            3 if b as usize + 6 > buf.len() => {
+                self.count = 0;
+                return None;
+            }
+            _ => (),
+        }
+        buf[self.count] = b;
+        self.count += 1;
+        if self.count >= 4 && self.count == buf[3] as usize + 6 {
+            self.count = 0;
+            return Some(buf[3] as usize + 6);
+        }
+        None
+    }
+}
+
+static RX_FRAMER: UCell<RxFramer> = Default::default();
+
+/// Received command frame.  Written by the ISR while `RX_PENDING` is zero,
+/// and read by the main loop while it is non-zero.
+static RX_BUF: UCell<MessageBuf> = Default::default();
+
+/// Length of the received command awaiting processing, or zero.
+static RX_PENDING: VCell<usize> = VCell::new(0);
+
+fn rx_buf() -> &'static mut [u8] {
+    unsafe {core::slice::from_raw_parts_mut(
+        RX_BUF.as_mut() as *mut MessageBuf as *mut u8, size_of::<MessageBuf>())}
+}
+
+/// Drain the UART RX FIFO into the command framer.
+fn rx_isr() {
+    let uart = DebugMeta.uart();
+    // Overrun also interrupts via RXFNEIE.  We just lose the bytes.
+    uart.ICR.write(|w| w.ORECF().set_bit());
+    while uart.ISR.read().RXFNE().bit() {
+        let b = uart.RDR.read().bits() as u8;
+        if RX_PENDING.read() != 0 {
+            continue;                   // Busy, drop it.
+        }
+        if let Some(len) = unsafe {RX_FRAMER.as_mut()}.push(rx_buf(), b) {
+            stm_common::utils::barrier();
+            RX_PENDING.write(len);
+        }
+    }
+}
+
+/// Run any command received on the debug UART.  Called from the main loop.
+pub fn command_poll() {
+    let len = RX_PENDING.read();
+    if len == 0 {
+        return;
+    }
+    stm_common::utils::barrier();
+    {
+        let _prio = Priority::<PRIO_APP>::default();
+        crate::command::command_handler(RX_BUF.as_ref(), len, command_tx);
+    }
+    stm_common::utils::barrier();
+    RX_PENDING.write(0);
+}
+
+/// Send a command response out the debug UART.
+fn command_tx(data: &[u8]) {
+    let _prio = Priority::<PRIO_COMMS>::default();
+    let ring = unsafe {TX_RING.as_mut()};
+    ring.push(data);
+    ring.kick();
+}
+
+/// Size of the buffer holding a copy of the debug output for USB.
+const USB_COPY_LEN: usize = 512;
+
+/// Copy of the debug output, for the USB debug serial.  If USB doesn't keep
+/// up, or nobody is listening, then new output is dropped.  Only accessed at
+/// PRIO_COMMS.
+struct UsbCopy {
+    data: [u8; USB_COPY_LEN],
+    start: usize,
+    len: usize,
+}
+
+static USB_COPY: UCell<UsbCopy> = UCell::new(
+    UsbCopy{data: [0; _], start: 0, len: 0});
+
+impl core::fmt::Write for UsbCopy {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for &b in s.as_bytes() {
+            if self.len >= USB_COPY_LEN {
+                break;
+            }
+            self.data[(self.start + self.len) % USB_COPY_LEN] = b;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Copy debug output for USB.  Call at PRIO_COMMS.
+pub fn copy_to_usb(fmt: core::fmt::Arguments) {
+    let _ = core::fmt::write(unsafe {USB_COPY.as_mut()}, fmt);
+}
+
+/// Take up to `out.len()` bytes of debug output for USB, returning the number
+/// of bytes.  Call at PRIO_COMMS.
+pub fn take_for_usb(out: &mut [u8]) -> usize {
+    let copy = unsafe {USB_COPY.as_mut()};
+    let n = copy.len.min(out.len());
+    for (i, o) in out[..n].iter_mut().enumerate() {
+        *o = copy.data[(copy.start + i) % USB_COPY_LEN];
+    }
+    copy.start = (copy.start + n) % USB_COPY_LEN;
+    copy.len -= n;
+    n
+}
+
 #[derive_const(Default)]
 pub struct DebugMeta;
 
@@ -36,6 +322,7 @@ impl Meta for DebugMeta {
 }
 
 pub fn debug_isr() {
+    rx_isr();
     DEBUG.isr();
 }
 
@@ -51,23 +338,36 @@ pub fn init() {
 
     rcc.APB1LENR.modify(|_,w| w.USART3EN().set_bit());
 
-    gpioa.AFRH.modify(|_,w| w.AFSEL15().B_0xD());
+    // PA15 is the blue LED on production boards, so only the prototype has
+    // the RX.
+    let rx = *crate::cpu::IS_PROTOTYPE.as_ref();
+    if rx {
+        gpioa.AFRH.modify(|_,w| w.AFSEL15().B_0xD());
+        gpioa.PUPDR.modify(|_,w| w.PUPD15().B_0x1());    // Pull-up RX.
+        gpioa.MODER.modify(|_,w| w.MODE15().B_0x2());
+    }
     gpiob.AFRL.modify(|_,w| w.AFSEL3().B_0xD());
-    gpioa.MODER.modify(|_,w| w.MODE15().B_0x2());
     gpiob.MODER.modify(|_,w| w.MODE3().B_0x2());
 
     uart.BRR.write(|w| w.bits(BRR));
 
+    uart.CR3.write(|w| w.DMAT().set_bit());
     uart.CR1.write(
-        |w|w.FIFOEN().set_bit().TE().set_bit().UE().set_bit());
+        |w|w.FIFOEN().set_bit().RXFNEIE().bit(rx).RE().bit(rx)
+            .TE().set_bit().UE().set_bit());
+
+    let dma = unsafe {&*DMA::ptr()};
+    rcc.AHB1ENR.modify(|_,w| w.GPDMA1EN().set_bit());
+    dma.C[DMA_CHANNEL].writes_to(uart.TDR.as_ptr() as *mut u8, TX_DMA_REQ);
 
     stm_common::interrupt::enable_priority(INTERRUPT, PRIO_DEBUG);
+    stm_common::interrupt::enable_priority(DMA_INTERRUPT, PRIO_COMMS);
 }
 
 #[cfg(target_os = "none")]
 #[panic_handler]
 fn ph(info: &core::panic::PanicInfo) -> ! {
-    stm_common::dbgln!("{info}");
+    fallback_fmt(format_args!("{info}\n"));
     loop {
         stm_common::debug::flush::<DebugMeta>();
     }
@@ -77,6 +377,7 @@ impl crate::cpu::Config {
     pub const fn debug(&mut self) -> &mut Self {
         if crate::DEBUG_ENABLE {
             self.vectors.isr(INTERRUPT, debug_isr);
+            self.vectors.isr(DMA_INTERRUPT, dma_isr);
         }
         self
     }
@@ -86,5 +387,29 @@ impl crate::cpu::Config {
 fn check_isr() {
     if crate::DEBUG_ENABLE {
         assert!(crate::VECTORS.isr[INTERRUPT as usize] == debug_isr);
+        assert!(crate::VECTORS.isr[DMA_INTERRUPT as usize] == dma_isr);
     }
 }
+
+#[test]
+fn test_rx_framer() {
+    let mut f = RxFramer::default();
+    let mut buf = [0u8; 64];
+    let mut frames = Vec::new();
+    let mut feed = |f: &mut RxFramer, buf: &mut [u8; 64], data: &[u8]| {
+        for &b in data {
+            if let Some(len) = f.push(buf, b) {
+                frames.push(buf[..len].to_vec());
+            }
+        }
+    };
+    // Noise, then a stray magic byte, then a ping with a two byte payload.
+    let ping = [0xce, 0x93, 0x00, 0x02, 0x12, 0x34, 0xab, 0xcd];
+    feed(&mut f, &mut buf, b"hello");
+    feed(&mut f, &mut buf, &[0xce]);
+    feed(&mut f, &mut buf, &ping);
+    // An over-long length is rejected, and the framer resyncs.
+    feed(&mut f, &mut buf, &[0xce, 0x93, 0x00, 59]);
+    feed(&mut f, &mut buf, &[0xce, 0x93, 0x02, 0x00, 0x11, 0x22]);
+    assert_eq!(frames, [ping.to_vec(), vec![0xce, 0x93, 0x02, 0x00, 0x11, 0x22]]);
+}
@@ -0,0 +1,34 @@
+//! GPDMA1 bookkeeping shared between the GPS UART (channels 0 and 3), the
+//! I²C (channels 1 and 2) and the debug UART (channel 4).
+
+use stm_common::vcell::VCell;
+
+use crate::cpu::Priority;
+use crate::cpu::interrupt::PRIO_COMMS;
+
+/// Number of GPDMA1 channels we use.
+pub const NUM_CHANNELS: usize = 5;
+
+/// Error flags in a channel status register: DTEF, ULEF, USEF and TOF.
+const SR_ERRORS: u32 = 0x5c00;
+
+/// Cumulative count of errored transfers on each channel.  Updated by the
+/// DMA ISRs.
+static ERRORS: [VCell<u32>; NUM_CHANNELS]
+    = [const {VCell::new(0)}; NUM_CHANNELS];
+
+/// Record any errors in a channel status register value.  Call from the
+/// channel's ISR.
+pub fn note_status(channel: usize, sr: u32) {
+    if sr & SR_ERRORS != 0 {
+        let count = &ERRORS[channel];
+        count.write(count.read().wrapping_add(1));
+    }
+}
+
+/// Fetch and reset the error counts.
+pub fn take_errors() -> [u32; NUM_CHANNELS] {
+    // The ISRs run at comms priority, so block them while we read-and-clear.
+    let _prio = Priority::<PRIO_COMMS>::default();
+    ERRORS.each_ref().map(|c| {let v = c.read(); c.write(0); v})
+}
@@ -1,12 +1,82 @@
 //! Flash memory handling.  The flash is in two 64kB banks, at 0x08000000
 //! and 0x08010000 repspectively.  We run from the first bank and only write
 //! to the second bank.
+//!
+//! A new firmware image may be written to the second bank, and then activated
+//! by swapping the banks, via the SWAP_BANK option bit, and rebooting.  After a
+//! swap, the bank we run from is still mapped at 0x08000000, and the other bank
+//! at 0x08010000.
+//!
+//! An image occupies the first six sectors of the bank (the last two hold
+//! configs), and is followed by its big-endian CRC32.  The u32 length of the
+//! image, including the CRC, is stored in the last word of the sixth sector.
+//! We only swap to an image with a valid CRC.  The length and CRC are added to
+//! the binary on the host, by `py/seal_image.py`.
 
 use stm_common::interrupt;
 
 pub type Mem32 = [u32; 8];
 
-pub type Result = core::result::Result<(), ()>;
+pub type Result = core::result::Result<(), Failure>;
+
+/// Why a flash operation failed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failure {
+    /// Address out of range, or misaligned.
+    Range,
+    /// The flash controller is busy, or could not be unlocked.
+    Busy,
+    /// The target is already written.
+    NotErased,
+    /// Reading back after programming did not match.
+    Verify,
+    /// NSSR WRPERR, write protection.
+    WriteProtect,
+    /// NSSR PGSERR, programming sequence error.
+    Sequence,
+    /// NSSR STRBERR, strobe error.
+    Strobe,
+    /// NSSR INCERR, inconsistency error.
+    Inconsistent,
+    /// Other NSSR error bits.
+    Status(u32),
+}
+
+/// NSSR error flags.
+const NSSR_ERRORS : u32 = 0xfffe0000;
+const NSSR_WRPERR : u32 = 1 << 17;
+const NSSR_PGSERR : u32 = 1 << 18;
+const NSSR_STRBERR: u32 = 1 << 19;
+const NSSR_INCERR : u32 = 1 << 20;
+
+/// Read back and compare the data after programming, to catch a silent
+/// programming failure.
+const VERIFY_WRITES: bool = true;
+
+/// Size of each flash bank.
+pub const BANK_SIZE: usize = 0x10000;
+
+/// Address of the active bank, that we run from.
+pub const ACTIVE_BANK: usize = 0x08000000;
+
+/// Address of the inactive bank.
+pub const INACTIVE_BANK: usize = ACTIVE_BANK + BANK_SIZE;
+
+/// Size of the image area at the start of a bank.
+const IMAGE_MAX: usize = 0xc000;
+
+/// Offset of the image length word.
+const IMAGE_LENGTH: usize = IMAGE_MAX - 4;
+
+unsafe extern "C" {
+    static __data_start: u8;
+    static __data_end: u8;
+    static __rom_data_start: u8;
+}
+
+/// Option byte unlock keys.
+const OPT_KEY1: u32 = 0x08192a3b;
+const OPT_KEY2: u32 = 0x4c5d6e7f;
 
 macro_rules!dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
 
@@ -18,9 +88,9 @@ pub unsafe fn program32(address: usize, data: &Mem32) -> Result {
     address_check(address, 31)?;
 
     // Check that the flash block is erased.
-    if unsafe{&*(address as *const Mem32)}.iter().any(|&x| x != !0) {
+    if !is_blank(address) {
         dbgln!("FLASH - block @ {address:#010x} is already written.");
-        return Err(());
+        return Err(Failure::NotErased);
     }
     if data.iter().all(|&x| x == !0) {
         dbgln!("FLASH - nothing to do.");
@@ -28,7 +98,7 @@ pub unsafe fn program32(address: usize, data: &Mem32) -> Result {
     }
     if busy() {
         dbgln!("FLASH - busy! WTF? NSSR = {:#010x}", flash.NSSR.read().bits());
-        return Err(());
+        return Err(Failure::Busy);
     }
 
     write_unlock()?;
@@ -41,31 +111,86 @@ pub unsafe fn program32(address: usize, data: &Mem32) -> Result {
         unsafe {core::ptr::write_volatile(target.wrapping_add(i), b)};
     }
 
-    flash_result()
+    flash_result()?;
+
+    if VERIFY_WRITES && !verify(address, data) {
+        dbgln!("FLASH - verify failed @ {address:#010x}.");
+        return Err(Failure::Verify);
+    }
+    Ok(())
 }
 
-/// Erase a sector (in the inactive bank), or erase the entire bank.
-pub fn erase(address: usize) -> Result {
-    let flash = unsafe {&*stm32h503::FLASH::ptr()};
+/// Compare flash contents against the data just written.
+fn verify(address: usize, data: &Mem32) -> bool {
+    let icache = unsafe {&*stm32h503::ICACHE::ptr()};
+    // The ICACHE invalidation was started by write_unlock; make sure that it
+    // is complete, so that we see the fresh data.
+    while icache.SR.read().BUSYF().bit() {}
+    let target = address as *const u32;
+    data.iter().enumerate().all(
+        |(i, &b)| unsafe {core::ptr::read_volatile(target.wrapping_add(i))} == b)
+}
 
-    let bank = address == 0x0801ffff;
-    if bank {
-        dbgln!("FLASH - bank erase");
+/// Program an arbitrary range of bytes.  Every 32 byte row touched must be
+/// erased, including the parts outside the range, which are left erased.  All
+/// the rows are checked before anything is written.
+pub unsafe fn program(address: usize, data: &[u8]) -> Result {
+    if data.is_empty() {
+        return Ok(());
     }
-    else {
-        dbgln!("FLASH - erase_sector {address:#010x}");
-        address_check(address, 8191)?;
+    let end = address.checked_add(data.len()).ok_or(Failure::Range)?;
+    let rows = (address & !31 .. end).step_by(32);
+    for row in rows.clone() {
+        address_check(row, 31)?;
+        if !is_blank(row) {
+            dbgln!("FLASH - block @ {row:#010x} is already written.");
+            return Err(Failure::NotErased);
+        }
+    }
+    for row in rows {
+        unsafe {program32(row, &row_data(row, address, data))}?;
     }
+    Ok(())
+}
+
+/// The contents of the 32 byte row at `row` for programming `data` at
+/// `address`, with bytes outside the data left erased.
+fn row_data(row: usize, address: usize, data: &[u8]) -> Mem32 {
+    let byte = |i: usize| (row + i).checked_sub(address)
+        .and_then(|o| data.get(o)).copied().unwrap_or(0xff);
+    core::array::from_fn(
+        |w| u32::from_le_bytes(core::array::from_fn(|b| byte(w * 4 + b))))
+}
+
+/// Is the 32 byte row at `address` erased?
+fn is_blank(address: usize) -> bool {
+    unsafe{&*(address as *const Mem32)}.iter().all(|&x| x == !0)
+}
+
+/// Erase a sector in the inactive bank.
+pub fn erase(address: usize) -> Result {
+    dbgln!("FLASH - erase_sector {address:#010x}");
+    address_check(address, 8191)?;
+    erase_op(false, address >> 13 & 7)
+}
+
+/// Erase the entire inactive bank.
+pub fn erase_bank() -> Result {
+    dbgln!("FLASH - bank erase");
+    erase_op(true, 0)
+}
+
+fn erase_op(bank: bool, snb: usize) -> Result {
+    let flash = unsafe {&*stm32h503::FLASH::ptr()};
 
     if busy() {
         dbgln!("FLASH - busy! WTF? NSSR = {:#010x}", flash.NSSR.read().bits());
-        return Err(());
+        return Err(Failure::Busy);
     }
 
     write_unlock()?;
 
     let swapped = flash.OPTCR.read().SWAP_BANK().bit();
-    let snb = if bank {0} else {address >> 13 & 7};
 
     dbgln!("FLASH - bank {bank} swapped {swapped} sector number {snb}");
 
@@ -76,10 +201,124 @@ pub fn erase(address: usize) -> Result {
     flash_result()
 }
 
+/// Does the inactive bank hold an image with a valid length and CRC?
+pub fn inactive_image_ok() -> bool {image_ok(INACTIVE_BANK)}
+
+/// Does the active bank hold an image with a valid length and CRC, and is it
+/// the length of the running build, as given by the linker?
+pub fn active_image_ok() -> bool {
+    image_ok(ACTIVE_BANK) && image_length(ACTIVE_BANK) == linked_length() + 4
+}
+
+/// The length of the running build, up to the end of the initialized data
+/// in flash.
+fn linked_length() -> usize {
+    let data_len = &raw const __data_end as usize
+        - &raw const __data_start as usize;
+    &raw const __rom_data_start as usize + data_len - ACTIVE_BANK
+}
+
+/// The image length word of the image at `base`.
+fn image_length(base: usize) -> usize {
+    unsafe {core::ptr::read_volatile((base + IMAGE_LENGTH) as *const u32)}
+        as usize
+}
+
+/// Check the image length word and CRC of the image at `base`.
+fn image_ok(base: usize) -> bool {
+    let length = image_length(base);
+    if length < 8 || length > IMAGE_LENGTH {
+        dbgln!("FLASH - image length {length:#x} bad.");
+        return false;
+    }
+    crate::crc32::compute(base as *const u8, length)
+        == crate::crc32::VERIFY_MAGIC
+}
+
+/// Are the banks currently swapped?
+pub fn bank_swapped() -> bool {
+    let flash = unsafe {&*stm32h503::FLASH::ptr()};
+    flash.OPTCR.read().SWAP_BANK().bit()
+}
+
+/// Program the SWAP_BANK option bit.  This takes effect at the next reset.
+/// The OPTCR copy of the bit is read-only status; the bit is programmed via
+/// OPTSR_PRG.
+pub fn set_bank_swap(swap: bool) -> Result {
+    let flash = unsafe {&*stm32h503::FLASH::ptr()};
+    dbgln!("FLASH - set bank swap {swap}");
+    program_options(|| {flash.OPTSR_PRG.modify(|_,w| w.SWAP_BANK().bit(swap));})
+}
+
+/// The brown-out reset level, 0 to 3, from the option bytes: 0 is the default
+/// BOR level (BORH_EN clear), 1 to 3 are BOR_LEV 0 to 2 with BORH_EN set.
+/// Returns the current level and the programmed level.
+pub fn bor_level() -> [u8; 2] {
+    let flash = unsafe {&*stm32h503::FLASH::ptr()};
+    let cur = flash.OPTSR_CUR.read();
+    let prg = flash.OPTSR_PRG.read();
+    [bor_level_of(cur.BORH_EN().bit(), cur.BOR_LEV().bits()),
+     bor_level_of(prg.BORH_EN().bit(), prg.BOR_LEV().bits())]
+}
+
+fn bor_level_of(borh_en: bool, bor_lev: u8) -> u8 {
+    if borh_en {bor_lev.min(2) + 1} else {0}
+}
+
+/// Program the brown-out reset level, as for `bor_level`.  The option bytes
+/// persist, so this should be used sparingly.
+pub fn set_bor_level(level: u8) -> Result {
+    let flash = unsafe {&*stm32h503::FLASH::ptr()};
+    if level > 3 {
+        return Err(Failure::Range);
+    }
+    dbgln!("FLASH - set BOR level {level}");
+    program_options(|| {flash.OPTSR_PRG.modify(
+        |_,w| w.BORH_EN().bit(level != 0)
+            .BOR_LEV().bits(level.saturating_sub(1)));})
+}
+
+/// Run the option byte programming sequence, with `update` making the changes
+/// to the option registers, once they are unlocked.
+fn program_options(update: impl FnOnce()) -> Result {
+    let flash = unsafe {&*stm32h503::FLASH::ptr()};
+
+    if busy() {
+        dbgln!("FLASH - busy! WTF? NSSR = {:#010x}", flash.NSSR.read().bits());
+        return Err(Failure::Busy);
+    }
+
+    // Clear any left over errors.
+    flash.NSCCR.write(|w| w.bits(flash.NSSR.read().bits()));
+
+    if flash.OPTCR.read().OPTLOCK().bit() {
+        interrupt::disable_all();
+        flash.OPTKEYR.write(|w| w.bits(OPT_KEY1));
+        flash.OPTKEYR.write(|w| w.bits(OPT_KEY2));
+        interrupt::enable_all();
+        if flash.OPTCR.read().OPTLOCK().bit() {
+            dbgln!("FLASH - option unlock failed.");
+            return Err(Failure::Busy);
+        }
+    }
+
+    update();
+    flash.OPTCR.modify(|_,w| w.OPTSTRT().set_bit());
+
+    while flash.NSSR.read().BSY().bit() {}
+
+    let errors = flash.NSSR.read().bits();
+    flash.NSCCR.write(|w| w.bits(errors & 0xffff0000));
+    flash.OPTCR.modify(|_,w| w.OPTLOCK().set_bit());
+
+    dbgln!("FLASH - option result NSSR = {errors:#010x}");
+    nssr_result(errors)
+}
+
 fn address_check(address: usize, mask: usize) -> Result {
     if address & mask != 0 || address < 0x08010000 || address >= 0x08020000 {
         dbgln!("FLASH - out of range or unaligned {address:#010x}.");
-        return Err(());
+        return Err(Failure::Range);
     }
     Ok(())
 }
@@ -110,7 +349,7 @@ fn write_unlock() -> Result {
 
     dbgln!("FLASH - after unlock, NSCR = {:#010x}.", flash.NSCR.read().bits());
 
-    if flash.NSCR.read().LOCK().bit() {Err(())} else {Ok(())}
+    if flash.NSCR.read().LOCK().bit() {Err(Failure::Busy)} else {Ok(())}
 }
 
 fn flash_result() -> Result {
@@ -131,12 +370,29 @@ fn flash_result() -> Result {
     dbgln!("FLASH - result NSSR = {:#010x}", errors);
     dbgln!("FLASH - icache CR = {:#010x}", icache.CR.read().bits());
 
-    if errors & 0xfffe0000 == 0 {
-        dbgln!("FLASH success");
+    nssr_result(errors)
+}
+
+/// Convert the NSSR error flags to a result.
+fn nssr_result(errors: u32) -> Result {
+    let errors = errors & NSSR_ERRORS;
+    if errors == 0 {
         Ok(())
-    } else {
-        dbgln!("FLASH failure");
-        Err(())
+    }
+    else if errors & NSSR_WRPERR != 0 {
+        Err(Failure::WriteProtect)
+    }
+    else if errors & NSSR_PGSERR != 0 {
+        Err(Failure::Sequence)
+    }
+    else if errors & NSSR_STRBERR != 0 {
+        Err(Failure::Strobe)
+    }
+    else if errors & NSSR_INCERR != 0 {
+        Err(Failure::Inconsistent)
+    }
+    else {
+        Err(Failure::Status(errors))
     }
 }
 
@@ -146,3 +402,42 @@ fn busy() -> bool {
     // TODO - is it possible to clear the write buffer?
     nssr.BSY().bit() || nssr.DBNE().bit() || nssr.WBNE().bit()
 }
+
+#[test]
+fn test_row_data() {
+    let data: [u8; 40] = core::array::from_fn(|i| i as u8);
+    let row = 0x08010020;
+    // Leading partial row.
+    let r = row_data(row, row + 30, &data);
+    assert_eq!(r[..7], [!0; 7]);
+    assert_eq!(r[7], 0x0100ffff);
+    // Middle row.
+    let r = row_data(row + 32, row + 30, &data);
+    assert_eq!(r[0], 0x05040302);
+    assert_eq!(r[7], 0x21201f1e);
+    // Trailing partial row.
+    let r = row_data(row + 64, row + 30, &data);
+    assert_eq!(r[..2], [0x25242322, 0xffff2726]);
+    assert_eq!(r[2..], [!0; 6]);
+    // Whole row.
+    let r = row_data(row, row, &data[..32]);
+    assert_eq!(r[7], 0x1f1e1d1c);
+}
+
+#[test]
+fn test_nssr_result() {
+    assert_eq!(nssr_result(0x00010000), Ok(()));
+    assert_eq!(nssr_result(NSSR_WRPERR | NSSR_PGSERR), Err(Failure::WriteProtect));
+    assert_eq!(nssr_result(NSSR_PGSERR), Err(Failure::Sequence));
+    assert_eq!(nssr_result(NSSR_STRBERR), Err(Failure::Strobe));
+    assert_eq!(nssr_result(NSSR_INCERR | 0x10000), Err(Failure::Inconsistent));
+    assert_eq!(nssr_result(1 << 23), Err(Failure::Status(1 << 23)));
+}
+
+#[test]
+fn test_bor_level_of() {
+    assert_eq!(bor_level_of(false, 2), 0);
+    assert_eq!(bor_level_of(true, 0), 1);
+    assert_eq!(bor_level_of(true, 2), 3);
+    assert_eq!(bor_level_of(true, 3), 3);
+}
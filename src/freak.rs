@@ -15,12 +15,16 @@ mod command;
 mod cpu;
 mod crc;
 mod crc32;
+mod dma;
 mod flash;
 mod gps_uart;
 mod i2c;
 mod led;
 mod lmk05318b;
 mod provision;
+mod tick;
+mod ubx;
+mod watchdog;
 #[macro_use]
 mod debug;
 mod usb;
@@ -32,7 +36,8 @@ pub const DEBUG_ENABLE: bool = true;
 fn debug_fmt(fmt: core::fmt::Arguments) {
     if DEBUG_ENABLE {
         let _guard = cpu::Priority::<{cpu::interrupt::PRIO_COMMS}>::default();
-        stm_common::debug::debug_fmt::<debug::DebugMeta>(fmt);
+        debug::write_fmt(fmt);
+        debug::copy_to_usb(fmt);
     }
 }
 
@@ -45,6 +50,8 @@ pub fn main() -> ! {
 
     cpu::init();
 
+    watchdog::note_reset_cause();
+
     cpu::maybe_enter_dfu();
 
     if DEBUG_ENABLE {
@@ -55,6 +62,7 @@ pub fn main() -> ! {
 
     crc::init();
     led::init();
+    tick::init();
 
     i2c::init();
 
@@ -76,6 +84,9 @@ pub fn main() -> ! {
 
     usb::init();
 
+    // Slow breathing on the blue LED shows that we are alive.
+    led::BLUE.breathe(4000);
+
     // Enable FPU.  We aren't using it yet!!!
     // unsafe {scb.cpacr.write(0x00f00000)};
 
@@ -99,13 +110,21 @@ pub fn main() -> ! {
         gpiob.MODER.modify(|_,w| w.MODE4().B_0x1().MODE5().B_0x1().MODE8().B_0x1());
     }
 
+    watchdog::init();
+
+    // Start-up is done, so allow DFU entries afresh.
+    cpu::boot_complete();
+
     loop {
         WFE();
+        watchdog::refresh();
+        debug::command_poll();
+        command::run_deferred();
     }
 }
 
 static CONFIG: cpu::Config = *cpu::Config::new()
-        .debug().gps_uart().i2c().led().lmk05318b().usb().command_usb();
+        .debug().gps_uart().i2c().led().lmk05318b().usb().command_usb().tick();
 
 #[used]
 #[unsafe(link_section = ".vectors")]
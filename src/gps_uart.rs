@@ -7,12 +7,13 @@ use crate::cpu::interrupt::PRIO_COMMS;
 use stm_common::dma::DMA_Channel;
 use stm_common::interrupt;
 use stm_common::utils::{WFE, barrier};
-use stm_common::vcell::VCell;
+use stm_common::vcell::{UCell, VCell};
 
 use stm32h503::GPDMA1 as DMA;
 use stm32h503::USART2 as UART;
 use stm32h503::Interrupt::USART2 as INTERRUPT;
 use stm32h503::Interrupt::GPDMA1_CH0 as DMA_INTERRUPT;
+use stm32h503::Interrupt::GPDMA1_CH3 as RX_DMA_INTERRUPT;
 
 // NOTE: In safe boot we seem to need a UU training sequence to get the baud
 // rate sane.
@@ -33,11 +34,93 @@ const DMA_CHANNEL: usize = 0;
 /// USART2 DMA TX.
 const TX_DMA_REQ: u8 = 24;
 
-/// Set to true to loopback our own data instead of processing received data.
-const LOOPBACK: bool = false;
+/// For serial RX we use circular DMA into a ring buffer.
+const RX_DMA_CHANNEL: usize = 3;
+
+/// USART2 DMA RX.
+const RX_DMA_REQ: u32 = 23;
+
+/// Longest TX DMA transfer made once running, a USB serial packet.  Command
+/// transfers are no longer, so a transfer waits for at most this much ahead of
+/// it.
+pub const TX_MAX_LEN: usize = 64;
+
+/// Allowance for DMA and interrupt latency in `tx_time`, 10ms.
+const TX_MARGIN: u32 = crate::cpu::CPU_FREQ / 100;
+
+/// Upper bound for `tx_time`, 400ms, so that a command's waits together stay
+/// clear of the watchdog (e.g., 1e waits twice around a delay of up to 1s).  At
+/// very low baud rates, a long transfer times out rather than completing.
+const TX_TIME_MAX: u32 = crate::cpu::CPU_FREQ / 5 * 2;
+
+/// Size of the RX ring buffer.  At 921600 baud, this is ≈10ms of data.  The
+/// USB serial takes at most 64 bytes per frame, so a fast GPS can overrun it;
+/// see `rx_resync`.  This must divide 2³², so that the byte counts wrap
+/// cleanly.
+const RX_RING_LEN: usize = 1024;
+const _: () = assert!(RX_RING_LEN.is_power_of_two());
+
+#[repr(C, align(4))]
+struct RxRing([u8; RX_RING_LEN]);
+
+/// The RX ring buffer, written by DMA.
+static RX_RING: UCell<RxRing> = UCell::new(RxRing([0; _]));
+
+/// Count of the next byte to forward.  This, and the other ring positions,
+/// count bytes since the DMA was started, and wrap; the index into the ring is
+/// the count modulo `RX_RING_LEN`.
+static RX_POS: VCell<u32> = VCell::new(0);
+
+/// Count of the next byte to snoop, see `rx_snoop`.
+static SNOOP_POS: VCell<u32> = VCell::new(0);
+
+/// Number of times the DMA has wrapped around the ring, counted in the block
+/// complete interrupt.  Together with the DMA block count, this gives the
+/// count of bytes written, so we can tell if the DMA laps a reader.
+static RX_WRAPS: VCell<u32> = VCell::new(0);
+
+/// Bytes skipped because the DMA overtook `RX_POS` before they were forwarded.
+static RX_LOST: VCell<u32> = VCell::new(0);
+
+/// GPDMA linked-list item that reloads the RX block size and destination
+/// address, and links back to itself, making the transfer circular.  Fields
+/// are in register order: BR1, DAR, LLR.
+static RX_LLI: UCell<[u32; 3]> = UCell::new([0; 3]);
+
+/// GPDMA CxLLR: update BR1, DAR and LLR from the linked-list item.  (Bit 25
+/// is UB2, reserved on a linear channel.)
+const LLR_UB1: u32 = 1 << 29;
+const LLR_UDA: u32 = 1 << 27;
+const LLR_ULL: u32 = 1 << 16;
+
+/// When set, data from USB is echoed back to USB, and data received from the
+/// GPS is discarded.  The USB data is still written to the UART, as the TX DMA
+/// completion paces the USB serial RX.
+static LOOPBACK: VCell<bool> = VCell::new(false);
 
 static BAUD_RATE: VCell<u32> = VCell::new(BAUD);
 
+/// Character framing: data bits, parity (0 none, 1 even, 2 odd) and stop bits.
+static FRAMING: VCell<[u8; 3]> = VCell::new([8, 0, 1]);
+
+/// Count of TX DMA transfers started, and completed (or errored).  Used to
+/// wait for a particular transfer, see `dma_tx_seq` and `tx_complete`.
+static TX_STARTED: VCell<u32> = VCell::new(0);
+static TX_DONE: VCell<u32> = VCell::new(0);
+
+/// Link statistics: bytes received, bytes forwarded to USB, and TX DMA
+/// requests refused because the DMA was busy.
+static RX_BYTES: VCell<u32> = VCell::new(0);
+static RX_FORWARDED: VCell<u32> = VCell::new(0);
+static TX_BUSY: VCell<u32> = VCell::new(0);
+
+/// Counts of RX overrun, framing and noise errors.
+static RX_ERRORS: [VCell<u32>; 3]
+    = [VCell::new(0), VCell::new(0), VCell::new(0)];
+
+/// Clock divisions for the UART PRESC register values.
+const PRESC_DIV: [u32; 12] = [1, 2, 4, 6, 8, 10, 12, 16, 32, 64, 128, 256];
+
 macro_rules!dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
 
 pub fn init() {
@@ -57,28 +140,162 @@ pub fn init() {
 
     uart.BRR.write(|w| w.bits(BRR));
 
-    uart.CR3.write(|w| w.RXFTCFG().bits(5).RXFTIE().set_bit().DMAT().set_bit());
+    // RX is by DMA, we only take interrupts for errors and TX complete.
+    uart.CR3.write(|w| w.DMAR().set_bit().DMAT().set_bit().EIE().set_bit());
     uart.CR1.write(
-        |w|w.FIFOEN().set_bit().RE().set_bit().RXFNEIE().set_bit()
+        |w|w.FIFOEN().set_bit().RE().set_bit()
             .TCIE().set_bit().TE().set_bit().UE().set_bit());
 
     let ch = &dma.C[DMA_CHANNEL];
     ch.writes_to(uart.TDR.as_ptr() as *mut u8, TX_DMA_REQ);
 
+    rx_dma_init();
+
     // We interact with the USB subsystem, so share its priority.
     interrupt::enable_priority(INTERRUPT, PRIO_COMMS);
     interrupt::enable_priority(DMA_INTERRUPT, PRIO_COMMS);
+    interrupt::enable_priority(RX_DMA_INTERRUPT, PRIO_COMMS);
+}
+
+/// Start the circular RX DMA from the UART RDR into the ring buffer.
+fn rx_dma_init() {
+    let dma  = unsafe {&*DMA ::ptr()};
+    let uart = unsafe {&*UART::ptr()};
+    let ch = &dma.C[RX_DMA_CHANNEL];
+
+    let ring = RX_RING.as_ref().0.as_ptr() as u32;
+    let lli = RX_LLI.as_ref().as_ptr() as u32;
+    let llr = LLR_UB1 | LLR_UDA | LLR_ULL | lli & 0xfffc;
+    *unsafe {RX_LLI.as_mut()} = [RX_RING_LEN as u32, ring, llr];
+    barrier();
+
+    // Byte transfers, incrementing the destination only.
+    ch.TR1.write(|w| w.bits(1 << 19));  // DINC
+    // Peripheral request, block complete events.
+    ch.TR2.write(|w| w.bits(RX_DMA_REQ));
+    ch.BR1.write(|w| w.bits(RX_RING_LEN as u32));
+    ch.SAR.write(|w| w.bits(uart.RDR.as_ptr() as u32));
+    ch.DAR.write(|w| w.bits(ring));
+    ch.LBAR.write(|w| w.bits(lli & 0xffff0000));
+    ch.LLR.write(|w| w.bits(llr));
+    // Half and full transfer interrupts, plus errors, and go.
+    ch.CR.write(
+        |w| w.TCIE().set_bit().HTIE().set_bit().DTEIE().set_bit()
+             .ULEIE().set_bit().USEIE().set_bit().EN().set_bit());
+}
+
+/// Forward received GPS data from the ring buffer to USB.  Called from the USB
+/// SOF and the RX DMA interrupts, which share our priority.  We only forward
+/// what the USB serial TX can take, and leave the rest in the ring.
+pub fn rx_forward() {
+    let end = rx_end();
+    let ring = &RX_RING.as_ref().0;
+    let (mut pos, lost) = rx_resync(RX_POS.read(), end);
+    let mut space = crate::usb::serial::serial_tx_space();
+    let mut received = lost;
+    let mut forwarded = 0;
+    while pos != end && space > 0 {
+        if !LOOPBACK.read() {
+            crate::usb::serial::serial_tx_byte(ring[ring_index(pos)]);
+            space -= 1;
+            forwarded += 1;
+        }
+        pos = pos.wrapping_add(1);
+        received += 1;
+    }
+    RX_POS.write(pos);
+    RX_LOST.write(RX_LOST.read().wrapping_add(lost));
+    RX_BYTES.write(RX_BYTES.read().wrapping_add(received));
+    RX_FORWARDED.write(RX_FORWARDED.read().wrapping_add(forwarded));
+}
+
+/// Count of bytes that the DMA has written to the ring.  Call at our priority,
+/// so that the RX DMA ISR cannot run underneath us.
+fn rx_end() -> u32 {
+    let dma = unsafe {&*DMA::ptr()};
+    let ch = &dma.C[RX_DMA_CHANNEL];
+    loop {
+        // A wrap not yet counted by the ISR shows as a pending TC.  Make sure
+        // that the block count is consistent with it.
+        let pending = ch.SR.read().TCF().bit();
+        let remaining = ch.BR1.read().bits() & 0xffff;
+        if ch.SR.read().TCF().bit() != pending {
+            continue;
+        }
+        let wraps = RX_WRAPS.read().wrapping_add(pending as u32);
+        return rx_count(wraps, remaining);
+    }
+}
+
+/// Count of bytes written, from the number of wraps, and the DMA block count
+/// remaining.  BNDT counts down, and reloads on wrap.
+fn rx_count(wraps: u32, remaining: u32) -> u32 {
+    let offset = (RX_RING_LEN as u32 - remaining) % RX_RING_LEN as u32;
+    wraps.wrapping_mul(RX_RING_LEN as u32).wrapping_add(offset)
+}
+
+/// Index into the ring of the byte with count `pos`.
+fn ring_index(pos: u32) -> usize {pos as usize % RX_RING_LEN}
+
+/// Check a reader at `pos` against the DMA at `end`.  If the DMA has lapped
+/// the reader, the data at `pos` is gone, so skip forward to `end`.  Returns
+/// the new position, and the number of bytes skipped.  If `pos` appears ahead
+/// of `end`, the DMA is just about to count a wrap; leave it alone.
+fn rx_resync(pos: u32, end: u32) -> (u32, u32) {
+    let behind = end.wrapping_sub(pos);
+    if (behind as i32) > RX_RING_LEN as i32 {(end, behind)} else {(pos, 0)}
+}
+
+/// Start snooping received data, from the next byte received.
+pub fn rx_snoop_start() {
+    let _prio = GpsPriority::default();
+    SNOOP_POS.write(rx_end());
+}
+
+/// Pass any received data since the last call to `f`, independently of
+/// forwarding to USB.  If we fall more than a ring's worth behind, then data is
+/// lost, and we carry on from the most recent byte received.
+pub fn rx_snoop(mut f: impl FnMut(u8)) {
+    let _prio = GpsPriority::default();
+    let end = rx_end();
+    let ring = &RX_RING.as_ref().0;
+    let (mut pos, _) = rx_resync(SNOOP_POS.read(), end);
+    while pos != end {
+        f(ring[ring_index(pos)]);
+        pos = pos.wrapping_add(1);
+    }
+    SNOOP_POS.write(pos);
+}
+
+/// Compute the PRESC and BRR values for a baud rate.  We use the smallest
+/// prescaler that brings BRR within range, to keep the most resolution.
+/// Returns None if the baud rate is out of range.
+fn baud_divisors(baud: u32) -> Option<(u8, u32)> {
+    if baud == 0 {
+        return None;
+    }
+    for (presc, &div) in PRESC_DIV.iter().enumerate() {
+        let clock = crate::cpu::CPU_FREQ / div;
+        let brr = (clock + baud / 2) / baud;
+        if brr < 16 {
+            return None;
+        }
+        if brr < 65536 {
+            return Some((presc as u8, brr));
+        }
+    }
+    None
 }
 
 pub fn set_baud_rate(baud: u32) -> bool {
     let uart  = unsafe {&*UART::ptr()};
+    let Some((presc, brr)) = baud_divisors(baud) else {return false};
     // We need to disable the UART to update the baud rate.
-    // FIXME - use the prescalar also.
-    let brr = (crate::cpu::CPU_FREQ * 2 + baud) / (baud * 2);
     // We are called from the USB ISR, which is the same priority as our ISRs.
     // So there should be no interrupt to race with.
     let config = uart.CR1.read().bits();
     uart.CR1.write(|w| w.UE().clear_bit());
+    uart.PRESC.write(|w| w.PRESCALER().bits(presc));
     uart.BRR.write(|w| w.bits(brr));
     uart.CR1.write(|w| w.bits(config));
     BAUD_RATE.write(baud);
@@ -89,28 +306,184 @@ pub fn get_baud_rate() -> u32 {
     BAUD_RATE.read()
 }
 
+/// UART register settings for a character framing, see `framing_bits`.
+#[derive(Debug, PartialEq)]
+struct FramingBits {
+    m0: bool,
+    m1: bool,
+    pce: bool,
+    ps: bool,
+    stop: u8,
+}
+
+/// Compute the CR1 word length and parity, and CR2 stop bit, settings for a
+/// framing.  The UART word includes the parity bit, and is 7, 8 or 9 bits.
+/// We only support 7 or 8 data bits, as the DMA transfers bytes.  Returns None
+/// for unsupported combinations.
+fn framing_bits(data: u8, parity: u8, stop: u8) -> Option<FramingBits> {
+    if parity > 2 || !(7 ..= 8).contains(&data) {
+        return None;
+    }
+    let pce = parity != 0;
+    let (m1, m0) = match data + pce as u8 {
+        7 => (true, false),
+        8 => (false, false),
+        _ => (false, true),
+    };
+    let stop = match stop {1 => 0, 2 => 2, _ => return None};
+    Some(FramingBits{m0, m1, pce, ps: parity == 2, stop})
+}
+
+/// Set the character framing: 7 or 8 data bits, parity 0 (none), 1 (even) or
+/// 2 (odd), and 1 or 2 stop bits.  With parity enabled, the received parity
+/// bit is left in the MSB of 7 bit data.  Returns false if the combination is
+/// not supported.  Like `set_baud_rate`, this must be called at our interrupt
+/// priority.
+pub fn set_framing(data: u8, parity: u8, stop: u8) -> bool {
+    let uart = unsafe {&*UART::ptr()};
+    let Some(bits) = framing_bits(data, parity, stop) else {return false};
+    // The framing can only be changed with the UART disabled.
+    let config = uart.CR1.read().bits();
+    uart.CR1.write(|w| w.UE().clear_bit());
+    uart.CR2.modify(|_,w| w.STOP().bits(bits.stop));
+    uart.CR1.write(
+        |w|w.bits(config).M0().bit(bits.m0).M1().bit(bits.m1)
+            .PCE().bit(bits.pce).PS().bit(bits.ps));
+    FRAMING.write([data, parity, stop]);
+    true
+}
+
+/// Returns [data bits, parity, stop bits].
+pub fn get_framing() -> [u8; 3] {FRAMING.read()}
+
+/// The baud rate the hardware is actually running, from the current PRESC and
+/// BRR register values.  Returns [BRR, prescaler division, actual baud].
+pub fn actual_baud() -> [u32; 3] {
+    let uart = unsafe {&*UART::ptr()};
+    let presc = uart.PRESC.read().PRESCALER().bits();
+    let brr = uart.BRR.read().bits() & 0xffff;
+    // PRESC values above 11 divide by 256.
+    let div = PRESC_DIV[(presc as usize).min(PRESC_DIV.len() - 1)];
+    [brr, div, divisors_baud(div, brr)]
+}
+
+/// The baud rate resulting from a prescaler division and BRR value, rounded.
+fn divisors_baud(div: u32, brr: u32) -> u32 {
+    if brr == 0 {
+        return 0;
+    }
+    let clock = crate::cpu::CPU_FREQ / div;
+    (clock + brr / 2) / brr
+}
+
+/// Set the TX DMA channel priority (0..3) and the UART TX FIFO threshold
+/// (TXFTCFG encoding, 0..5).  Returns false if the DMA is busy.  Like
+/// `set_baud_rate`, this must be called at our interrupt priority.
+pub fn set_dma_tuning(prio: u8, tx_fifo: u8) -> bool {
+    let dma  = unsafe {&*DMA ::ptr()};
+    let uart = unsafe {&*UART::ptr()};
+    let ch = &dma.C[DMA_CHANNEL];
+
+    if ch.busy() {
+        return false;
+    }
+    ch.CR.modify(|_,w| w.PRIO().bits(prio));
+
+    // The FIFO threshold can only be changed with the UART disabled.
+    let config = uart.CR1.read().bits();
+    uart.CR1.write(|w| w.UE().clear_bit());
+    uart.CR3.modify(|_,w| w.TXFTCFG().bits(tx_fifo));
+    uart.CR1.write(|w| w.bits(config));
+    true
+}
+
+/// Returns [TX DMA priority, TX FIFO threshold].
+pub fn get_dma_tuning() -> [u8; 2] {
+    let dma  = unsafe {&*DMA ::ptr()};
+    let uart = unsafe {&*UART::ptr()};
+    [dma.C[DMA_CHANNEL].CR.read().PRIO().bits(),
+     uart.CR3.read().TXFTCFG().bits()]
+}
+
+/// Drive the GPS reset line (PB1, active low).
+pub fn set_reset(reset: bool) {
+    let gpiob = unsafe {&*stm32h503::GPIOB::ptr()};
+    if reset {
+        gpiob.BSRR.write(|w| w.BR1().set_bit());
+    }
+    else {
+        gpiob.BSRR.write(|w| w.BS1().set_bit());
+    }
+}
+
+/// Send a break character on the UART TX.
+pub fn send_break() {
+    let uart = unsafe {&*UART::ptr()};
+    uart.RQR.write(|w| w.SBKRQ().set_bit());
+}
+
+/// Enable or disable loopback of USB serial data.
+pub fn set_loopback(loopback: bool) {
+    let _prio = GpsPriority::default();
+    LOOPBACK.write(loopback);
+}
+
+pub fn get_loopback() -> bool {LOOPBACK.read()}
+
+/// Fetch and reset the RX error counts: [overrun, framing, noise].
+pub fn take_rx_errors() -> [u32; 3] {
+    let _prio = GpsPriority::default();
+    RX_ERRORS.each_ref().map(|c| {let v = c.read(); c.write(0); v})
+}
+
+/// Link statistics: [bytes received, bytes forwarded to USB, TX DMA busy
+/// refusals, bytes dropped by the USB serial TX, bytes lost to RX ring
+/// overruns].  GPS data is only offered to the USB serial as space allows, so
+/// it is lost to ring overruns, not USB serial drops.  The counts are
+/// cumulative and wrap.
+pub fn link_stats() -> [u32; 5] {
+    let _prio = GpsPriority::default();
+    [RX_BYTES.read(), RX_FORWARDED.read(), TX_BUSY.read(),
+     crate::usb::serial::serial_tx_drops(), RX_LOST.read()]
+}
+
 /// Returns false if the DMA is busy, or true if the DMA is started.
 /// Len must fit in 16 bits.  This is called at the same priority as our
 /// interrupt handlers, so we do not race with our ISRs.
 pub fn dma_tx(data: *const u8, len: usize) -> bool {
+    dma_tx_seq(data, len).is_some()
+}
+
+/// As `dma_tx`, but on success returns a sequence number for the transfer,
+/// to pass to `tx_complete`.
+pub fn dma_tx_seq(data: *const u8, len: usize) -> Option<u32> {
     dbgln!("UART TX {len} bytes");
-    if LOOPBACK {
-        for b in unsafe {core::slice::from_raw_parts(data, len)} {
-            // Evil alert - this is broken rust, we grab a second &mut pointer!
-            // It's only debug code so I don't care.
-            crate::usb::serial::serial_tx_byte(*b);
-        }
-    }
     let dma  = unsafe {&*DMA::ptr()};
     let ch = &dma.C[DMA_CHANNEL];
 
     if ch.busy() {
-        return false;
+        TX_BUSY.write(TX_BUSY.read().wrapping_add(1));
+        return None;
     }
 
+    if LOOPBACK.read() {
+        for b in unsafe {core::slice::from_raw_parts(data, len)} {
+            // Evil alert - this is broken rust, we grab a second &mut pointer!
+            crate::usb::serial::serial_tx_byte(*b);
+        }
+    }
+
+    let seq = TX_STARTED.read().wrapping_add(1);
+    TX_STARTED.write(seq);
     ch.write(data as usize, len, 0);
     barrier();
-    true
+    Some(seq)
+}
+
+/// Has the transfer with sequence number `seq` completed?  Transfers complete
+/// in order, so this does not depend on any later transfers.
+pub fn tx_complete(seq: u32) -> bool {
+    TX_DONE.read().wrapping_sub(seq) as i32 >= 0
 }
 
 pub fn dma_tx_busy() -> bool {
@@ -118,15 +491,40 @@ pub fn dma_tx_busy() -> bool {
     dma.C[DMA_CHANNEL].busy()
 }
 
-pub fn wait_for_tx_idle() {
+/// Wait for the UART TX to go idle, giving up after `timeout` CPU cycles, and
+/// returning false.  This uses the cycle counter rather than the uptime, as the
+/// SysTick runs below the priority of the command handlers.  See `tx_time` for
+/// a suitable timeout.
+pub fn wait_for_tx_idle_timeout(timeout: u32) -> bool {
     let uart = unsafe {&*UART::ptr()};
+    let start = crate::tick::cycles();
     while dma_tx_busy() || !uart.ISR.read().TC().bit() {
+        if crate::tick::cycles().wrapping_sub(start) > timeout {
+            return false;
+        }
         // Arm the TC interrupt.
         let prio = GpsPriority::default();
         uart.CR1().modify(|_,w| w.TCIE().set_bit());
         drop(prio);
         WFE();
     }
+    true
+}
+
+/// CPU cycles to send `len` characters at the current baud rate and framing,
+/// with a margin for DMA and interrupt latency.  Use as the timeout for TX
+/// waits, so that a stuck UART or DMA gives an error rather than a hang.
+pub fn tx_time(len: usize) -> u32 {
+    let [data, parity, stop] = FRAMING.read();
+    let bits = 1 + data as u32 + (parity != 0) as u32 + stop as u32;
+    tx_cycles(len as u32 * bits, BAUD_RATE.read())
+}
+
+/// CPU cycles to send `bits` at `baud`, plus `TX_MARGIN`, capped at
+/// `TX_TIME_MAX`.
+fn tx_cycles(bits: u32, baud: u32) -> u32 {
+    let cycles = bits as u64 * crate::cpu::CPU_FREQ as u64 / baud.max(1) as u64;
+    (cycles + TX_MARGIN as u64).min(TX_TIME_MAX as u64) as u32
 }
 
 fn uart_isr() {
@@ -138,25 +536,17 @@ fn uart_isr() {
     uart.ICR.write(|w| w.bits(isr.bits()).TCCF().clear_bit());
     //crate::dbg!("UART ISR = {:#010x}", isr.bits());
 
-    let rxfne = isr.RXFNE().bit();
-    // Whenever RXFT is set, or we reach idle, push the data through.
-    // TODO - do we need IDLE interrupt?  We could just poll from SOF.
-    if isr.RXFT().bit() || rxfne && isr.IDLE().bit() && cr1.IDLEIE().bit() {
-        // Drain the FIFO.
-        loop {
-            let byte = uart.RDR.read().bits() as u8;
-            if !LOOPBACK {
-                crate::usb::serial::serial_tx_byte(byte);
-            }
-
-            if !uart.ISR.read().RXFNE().bit() {
-                break;
-            }
+    for (count, flag) in RX_ERRORS.iter().zip(
+            [isr.ORE().bit(), isr.FE().bit(), isr.NE().bit()]) {
+        if flag {
+            count.write(count.read().wrapping_add(1));
         }
     }
 
+    // Received data arrives by DMA, see rx_forward().
+
     uart.CR1.write(
-        |w| w.bits(cr1.bits()).RXFNEIE().bit(!rxfne).IDLEIE().bit(rxfne)
+        |w| w.bits(cr1.bits())
              .TCIE().bit(cr1.TCIE().bit() & !isr.TC().bit()));
 }
 
@@ -166,18 +556,133 @@ fn dma_isr() {
 
     let sr = ch.SR.read();
     ch.FCR.write(|w| w.bits(sr.bits()));      // Clear the interrupts.
+    crate::dma::note_status(DMA_CHANNEL, sr.bits());
 
     // Be care to read CR after SR.
     let cr = ch.CR.read();
 
     if !cr.EN().bit() && sr.bits() & 0x7f00 != 0 {
         // We completed a transfer, or it errored.
+        TX_DONE.write(TX_DONE.read().wrapping_add(1));
         crate::usb::serial::serial_rx_done();
     }
 }
 
+fn rx_dma_isr() {
+    let dma = unsafe {&*DMA::ptr()};
+    let ch = &dma.C[RX_DMA_CHANNEL];
+
+    let sr = ch.SR.read();
+    ch.FCR.write(|w| w.bits(sr.bits()));      // Clear the interrupts.
+    crate::dma::note_status(RX_DMA_CHANNEL, sr.bits());
+
+    if !ch.CR.read().EN().bit() {
+        // An error stopped the DMA.  Restart it, discarding the ring.
+        RX_WRAPS.write(0);
+        RX_POS.write(0);
+        SNOOP_POS.write(0);
+        rx_dma_init();
+        return;
+    }
+    if sr.TCF().bit() {
+        RX_WRAPS.write(RX_WRAPS.read().wrapping_add(1));
+    }
+    // Half or full transfer.  Push the data through before the DMA catches
+    // up with us.
+    rx_forward();
+}
+
 impl crate::cpu::Config {
     pub const fn gps_uart(&mut self) -> &mut Self {
         self.isr(INTERRUPT, uart_isr).isr(DMA_INTERRUPT, dma_isr)
+            .isr(RX_DMA_INTERRUPT, rx_dma_isr)
+    }
+}
+
+#[test]
+fn test_llr_bits() {
+    // RM0492 GPDMA CxLLR: UT1 31, UT2 30, UB1 29, USA 28, UDA 27, ULL 16.
+    const UT1: u32 = 1 << 31;
+    const UT2: u32 = 1 << 30;
+    const USA: u32 = 1 << 28;
+    assert_eq!(LLR_UB1, 1 << 29);
+    assert_eq!(LLR_UDA, 1 << 27);
+    assert_eq!(LLR_ULL, 1 << 16);
+    // The LLI holds exactly BR1, DAR and LLR, so nothing else may be set.
+    let update = LLR_UB1 | LLR_UDA | LLR_ULL;
+    assert_eq!(update.count_ones(), 3);
+    assert_eq!(update & (UT1 | UT2 | USA | 0xffff), 0);
+}
+
+#[test]
+fn test_rx_count() {
+    const LEN: u32 = RX_RING_LEN as u32;
+    assert_eq!(rx_count(0, LEN), 0);
+    assert_eq!(rx_count(0, LEN - 10), 10);
+    assert_eq!(rx_count(3, LEN - 10), 3 * LEN + 10);
+    // BNDT reaching zero before the reload.
+    assert_eq!(rx_count(0, 0), 0);
+    assert_eq!(rx_count(u32::MAX / LEN + 1, 1), LEN - 1);
+}
+
+#[test]
+fn test_rx_resync() {
+    const LEN: u32 = RX_RING_LEN as u32;
+    assert_eq!(rx_resync(100, 100), (100, 0));
+    assert_eq!(rx_resync(100, 100 + LEN), (100, 0));
+    assert_eq!(rx_resync(100, 101 + LEN), (101 + LEN, 1 + LEN));
+    assert_eq!(rx_resync(100, 100 + 5 * LEN), (100 + 5 * LEN, 5 * LEN));
+    // Across the wrap of the counts.
+    assert_eq!(rx_resync(u32::MAX - 5, 10), (u32::MAX - 5, 0));
+    assert_eq!(rx_resync(u32::MAX - 5, LEN + 20), (LEN + 20, LEN + 26));
+    // Reader apparently ahead, waiting on the wrap count.
+    assert_eq!(rx_resync(LEN + 10, 10), (LEN + 10, 0));
+}
+
+#[test]
+fn test_tx_cycles() {
+    // 64 characters of 10 bits at 9600 baud is ≈67ms.
+    assert_eq!(tx_cycles(640, 9600), 10666666 + TX_MARGIN);
+    assert_eq!(tx_cycles(0, 9600), TX_MARGIN);
+    assert_eq!(tx_cycles(640, 921600), 111111 + TX_MARGIN);
+    // 64 characters at 1200 baud is over 500ms, so is capped.
+    assert_eq!(tx_cycles(640, 1200), TX_TIME_MAX);
+    assert_eq!(tx_cycles(640, 2400), 42666666 + TX_MARGIN);
+    assert_eq!(tx_cycles(u32::MAX, 9), TX_TIME_MAX);
+    assert_eq!(tx_cycles(10, 0), TX_TIME_MAX);
+}
+
+#[test]
+fn test_framing_bits() {
+    let bits = |m1, m0, pce, ps, stop| Some(FramingBits{m0, m1, pce, ps, stop});
+    assert_eq!(framing_bits(8, 0, 1), bits(false, false, false, false, 0));
+    assert_eq!(framing_bits(7, 1, 1), bits(false, false, true, false, 0));
+    assert_eq!(framing_bits(8, 1, 1), bits(false, true, true, false, 0));
+    assert_eq!(framing_bits(7, 0, 2), bits(true, false, false, false, 2));
+    assert_eq!(framing_bits(8, 2, 2), bits(false, true, true, true, 2));
+    assert_eq!(framing_bits(9, 0, 1), None);
+    assert_eq!(framing_bits(6, 1, 1), None);
+    assert_eq!(framing_bits(8, 3, 1), None);
+    assert_eq!(framing_bits(8, 0, 0), None);
+    assert_eq!(framing_bits(8, 0, 3), None);
+}
+
+#[test]
+fn test_baud_divisors() {
+    for baud in [300, 1200, 2400, 4800, 9600, 19200, 38400, 57600, 115200,
+                 230400, 460800, 921600] {
+        let (presc, brr) = baud_divisors(baud).unwrap();
+        assert!(brr >= 16 && brr < 65536);
+        let actual = divisors_baud(PRESC_DIV[presc as usize], brr);
+        let error = (actual as i64 - baud as i64).abs();
+        assert!(error * 100 < baud as i64, "{baud} {presc} {brr} {actual}");
     }
+    assert_eq!(baud_divisors(BAUD), Some((0, BRR)));
+    assert_eq!(divisors_baud(2, 625), 128000);
+    assert_eq!(divisors_baud(1, 1389), 115191); // Rounded from 115190.78.
+    assert_eq!(divisors_baud(1, 0), 0);
+    assert_eq!(baud_divisors(0), None);
+    assert_eq!(baud_divisors(20_000_000), None);
+    // Below 160MHz / 256 / 65536.
+    assert_eq!(baud_divisors(9), None);
 }
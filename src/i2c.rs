@@ -7,7 +7,7 @@ use i2c::{F_DMA_RX, F_DMA_TX, I2cContext, Meta};
 /// Interrupt priority for the I2C and its DMA interrupt handlers.  Users of
 /// this code should run at no higher than that priority.
 use crate::cpu::interrupt::PRIO_COMMS as PRIORITY;
-use stm_common::vcell::UCell;
+use stm_common::vcell::{UCell, VCell};
 
 #[derive(Clone, Copy)]
 #[derive_const(Default)]
@@ -38,6 +38,45 @@ const TX_CHANNEL: usize = 2;
 
 static CONTEXT: UCell<I2cContext<I2CMeta>> = UCell::default();
 
+/// Number of consecutive timeouts or bus errors that triggers a bus recovery.
+const AUTO_RECOVER_FAILURES: u32 = 3;
+
+/// Count of consecutive timeouts or bus errors.
+static FAILURES: VCell<u32> = VCell::new(0);
+
+/// Bus speed presets, in kHz, with their TIMINGR fields (PRESC, SCLL, SCLH,
+/// SDADEL, SCLDEL) for the 4MHz CSI kernel clock.  1MHz is not achievable from
+/// that clock.
+const SPEEDS: [(u32, [u8; 5]); 2] = [
+    (100, [0, 19, 15, 2, 4]),
+    (400, [0,  3,  5, 1, 3]),
+];
+
+/// Default bus speed, in kHz.
+const DEFAULT_SPEED: u32 = 400;
+
+/// Current bus speed, in kHz.
+static SPEED: VCell<u32> = VCell::new(DEFAULT_SPEED);
+
+/// I2C ISR error flags, accumulated by the interrupt handlers, so that we can
+/// report why a transaction failed.
+static ERROR_FLAGS: VCell<u32> = VCell::new(0);
+
+const ISR_NACKF: u32 = 1 << 4;
+const ISR_BERR : u32 = 1 << 8;
+const ISR_ARLO : u32 = 1 << 9;
+
+/// Why a transaction failed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failure {
+    /// The device did not acknowledge.
+    Nack,
+    /// Arbitration lost, or bus error.
+    ArbLost,
+    /// The transaction did not complete, with no bus error.
+    Timeout,
+}
+
 macro_rules!dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
 
 pub fn init() {
@@ -60,11 +99,7 @@ pub fn init() {
     rcc.AHB1ENR.modify(|_,w| w.GPDMA1EN().set_bit());
     rcc.APB1LENR.modify(|_,w| w.I2C1EN().set_bit());
 
-    // This is ≈ 400kHz from 4MHz.
-    i2c.TIMINGR.write(
-        |w|w.PRESC().bits(0)
-            .SCLL().bits(3).SCLH().bits(5)
-            .SDADEL().bits(1).SCLDEL().bits(3));
+    write_timing(timing(DEFAULT_SPEED).unwrap());
 
     // Configure the lines for use.
     gpiob.AFRL.modify(|_,w| w.AFSEL6().B_0x4().AFSEL7().B_0x4());
@@ -85,11 +120,173 @@ pub fn init() {
     interrupt::enable_priority(GPDMA1_CH2, PRIORITY);
 }
 
+/// Look up the TIMINGR fields for a bus speed in kHz.
+fn timing(speed: u32) -> Option<[u8; 5]> {
+    SPEEDS.iter().find(|&&(s, _)| s == speed).map(|&(_, t)| t)
+}
+
+fn write_timing([presc, scll, sclh, sdadel, scldel]: [u8; 5]) {
+    I2CMeta.i2c().TIMINGR.write(
+        |w|w.PRESC().bits(presc)
+            .SCLL().bits(scll).SCLH().bits(sclh)
+            .SDADEL().bits(sdadel).SCLDEL().bits(scldel));
+}
+
+/// Set the bus speed, in kHz, to one of the presets.  Returns false if the
+/// speed is not supported.  Do not call with a transaction in progress.
+pub fn set_speed(speed: u32) -> bool {
+    let Some(t) = timing(speed) else {return false};
+    let i2c = I2CMeta.i2c();
+    let _prio = crate::cpu::Priority::<PRIORITY>::default();
+    // TIMINGR may only be written with the peripheral disabled.
+    i2c.CR1.modify(|_,w| w.PE().clear_bit());
+    write_timing(t);
+    i2c.CR1.modify(|_,w| w.PE().set_bit());
+    SPEED.write(speed);
+    true
+}
+
+pub fn get_speed() -> u32 {SPEED.read()}
+
+/// Roughly half an SCL period at 100kHz, 5µs.  Each iteration takes about two
+/// cycles.
+fn half_clock() {
+    for _ in 0 .. crate::cpu::CPU_FREQ / 400000 {
+        stm_common::utils::nothing();
+    }
+}
+
+/// Recover a wedged bus: if a device is holding SDA low (e.g., after an
+/// interrupted transaction), clock SCL until it lets go, and then send a STOP.
+/// The I2C peripheral is disabled while we bit-bang the pins as open-drain
+/// GPIO.  Do not call with a transaction in progress.  Returns true if SDA is
+/// released.
+pub fn bus_recover() -> bool {
+    let i2c   = I2CMeta.i2c();
+    let gpiob = unsafe {&*stm32h503::GPIOB::ptr()};
+    let _prio = crate::cpu::Priority::<PRIORITY>::default();
+
+    dbgln!("I2C bus recovery");
+    i2c.CR1.modify(|_,w| w.PE().clear_bit());
+
+    // Both lines released high, then switch to GPIO.  They are already
+    // open-drain.
+    gpiob.BSRR.write(|w| w.BS6().set_bit().BS7().set_bit());
+    gpiob.MODER.modify(|_, w| w.MODE6().B_0x1().MODE7().B_0x1());
+    half_clock();
+
+    for _ in 0 .. 9 {
+        if gpiob.IDR.read().ID7().bit() {
+            break;
+        }
+        gpiob.BSRR.write(|w| w.BR6().set_bit());
+        half_clock();
+        gpiob.BSRR.write(|w| w.BS6().set_bit());
+        half_clock();
+    }
+
+    // STOP: SDA rising while SCL is high.
+    gpiob.BSRR.write(|w| w.BR6().set_bit());
+    half_clock();
+    gpiob.BSRR.write(|w| w.BR7().set_bit());
+    half_clock();
+    gpiob.BSRR.write(|w| w.BS6().set_bit());
+    half_clock();
+    gpiob.BSRR.write(|w| w.BS7().set_bit());
+    half_clock();
+
+    let released = gpiob.IDR.read().ID7().bit();
+
+    // Back to the I2C alternate function, as per init.
+    gpiob.MODER.modify(|_, w| w.MODE6().B_0x2().MODE7().B_0x2());
+    i2c.CR1.modify(|_,w| w.PE().set_bit());
+
+    FAILURES.write(0);
+    crate::infoln!("I2C bus recovery done, SDA {released}");
+    released
+}
+
+/// Forget any errors recorded from previous transactions.  Call before
+/// starting a transaction whose result will be passed to `note_result`.
+pub fn clear_failure() {
+    ERROR_FLAGS.write(0);
+}
+
+/// Track transaction results, and attempt bus recovery after several
+/// consecutive timeouts or bus errors.  A NACK, e.g., from probing an absent
+/// address, shows that the bus is working, so does not count.  On failure,
+/// returns the cause.
+pub fn note_result(result: Result<(), ()>) -> Result<(), Failure> {
+    let flags = ERROR_FLAGS.read();
+    ERROR_FLAGS.write(0);
+    if result.is_ok() {
+        FAILURES.write(0);
+        return Ok(());
+    }
+    let failure = failure(flags);
+    if !counts_to_recovery(failure) {
+        FAILURES.write(0);
+        return Err(failure);
+    }
+    let failures = FAILURES.read() + 1;
+    FAILURES.write(failures);
+    if failures >= AUTO_RECOVER_FAILURES {
+        bus_recover();
+    }
+    Err(failure)
+}
+
+/// Might the failure indicate a wedged bus, needing recovery?
+fn counts_to_recovery(failure: Failure) -> bool {
+    matches!(failure, Failure::Timeout | Failure::ArbLost)
+}
+
+/// Classify a failure from the recorded ISR flags.  A bus error takes
+/// precedence, as it may provoke a NACK.
+fn failure(flags: u32) -> Failure {
+    if flags & (ISR_ARLO | ISR_BERR) != 0 {
+        Failure::ArbLost
+    }
+    else if flags & ISR_NACKF != 0 {
+        Failure::Nack
+    }
+    else {
+        Failure::Timeout
+    }
+}
+
+/// Read consecutive 16-bit big-endian registers, starting at `reg`, into
+/// `values`.  Each register is read with a pointer write and a repeated-start
+/// read, as devices such as the TMP117 do not auto-increment the register
+/// pointer.  Values are converted to native order.
+pub fn read_regs16(address: u8, reg: u8, values: &mut [u16])
+                   -> Result<(), Failure> {
+    for (i, v) in values.iter_mut().enumerate() {
+        let mut raw = 0u16;
+        clear_failure();
+        note_result(read_reg(address, reg.wrapping_add(i as u8), &mut raw)
+                    .wait())?;
+        *v = u16::from_be(raw);
+    }
+    Ok(())
+}
+
+/// Record the error flags, and then pass on to the common handler.
+fn event_isr() {
+    let isr = I2CMeta.i2c().ISR.read().bits();
+    let errors = isr & (ISR_NACKF | ISR_BERR | ISR_ARLO);
+    if errors != 0 {
+        ERROR_FLAGS.write(ERROR_FLAGS.read() | errors);
+    }
+    i2c_isr();
+}
+
 fn dma_rx_isr() {
     dbgln!("I2C DMA RX ISR");
     let ch = I2CMeta.rx_channel();
     let sr = ch.SR().read();
     ch.FCR().write(|w| w.bits(sr.bits())); // Clear flags.
+    crate::dma::note_status(RX_CHANNEL, sr.bits());
     if sr.TCF().bit() {
         unsafe {*CONTEXT.as_mut().outstanding.as_mut() &= !F_DMA_RX};
     }
@@ -100,6 +297,7 @@ fn dma_tx_isr() {
     let ch = I2CMeta.tx_channel();
     let sr = ch.SR().read();
     ch.FCR().write(|w| w.bits(sr.bits())); // Clear flags.
+    crate::dma::note_status(TX_CHANNEL, sr.bits());
     if sr.TCF().bit() {
         unsafe {*CONTEXT.as_mut().outstanding.as_mut() &= !F_DMA_TX};
     }
@@ -112,8 +310,8 @@ impl crate::cpu::Config {
         use stm32h503::Interrupt::*;
         self.isr(GPDMA1_CH1, dma_rx_isr)
             .isr(GPDMA1_CH2, dma_tx_isr)
-            .isr(I2C1_EV, i2c_isr)
-            .isr(I2C1_ER, i2c_isr)
+            .isr(I2C1_EV, event_isr)
+            .isr(I2C1_ER, event_isr)
     }
 }
 
@@ -124,4 +322,30 @@ fn check_isr() {
     assert_eq!(TX_CHANNEL, 2);
     assert!(crate::VECTORS.isr[GPDMA1_CH1 as usize] == dma_rx_isr);
     assert!(crate::VECTORS.isr[GPDMA1_CH2 as usize] == dma_tx_isr);
+    assert!(crate::VECTORS.isr[I2C1_EV as usize] == event_isr);
+    assert!(crate::VECTORS.isr[I2C1_ER as usize] == event_isr);
+}
+
+#[test]
+fn test_timing() {
+    // SCL period from the low and high times, in 250ns CSI clocks, ignoring
+    // the synchronisation delays.
+    for &(speed, [presc, scll, sclh, _, _]) in &SPEEDS {
+        let clocks = (scll as u32 + sclh as u32 + 2) * (presc as u32 + 1);
+        assert!(clocks * speed <= 4000, "{speed}");
+        assert!(clocks * speed * 5 >= 4000 * 4, "{speed}");
+    }
+    assert!(timing(DEFAULT_SPEED).is_some());
+    assert_eq!(timing(1000), None);
+}
+
+#[test]
+fn test_failure() {
+    assert_eq!(failure(0), Failure::Timeout);
+    assert_eq!(failure(ISR_NACKF), Failure::Nack);
+    assert_eq!(failure(ISR_NACKF | ISR_ARLO), Failure::ArbLost);
+    assert_eq!(failure(ISR_BERR), Failure::ArbLost);
+    assert!(!counts_to_recovery(Failure::Nack));
+    assert!(counts_to_recovery(Failure::ArbLost));
+    assert!(counts_to_recovery(Failure::Timeout));
 }
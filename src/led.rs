@@ -16,14 +16,46 @@ type WTime = core::num::Wrapping<ITime>;
 use crate::cpu::interrupt::PRIO_LED as PRIORITY;
 type Priority = crate::cpu::Priority<PRIORITY>;
 
-type FiveHz = LedTimerUCell<1000, 1000>;
+/// Default on and off durations, in timer ticks (100µs).  Together these give
+/// a 5Hz blink.
+const DEFAULT_ON : ITime = 1000;
+const DEFAULT_OFF: ITime = 1000;
 
+/// Maximum on or off duration.  This keeps timer deadlines well within the
+/// wrapping range of the timer.
+pub const MAX_DURATION: ITime = 30000;
+
+/// The timer generating the breathing PWM on the blue LED, via the pin
+/// alternate function: CH1 on PA15, or CH2 on PA1 on the prototype.  TIM3 has
+/// no channel on either pin, and its counter is free-running for the timing
+/// above.
+use stm32h503::TIM2 as PWM_TIM;
+
+/// PWM period, in µs (500Hz).  The PWM timer counts at 1MHz.
+const PWM_PERIOD: u32 = 2000;
+
+/// Interval between breathing duty updates, in timer ticks (20ms).
+const BREATHE_STEP: ITime = 200;
+
+#[derive_const(Default)]
+pub struct LedTimerUCell(UCell<LedTimer>, UCell<Breathe>);
+
+/// State for the breathing (slow fade up and down) display.  When breathing,
+/// the LED shows the breathing brightness whenever the on/off state machine
+/// has it off.  The brightness is set by hardware PWM, and the duty cycle is
+/// updated every `BREATHE_STEP`, timed by the timer's compare channel 2.  Only
+/// the blue LED supports this.
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[derive_const(Default)]
-pub struct LedTimerUCell<const ON: ITime, const OFF: ITime>(
-    UCell<LedTimer<ON, OFF>>);
+struct Breathe {
+    /// Breathing period, in steps, or zero if not breathing.
+    period: u16,
+    /// Current step within the period.
+    cycle: u16,
+}
 
-pub static BLUE: FiveHz = Default::default();
-pub static RED_GREEN: FiveHz = Default::default();
+pub static BLUE: LedTimerUCell = Default::default();
+pub static RED_GREEN: LedTimerUCell = Default::default();
 
 macro_rules!dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
 
@@ -47,6 +79,26 @@ pub fn init() {
         gpiob.MODER.modify(|_,w| w.MODE4().B_0x1().MODE5().B_0x1());
     }
 
+    // The blue LED PWM.  PWM mode 1 with preload (OCxM = 6, OCxPE), active
+    // low as the LED is common anode.  The pin is switched to its alternate
+    // function only while breathing.
+    let pwm = unsafe {&*PWM_TIM::PTR};
+    rcc.APB1LENR.modify(|_,w| w.TIM2EN().set_bit());
+    if *crate::cpu::IS_PROTOTYPE.as_ref() {
+        gpioa.AFRL.modify(|_,w| w.AFSEL1().B_0x1());
+        pwm.CCMR1_Output().write(|w| w.bits(0x6800));
+        pwm.CCER.write(|w| w.bits(0x0030));     // CC2E, CC2P.
+    }
+    else {
+        gpioa.AFRH.modify(|_,w| w.AFSEL15().B_0x1());
+        pwm.CCMR1_Output().write(|w| w.bits(0x0068));
+        pwm.CCER.write(|w| w.bits(0x0003));     // CC1E, CC1P.
+    }
+    const PWM_PSC: u32 = crate::cpu::CPU_FREQ / 1000000 - 1;
+    pwm.PSC.write(|w| w.bits(PWM_PSC));
+    pwm.ARR.write(|w| w.bits(PWM_PERIOD - 1));
+    pwm.CR1.write(|w| w.bits(0x81));            // ARPE, CEN.
+
     rcc.APB1LENR.modify(|_,w| w.TIM3EN().set_bit());
     // Set ARR to 0?
     tim.DIER.write(|w| w.CC1IE().set_bit());
@@ -62,17 +114,19 @@ pub fn init() {
 }
 
 /// Set the physical LEDs.
-/// red_green indicator: true = good = green, false = red.
+/// red_green indicator: true = good = green, false = red, None = off.
 /// blue true = LED, false = LED off.
 /// Note that the LED is common anode, so that we drive the GPIO with negative
 /// logic.
-fn drive(red_green: bool, blue: bool) {
+fn drive(red_green: Option<bool>, blue: bool) {
     let gpioa = unsafe{&*stm32h503::GPIOA::PTR};
     let gpiob = unsafe{&*stm32h503::GPIOB::PTR};
 
+    let green = red_green == Some(true);
+    let red = red_green == Some(false);
     if *crate::cpu::IS_PROTOTYPE.as_ref() {
-        let green_off = !red_green;
-        let red_off = red_green && !blue;
+        let green_off = !green;
+        let red_off = !red && !blue;
         let blue_off = !blue;
         gpioa.BSRR.write(
             |w|w.BR1().set_bit().BR2().set_bit().BR3().set_bit()
@@ -81,12 +135,42 @@ fn drive(red_green: bool, blue: bool) {
     else {
         gpiob.BSRR.write(
             |w|w.BR4().set_bit().BR5().set_bit()
-                .BS4().bit(!red_green).BS5().bit(red_green));
+                .BS4().bit(!green).BS5().bit(!red));
         gpioa.BSRR.write(|w| w.BR15().set_bit().BS15().bit(!blue));
     }
+
+    // While breathing, the pin follows the PWM rather than the GPIO.
+    let breathe = BLUE.1.as_ref();
+    if breathe.period != 0 {
+        set_pwm(if blue {PWM_PERIOD} else {breathe.duty()});
+    }
 }
 
-impl<const ON: ITime, const OFF: ITime> LedTimerUCell<ON, OFF> {
+/// Set the blue LED PWM duty, in µs on per `PWM_PERIOD`.
+fn set_pwm(duty: u32) {
+    let pwm = unsafe {&*PWM_TIM::PTR};
+    if *crate::cpu::IS_PROTOTYPE.as_ref() {
+        pwm.CCR2.write(|w| w.bits(duty));
+    }
+    else {
+        pwm.CCR1.write(|w| w.bits(duty));
+    }
+}
+
+/// Switch the blue LED pin between the GPIO and the PWM.
+fn select_pwm(pwm: bool) {
+    let gpioa = unsafe {&*stm32h503::GPIOA::PTR};
+    if *crate::cpu::IS_PROTOTYPE.as_ref() {
+        gpioa.MODER.modify(
+            |_,w| if pwm {w.MODE1().B_0x2()} else {w.MODE1().B_0x1()});
+    }
+    else {
+        gpioa.MODER.modify(
+            |_,w| if pwm {w.MODE15().B_0x2()} else {w.MODE15().B_0x1()});
+    }
+}
+
+impl LedTimerUCell {
     fn isr(&self, now: ITime) {
         unsafe {self.0.as_mut()}.isr(now);
     }
@@ -99,7 +183,7 @@ impl<const ON: ITime, const OFF: ITime> LedTimerUCell<ON, OFF> {
         let now = tim.CNT.read().CNT().bits().cast_signed();
         schedule(unsafe {self.0.as_mut()}.set(state, now));
 
-        drive(RED_GREEN.0.led, BLUE.0.led);
+        drive(RED_GREEN.red_green(), BLUE.output());
     }
 
     pub fn pulse(&self, state: bool) {
@@ -110,13 +194,111 @@ impl<const ON: ITime, const OFF: ITime> LedTimerUCell<ON, OFF> {
         let now = tim.CNT.read().CNT().bits().cast_signed();
         schedule(unsafe {self.0.as_mut()}.pulse(state, now));
 
-        drive(RED_GREEN.0.led, BLUE.0.led);
+        drive(RED_GREEN.red_green(), BLUE.output());
+    }
+
+    /// Set the on and off durations, in timer ticks (100µs).  A change takes
+    /// effect from the next transition.  Returns false if a duration is out
+    /// of range.
+    pub fn set_timing(&self, on: ITime, off: ITime) -> bool {
+        if !(0 ..= MAX_DURATION).contains(&on)
+            || !(0 ..= MAX_DURATION).contains(&off) {
+            return false;
+        }
+        let _guard = Priority::default();
+        let timer = unsafe {self.0.as_mut()};
+        timer.on = on;
+        timer.off = off;
+        true
+    }
+
+    /// The on and off durations, in timer ticks.
+    pub fn timing(&self) -> (ITime, ITime) {
+        let _guard = Priority::default();
+        let timer = self.0.as_ref();
+        (timer.on, timer.off)
+    }
+
+    /// Start breathing, with a period in ms, or stop if the period is zero.
+    /// This coexists with `set` and `pulse`, which override the breathing
+    /// while the LED is on.
+    pub fn breathe(&self, period_ms: u16) {
+        let tim = unsafe {&*TIM::PTR};
+
+        let _guard = Priority::default();
+
+        let breathe = unsafe {self.1.as_mut()};
+        // The timer ticks are 100µs.
+        breathe.period = (period_ms as u32 * 10 / BREATHE_STEP as u32) as u16;
+        breathe.cycle = 0;
+        if breathe.period == 0 {
+            tim.DIER.modify(|_,w| w.CC2IE().clear_bit());
+        }
+        else {
+            let now = tim.CNT.read().CNT().bits().cast_signed();
+            tim.CCR2.write(|w| w.bits((now + BREATHE_STEP) as u16 as u32));
+            tim.SR.write(|w| w.bits(!0).CC2IF().clear_bit());
+            tim.DIER.modify(|_,w| w.CC2IE().set_bit());
+        }
+        drive(RED_GREEN.red_green(), BLUE.output());
+        select_pwm(breathe.period != 0);
+    }
+
+    /// Blink repeatedly between lit and dark, until the next `set` or
+    /// `pulse`.  For the red/green LED, `colour` gives the lit colour.
+    /// Requesting the blink already in progress does nothing.
+    pub fn blink(&self, colour: bool, on: ITime, off: ITime) {
+        let tim = unsafe {&*TIM::PTR};
+
+        let _guard = Priority::default();
+
+        let now = tim.CNT.read().CNT().bits().cast_signed();
+        let blink = Blink{on: on.max(1), off: off.max(1), colour};
+        schedule(unsafe {self.0.as_mut()}.blink(blink, now));
+
+        drive(RED_GREEN.red_green(), BLUE.output());
+    }
+
+    /// The state of an on/off LED.
+    fn output(&self) -> bool {
+        self.0.led
+    }
+
+    /// The physical state of the red/green LED: true = green, false = red,
+    /// None = off.
+    fn red_green(&self) -> Option<bool> {
+        let timer = self.0.as_ref();
+        match timer.blink {
+            Some(blink) => if timer.led {Some(blink.colour)} else {None},
+            None => Some(timer.led),
+        }
+    }
+
+    /// Advance the breathing by a step.  The new duty is applied by `drive`.
+    fn breathe_isr(&self, now: ITime) {
+        let tim = unsafe {&*TIM::PTR};
+        let breathe = unsafe {self.1.as_mut()};
+        if breathe.period == 0 {
+            return;
+        }
+        breathe.cycle = (breathe.cycle + 1) % breathe.period;
+        tim.CCR2.write(|w| w.bits((now + BREATHE_STEP) as u16 as u32));
+    }
+}
+
+impl Breathe {
+    /// The PWM on time for the current step, ramping up and down over the
+    /// period.  Squaring the ramp gives a more even perceived fade.
+    fn duty(&self) -> u32 {
+        let period = self.period as u32;
+        let ramp = 2 * self.cycle.min(self.period - self.cycle) as u32;
+        PWM_PERIOD * ramp * ramp / (period * period)
     }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 #[derive_const(Default)]
-pub struct LedTimer<const ON: ITime, const OFF: ITime> {
+pub struct LedTimer {
     /// Current desired state of the LED.  Various methods update this field,
     /// the wrapping methods in `Led` update the physical LED to match.
     led: bool,
@@ -126,6 +308,22 @@ pub struct LedTimer<const ON: ITime, const OFF: ITime> {
     target: bool,
     /// Time at which the current setting expires.
     expiry: Option<WTime>,
+    /// Duration of the on state, in timer ticks.
+    on: ITime = DEFAULT_ON,
+    /// Duration of the off state, in timer ticks.
+    off: ITime = DEFAULT_OFF,
+    /// Repeated blinking, if active.
+    blink: Option<Blink>,
+}
+
+/// Settings for repeated blinking.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Blink {
+    /// Lit and dark durations, in timer ticks.  Non-zero.
+    on: ITime,
+    off: ITime,
+    /// Lit colour for the red/green LED.
+    colour: bool,
 }
 
 fn schedule(deadline: Option<WTime>) {
@@ -151,13 +349,23 @@ fn trigger(deadline: WTime) {
     }
 }
 
-impl<const ON: ITime, const OFF: ITime> LedTimer<ON, OFF> {
+impl LedTimer {
+    #[cfg(test)]
+    fn new(on: ITime, off: ITime) -> Self {
+        LedTimer{on, off, ..Default::default()}
+    }
+
     fn isr(&mut self, now: ITime) {
         let Some(expiry) = self.expiry else {return};
         if W(now) - expiry < W(0) {
             return;
         }
-        if self.next != self.led {
+        if self.blink.is_some() {
+            let state = !self.led;
+            self.target = state;
+            self.update(state, state, now);
+        }
+        else if self.next != self.led {
             self.update(self.next, self.target, now);
         }
         else {
@@ -171,10 +379,23 @@ impl<const ON: ITime, const OFF: ITime> LedTimer<ON, OFF> {
     }
 
     fn duration(&self, state: bool) -> ITime {
-        if state {ON} else {OFF}
+        match self.blink {
+            Some(blink) => if state {blink.on} else {blink.off},
+            None => if state {self.on} else {self.off},
+        }
+    }
+
+    fn blink(&mut self, blink: Blink, now: ITime) -> Option<WTime> {
+        if self.blink == Some(blink) {
+            return None;
+        }
+        self.blink = Some(blink);
+        self.target = true;
+        self.update(true, true, now)
     }
 
     fn set(&mut self, state: bool, now: ITime) -> Option<WTime> {
+        self.blink = None;
         self.target = state;
         if let Some(_) = self.expiry {
             if self.led == self.next || self.duration(self.next) == 0 {
@@ -192,6 +413,7 @@ impl<const ON: ITime, const OFF: ITime> LedTimer<ON, OFF> {
 
     fn pulse(&mut self, state: bool, now: ITime) -> Option<WTime> {
         // Equivalent to: request(state) ; request(!state)
+        self.blink = None;
         self.target = !state;
         let next = state ^ (self.duration(state) == 0 || self.led == state);
         if self.expiry != None {
@@ -219,10 +441,17 @@ impl<const ON: ITime, const OFF: ITime> LedTimer<ON, OFF> {
 fn isr() {
     dbgln!("LED isr");
     let tim = unsafe {&*TIM::PTR};
-    tim.SR.write(|w| w.bits(0));
+    let sr = tim.SR.read();
+    tim.SR.write(|w| w.bits(!sr.bits()));
 
     let now = tim.CNT.read().CNT().bits().cast_signed();
 
+    if sr.CC2IF().bit() {
+        BLUE.breathe_isr(now);
+    }
+    // We may also be triggered by software, without CC1IF, so always process
+    // the on/off timers.
+
     let min = |a: WTime, b: Option<WTime>| -> WTime {
         match (a, b) {
             (a, Some(b)) => if a - b < W(0) {a} else {b},
@@ -232,7 +461,7 @@ fn isr() {
 
     BLUE.isr(now);
     RED_GREEN.isr(now);
-    drive(RED_GREEN.0.led, BLUE.0.led);
+    drive(RED_GREEN.red_green(), BLUE.output());
 
     // We make sure that the time is always scheduled in the future, even if
     // there is nothing to do.  Otherwise we need to deal with the ambiguity
@@ -258,7 +487,7 @@ fn check_isr() {
 
 #[test]
 fn test_fast() {
-    let mut lt = LedTimer::<10, 10>::default();
+    let mut lt = LedTimer::new(10, 10);
 
     let mut exp = lt;
 
@@ -331,7 +560,7 @@ fn test_fast() {
 
 #[test]
 fn test_zero() {
-    let mut lt = LedTimer::<10, 0>::default();
+    let mut lt = LedTimer::new(10, 0);
     let mut exp = lt;
     lt.set(false, 0);
     assert_eq!(lt, exp);
@@ -373,16 +602,17 @@ fn test_zero() {
 }
 
 #[cfg(test)]
-impl<const ON: ITime, const OFF: ITime> LedTimer<ON, OFF> {
-    fn test_pulse1() {
+impl LedTimer {
+    fn test_pulse1(on: ITime, off: ITime) {
         for led in [false, true] {
             for next in [false, true] {
                 for target in [false, true] {
                     for expiry in [None, Some(W(10))] {
-                        if expiry != None && ON == 0 && OFF == 0 {
+                        if expiry != None && on == 0 && off == 0 {
                             continue;
                         }
-                        let l = Self{led, next, target, expiry};
+                        let l = Self{
+                            led, next, target, expiry, on, off, blink: None};
                         for request in [false, true] {
                             let (mut r, mut p) = (l, l);
                             r.set(request, 5);
@@ -397,10 +627,52 @@ impl<const ON: ITime, const OFF: ITime> LedTimer<ON, OFF> {
     }
 }
 
+#[test]
+fn test_blink() {
+    let mut lt = LedTimer::new(10, 10);
+    let blink = Blink{on: 5, off: 20, colour: false};
+    assert_eq!(lt.blink(blink, 0), Some(W(5)));
+    assert!(lt.led);
+    // Repeating the same blink does not restart it.
+    assert_eq!(lt.blink(blink, 1), None);
+    lt.isr(5);
+    assert!(!lt.led);
+    assert_eq!(lt.expiry, Some(W(25)));
+    lt.isr(25);
+    assert!(lt.led);
+    assert_eq!(lt.expiry, Some(W(30)));
+    // A set cancels the blink, and uses the normal timing.
+    assert_eq!(lt.set(false, 26), None);
+    lt.isr(30);
+    assert!(!lt.led);
+    assert_eq!(lt.expiry, Some(W(40)));
+    lt.isr(40);
+    assert_eq!(lt.expiry, None);
+    assert!(!lt.led);
+}
+
+#[test]
+fn test_breathe_duty() {
+    let mut b = Breathe{period: 100, cycle: 0};
+    assert_eq!(b.duty(), 0);
+    b.cycle = 50;
+    assert_eq!(b.duty(), PWM_PERIOD);
+    b.cycle = 25;
+    assert_eq!(b.duty(), PWM_PERIOD / 4);
+    b.cycle = 75;
+    assert_eq!(b.duty(), PWM_PERIOD / 4);
+    // Odd periods never quite reach full brightness, but stay in range.
+    b.period = 7;
+    for cycle in 0 .. 7 {
+        b.cycle = cycle;
+        assert!(b.duty() < PWM_PERIOD);
+    }
+}
+
 #[test]
 fn test_pulse() {
-    LedTimer::< 0,  0>::test_pulse1();
-    LedTimer::< 0, 10>::test_pulse1();
-    LedTimer::<10,  0>::test_pulse1();
-    LedTimer::<10, 10>::test_pulse1();
+    LedTimer::test_pulse1( 0,  0);
+    LedTimer::test_pulse1( 0, 10);
+    LedTimer::test_pulse1(10,  0);
+    LedTimer::test_pulse1(10, 10);
 }
@@ -2,7 +2,8 @@
 //!
 //! Mostly, the LMK05318b clock generator is handled via the host, or start-up
 //! configuration, sending I²C commands.  This is basically just the status
-//! LED handling.
+//! LED handling, plus computing the output frequencies from the live register
+//! settings.
 
 macro_rules!dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
 
@@ -16,6 +17,73 @@ pub const LMK05318: u8 = 0xc8;
 
 pub type TIM = stm32h503::TIM6;
 
+/// Status bits, R13 in the low byte and R14 in the high byte, as read.
+/// Loss of the XO, or loss of lock of an APLL, is a fault.
+const STATUS_FAULT: u16 = 0x1d;
+/// Loss of reference, by amplitude, frequency or missing clock.
+const STATUS_LOR: u16 = 0x0700;
+/// DPLL holdover.
+const STATUS_HOLDOVER: u16 = 0x1000;
+
+/// LED blink timings, in LED timer ticks (100µs).
+const SLOW_BLINK: i16 = 10000;
+const FAST_BLINK: i16 = 1000;
+
+/// Clock generator state, as shown on the red/green LED.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ClockStatus {
+    /// Everything good: steady green.
+    Locked,
+    /// The DPLL is not locked, but has a reference: slow blinking red.
+    Unlocked,
+    /// DPLL holdover: slow blinking green.
+    Holdover,
+    /// No reference: fast blinking red.
+    NoReference,
+    /// XO or APLL failure, or we can't talk to the LMK05318b: steady red.
+    Fault,
+}
+
+struct Status {
+    clock: ClockStatus,
+    /// The LMK05318b flagged an interrupt.
+    changes: bool,
+    /// The status changed while we were reading it.
+    flicker: bool,
+}
+
+impl ClockStatus {
+    /// Classify the unmasked status bits.
+    fn from_bits(bits: u16) -> ClockStatus {
+        if bits & STATUS_FAULT != 0 {
+            ClockStatus::Fault
+        }
+        else if bits & STATUS_LOR != 0 {
+            ClockStatus::NoReference
+        }
+        else if bits & STATUS_HOLDOVER != 0 {
+            ClockStatus::Holdover
+        }
+        else if bits != 0 {
+            ClockStatus::Unlocked
+        }
+        else {
+            ClockStatus::Locked
+        }
+    }
+}
+
+/// Rate limiting timer clock frequency.
+const TIMER_FREQ: u32 = 10000;
+/// Default and limits for the status re-check rate, in Hz.
+const DEFAULT_POLL_RATE: u32 = 25;
+const MIN_POLL_RATE: u32 = 1;
+const MAX_POLL_RATE: u32 = 100;
+
+/// Reference frequency for the DPLL.  This matches the default used by the
+/// host tools.
+const REF_FREQ: u128 = 8_844_582;
+
 pub fn init() {
     let exti  = unsafe {&*stm32h503::EXTI ::PTR};
     let gpiob = unsafe {&*stm32h503::GPIOB::PTR};
@@ -32,11 +100,12 @@ pub fn init() {
     exti.IMR1.modify(|_,w| w.IM0().set_bit()); // This should be default!
 
     // Timer for rate limiting.
-    const PRESCALE: u32 = crate::cpu::CPU_FREQ / 10000 - 1; // 10kHz
+    const PRESCALE: u32 = crate::cpu::CPU_FREQ / TIMER_FREQ - 1; // 10kHz
     const {assert!(PRESCALE >= 1 && PRESCALE < 65536)};
+    const RELOAD: u16 = poll_reload(DEFAULT_POLL_RATE).unwrap();
     tim.PSC.write(|w| w.PSC().bits(PRESCALE as u16));
-    tim.ARR.write(|w| w.ARR().bits(400 - 1)); // 25Hz.
-    tim.CNT.write(|w| w.CNT().bits(400 - 1));
+    tim.ARR.write(|w| w.ARR().bits(RELOAD)); // 25Hz.
+    tim.CNT.write(|w| w.CNT().bits(RELOAD));
     tim.DIER.write(|w| w.UIE().set_bit());
 
     // This needs to run at the same priority as the command code, because both
@@ -49,6 +118,37 @@ pub fn init() {
     unsafe {nvic.stir.write(INTERRUPT as u32)};
 }
 
+/// Timer reload value for a status re-check rate, if valid.
+const fn poll_reload(rate: u32) -> Option<u16> {
+    if rate < MIN_POLL_RATE || rate > MAX_POLL_RATE {
+        None
+    }
+    else {
+        Some((TIMER_FREQ / rate - 1) as u16)
+    }
+}
+
+/// Set the rate, in Hz, at which the status is re-checked while it is
+/// unsettled.  Returns false if the rate is out of range.  Call at
+/// PRIO_STATUS.
+pub fn set_poll_rate(rate: u32) -> bool {
+    let Some(reload) = poll_reload(rate) else {return false};
+    let tim = unsafe {&*TIM::PTR};
+    tim.ARR.write(|w| w.ARR().bits(reload));
+    // If a count is in progress and already past the new reload value, cut it
+    // short rather than letting it wrap.
+    if tim.CNT.read().CNT().bits() > reload {
+        tim.CNT.write(|w| w.CNT().bits(reload));
+    }
+    true
+}
+
+/// Get the status re-check rate, in Hz.
+pub fn get_poll_rate() -> u32 {
+    let tim = unsafe {&*TIM::PTR};
+    TIMER_FREQ / (tim.ARR.read().ARR().bits() as u32 + 1)
+}
+
 pub fn update_status() {
     dbgln!("exti6_isr");
     let gpiob = unsafe {&*stm32h503::GPIOB::ptr()};
@@ -63,15 +163,24 @@ pub fn update_status() {
 
     use crate::led::RED_GREEN;
 
-    let (good, changes, flicker)
-        = lmk05318b_status().unwrap_or((false, true, false));
-    if !good || changes || flicker {
-        dbgln!("Set red");
-        RED_GREEN.set(false);
+    let Status{clock, changes, flicker} = lmk05318b_status().unwrap_or(
+        Status{clock: ClockStatus::Fault, changes: true, flicker: false});
+    dbgln!("Clock status {clock:?}");
+    match clock {
+        // Flash red on any change, before settling on green.
+        ClockStatus::Locked if changes || flicker => RED_GREEN.pulse(false),
+        ClockStatus::Locked => RED_GREEN.set(true),
+        ClockStatus::Unlocked =>
+            RED_GREEN.blink(false, SLOW_BLINK, SLOW_BLINK),
+        ClockStatus::Holdover => RED_GREEN.blink(true, SLOW_BLINK, SLOW_BLINK),
+        ClockStatus::NoReference =>
+            RED_GREEN.blink(false, FAST_BLINK, FAST_BLINK),
+        ClockStatus::Fault => RED_GREEN.set(false),
     }
-    if good {
-        dbgln!("Set green");
-        RED_GREEN.set(true);
+
+    // Alert a suspended host to a loss of lock.
+    if changes && clock != ClockStatus::Locked {
+        crate::usb::wakeup::request();
     }
 
     // Hopefully we have cleared the interrupt line, but if not, software
@@ -82,7 +191,7 @@ pub fn update_status() {
     }
 }
 
-fn lmk05318b_status() -> Result<(bool, bool, bool), ()> {
+fn lmk05318b_status() -> Result<Status, ()> {
     // FIXME - error handling.
     // Read status, 13 through 20.
     let mut data = [0u16; 4];
@@ -101,12 +210,199 @@ fn lmk05318b_status() -> Result<(bool, bool, bool), ()> {
     crate::i2c::write_read(LMK05318, &13u16.to_be(), &mut new_bits).wait()?;
     dbgln!("bits {new_bits:#06x}");
 
-    let good = new_bits & !mask == 0; // Everything good.
+    let clock = ClockStatus::from_bits(new_bits & !mask);
     // Note that we get called pre-emptively in various situations.  So note
     // whether or not the LMK05318b thought it was giving us an interrupt.
     let changes = intr & !mask != 0;          // This was a real interrupt.
     let flicker = new_bits & !mask != bits & !mask; // WTF.
-    Ok((good, changes, flicker))
+    Ok(Status{clock, changes, flicker})
+}
+
+/// Read the raw status, mask, polarity and interrupt registers, R13 through
+/// R20, without altering any device state.
+pub fn raw_status() -> Result<[u16; 4], ()> {
+    let mut data = [0u16; 4];
+    crate::i2c::write_read(LMK05318, &13u16.to_be(), &mut data).wait()?;
+    Ok(data)
+}
+
+/// Snapshot of the registers needed to compute the output frequencies.
+#[derive(Default)]
+struct FreqRegs {
+    outputs  : [u8; 18],                // R50 ..= R67
+    den_mode : [u8; 1],                 // R40
+    pll2_ctrl: [u8; 3],                 // R100 ..= R102
+    pll2_n   : [u8; 5],                 // R134 ..= R138
+    pll2_den : [u8; 3],                 // R333 ..= R335
+    rdiv     : [u8; 2],                 // R256 ..= R257
+    dpll     : [u8; 15],                // R304 ..= R318
+}
+
+/// Offsets into `FreqRegs::outputs` of the mux and divider for each channel.
+const CH_MUX: [usize; 6] = [1, 4, 7, 9, 11, 13];
+const CH_DIV: [usize; 6] = [3, 6, 8, 10, 12, 17];
+
+fn read_regs(address: u16, data: &mut [u8]) -> Result<(), ()> {
+    crate::i2c::write_read(LMK05318, &address.to_be(), data).wait()
+}
+
+/// NVM control register, with the REGCOMMIT and NVMBUSY flags, and the
+/// erase / program control.
+const R_NVMCTL: u16 = 157;
+const REGCOMMIT: u8 = 1 << 6;
+const NVMBUSY: u8 = 1 << 2;
+const NVM_ERASE_PROG: u8 = 3;
+/// MEMADR, bits 12:8 in R159 and 7:0 in R160.
+const R_MEMADR: u16 = 159;
+/// EEPROM read data, from the address in MEMADR.
+const R_NVMDAT: u16 = 161;
+/// NVM SRAM data, from the address in MEMADR.
+const R_RAMDAT: u16 = 162;
+/// NVM program unlock, and its magic value.
+const R_NVMUNLK: u16 = 164;
+const NVM_UNLOCK: u8 = 0xea;
+/// Size of the MEMADR address space.
+pub const NVM_SIZE: usize = 0x2000;
+
+fn read_reg(reg: u16) -> Result<u8, ()> {
+    let mut value = 0u8;
+    read_regs(reg, core::slice::from_mut(&mut value))?;
+    Ok(value)
+}
+
+fn write_reg(reg: u16, value: u8) -> Result<(), ()> {
+    let [hi, lo] = reg.to_be_bytes();
+    crate::i2c::write(LMK05318, &[hi, lo, value]).wait()
+}
+
+/// Read a byte of one of the NVM arrays, via MEMADR.  MEMADR is set for each
+/// byte, rather than relying on any auto-increment.
+fn nvm_byte(data_reg: u16, address: u16) -> Result<u8, ()> {
+    let [reg_hi, reg_lo] = R_MEMADR.to_be_bytes();
+    let [hi, lo] = address.to_be_bytes();
+    crate::i2c::write(LMK05318, &[reg_hi, reg_lo, hi, lo]).wait()?;
+    read_reg(data_reg)
+}
+
+/// Read the on-chip EEPROM, starting at `address`.  Fails if an EEPROM
+/// program cycle is in progress.
+pub fn nvm_read(address: u16, data: &mut [u8]) -> Result<(), ()> {
+    if read_reg(R_NVMCTL)? & NVMBUSY != 0 {
+        return Err(());
+    }
+    for (i, d) in data.iter_mut().enumerate() {
+        *d = nvm_byte(R_NVMDAT, address.wrapping_add(i as u16))?;
+    }
+    Ok(())
+}
+
+/// Wait, for up to a second, for `bits` in the NVM control register to clear.
+/// This may be called with the main loop blocked, so refresh the watchdog.
+fn nvm_wait(bits: u8) -> Result<(), ()> {
+    let start = crate::tick::cycles();
+    while read_reg(R_NVMCTL)? & bits != 0 {
+        if crate::tick::cycles().wrapping_sub(start) > crate::cpu::CPU_FREQ {
+            return Err(());
+        }
+        crate::watchdog::refresh();
+    }
+    Ok(())
+}
+
+/// Commit the live registers to the EEPROM: copy them to the NVM SRAM, and
+/// then erase and program the EEPROM from the SRAM.  The first `verify_len`
+/// bytes of the EEPROM are then read back and compared with the SRAM.
+/// Returns false on a mismatch.
+pub fn nvm_commit(verify_len: usize) -> Result<bool, ()> {
+    write_reg(R_NVMCTL, REGCOMMIT)?;
+    nvm_wait(REGCOMMIT)?;
+
+    // The erase / program must be the write immediately after the unlock.
+    write_reg(R_NVMUNLK, NVM_UNLOCK)?;
+    write_reg(R_NVMCTL, NVM_ERASE_PROG)?;
+    nvm_wait(NVMBUSY)?;
+
+    for address in 0 .. verify_len as u16 {
+        if nvm_byte(R_NVMDAT, address)? != nvm_byte(R_RAMDAT, address)? {
+            dbgln!("LMK05318b NVM mismatch at {address:#x}");
+            return Ok(false);
+        }
+        crate::watchdog::refresh();
+    }
+    Ok(true)
+}
+
+/// Big endian multi-byte register field.
+fn be(bytes: &[u8]) -> u128 {
+    bytes.iter().fold(0, |a, &b| a << 8 | b as u128)
+}
+
+/// Compute num / den as 32.32 fixed point.
+fn fixed(num: u128, den: u128) -> u128 {
+    if den == 0 {0} else {(num / den << 32) + ((num % den << 32) / den)}
+}
+
+impl FreqRegs {
+    fn read() -> Result<FreqRegs, ()> {
+        let mut r = FreqRegs::default();
+        read_regs( 50, &mut r.outputs)?;
+        read_regs( 40, &mut r.den_mode)?;
+        read_regs(100, &mut r.pll2_ctrl)?;
+        read_regs(134, &mut r.pll2_n)?;
+        read_regs(333, &mut r.pll2_den)?;
+        read_regs(256, &mut r.rdiv)?;
+        read_regs(304, &mut r.dpll)?;
+        Ok(r)
+    }
+
+    /// Frequencies in 32.32 fixed point Hz.  Channels are OUT0/1, OUT2/3,
+    /// OUT4, OUT5, OUT6, OUT7.  Powered down channels give zero.
+    fn frequencies(&self) -> [u128; 6] {
+        // BAW VCO: REF / RDIV * 2 * PRE_DIV * (FB_DIV + NUM / DEN).
+        let prediv = (self.dpll[0] & 15) as u128 + 2;
+        let fb_div = be(&self.dpll[1..5]) & 0x3fffffff;
+        let fb_num = be(&self.dpll[5..10]);
+        let fb_den = be(&self.dpll[10..15]);
+        let baw = fixed(REF_FREQ * 2 * prediv * (fb_div * fb_den + fb_num),
+                        be(&self.rdiv) * fb_den);
+
+        // PLL2: BAW / RDIV * (NDIV + NUM / DEN).
+        let ctrl = self.pll2_ctrl[0];
+        let pll2_rdiv
+            = ((ctrl >> 1 & 3) as u128 + 3) * ((ctrl >> 3) as u128 + 1);
+        let ndiv = be(&self.pll2_n[0..2]) & 0x1ff;
+        let num = be(&self.pll2_n[2..5]);
+        let den = if self.den_mode[0] & 1 == 0 {1 << 24}
+            else {be(&self.pll2_den)};
+        let pll2 = if ctrl & 1 != 0 || den == 0 {0}
+            else {baw * (ndiv * den + num) / (pll2_rdiv * den)};
+        let p1 = (self.pll2_ctrl[2]      & 7) as u128 + 1;
+        let p2 = (self.pll2_ctrl[2] >> 4 & 7) as u128 + 1;
+
+        let mut result = [0; 6];
+        for (i, f) in result.iter_mut().enumerate() {
+            if self.outputs[0] >> i & 1 != 0 {
+                continue;               // Powered down.
+            }
+            let (source, pre) = match self.outputs[CH_MUX[i]] >> 6 {
+                2 => (pll2, p1),
+                3 => (pll2, p2),
+                _ => (baw, 1),
+            };
+            let s1 = self.outputs[CH_DIV[i]] as u128 + 1;
+            let s2 = if i == 5 {be(&self.outputs[14..17]) + 1} else {1};
+            *f = source / (pre * s1 * s2);
+        }
+        result
+    }
+}
+
+/// Compute the output frequencies from the live PLL and divider settings.
+/// Each is returned as integer Hz and fractional µHz.
+pub fn output_frequencies() -> Result<[[u32; 2]; 6], ()> {
+    let freqs = FreqRegs::read()?.frequencies();
+    Ok(freqs.map(
+        |f| [(f >> 32) as u32, ((f & 0xffffffff) * 1000000 >> 32) as u32]))
 }
 
 impl crate::cpu::Config {
@@ -119,3 +415,45 @@ impl crate::cpu::Config {
 fn check_isr() {
     assert!(crate::VECTORS.isr[INTERRUPT as usize] == update_status);
 }
+
+#[test]
+fn test_poll_reload() {
+    assert_eq!(poll_reload(25), Some(399));
+    assert_eq!(poll_reload(1), Some(9999));
+    assert_eq!(poll_reload(100), Some(99));
+    assert_eq!(poll_reload(0), None);
+    assert_eq!(poll_reload(101), None);
+}
+
+#[test]
+fn test_clock_status() {
+    use ClockStatus::*;
+    assert_eq!(ClockStatus::from_bits(0), Locked);
+    assert_eq!(ClockStatus::from_bits(0x8000), Unlocked);  // LOPL_DPLL
+    assert_eq!(ClockStatus::from_bits(0x1000), Holdover);
+    assert_eq!(ClockStatus::from_bits(0x9000), Holdover);
+    assert_eq!(ClockStatus::from_bits(0x1200), NoReference);
+    assert_eq!(ClockStatus::from_bits(0x0100), NoReference);
+    assert_eq!(ClockStatus::from_bits(0x1208), Fault);     // LOL_PLL2
+    assert_eq!(ClockStatus::from_bits(0x0001), Fault);     // LOS_XO
+}
+
+#[test]
+fn test_frequencies() {
+    let mut r = FreqRegs::default();
+    r.rdiv = [0, 1];
+    r.dpll[4] = 70;                     // FB_DIV = 70, PRE_DIV = 2.
+    r.dpll[14] = 1;                     // DEN = 1.
+    r.outputs[0] = 0x3c;                // Only channels 0 and 1 powered up.
+    r.outputs[CH_MUX[0]] = 1 << 6;      // BAW.
+    r.outputs[CH_DIV[0]] = 15;
+    r.outputs[CH_MUX[1]] = 2 << 6;      // PLL2 via P1.
+    r.outputs[CH_DIV[1]] = 0;
+    r.pll2_ctrl = [5 << 3, 0, 1];       // RDIV = 3 * 6, P1 = 2.
+    r.pll2_n = [0, 36, 0, 0, 0];        // NDIV = 36.
+    let baw = 8_844_582 * 4 * 70;
+    let f = r.frequencies();
+    assert_eq!(f[0], (baw << 32) / 16);
+    assert_eq!(f[1], baw << 32);
+    assert_eq!(f[2 ..], [0; 4]);
+}
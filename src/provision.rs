@@ -9,7 +9,7 @@
 //! The format of the provisioning buffer is:
 //!
 //! u32 Magic
-//! u32 Format revision (currently 1)
+//! u32 Format revision (1 or 2)
 //! u32 Configuration generation number.
 //! u32 Byte length (including magic and CRC)
 //! The byte array containing the config.
@@ -18,6 +18,20 @@
 //! The byte content just a sequence of packets, we parse them out of the
 //! byte stream.  Zero bytes maybe inserted for padding.
 //!
+//! Format revision 2 adds conditional sections, so that one config can serve
+//! several board variants.  A section starts with a six byte header: CE A6
+//! ('Φ' in UTF-8), the condition kind, the condition value, and the u16 length
+//! of the section.  If the condition holds, the section contents are run as
+//! normal, otherwise they are skipped.  The only condition kind is 0, for the
+//! board variant (0 production, 1 prototype, as reported by command 07).
+//! Sections may be nested, up to `MAX_SECTION_DEPTH` deep.  A section must fit
+//! inside its parent, and a packet inside its section; an overrun stops
+//! provisioning, as for any other malformed data.
+//!
+//! UBX packets with a bad checksum are skipped.  After sending a UBX-CFG
+//! packet, we wait for the GPS to acknowledge it, and stop provisioning if it
+//! is rejected, as later steps may depend on it.
+//!
 //! The flash can contain multiple configs; each config is up to 2kB long and
 //! is 2kB align.  Four 8kB flash sectors (the last two in each 64kB flash bank)
 //! are assigned for this purpose.
@@ -34,19 +48,48 @@
 //!
 //! That procedure should ensure that an interrupted config update leaves us
 //! still using the previous one.
+//!
+//! The device can carry out that procedure itself: a config may be uploaded in
+//! 32 byte chunks via `upload_chunk` (the first chunk choosing the slot), and
+//! then checked with `upload_finish`.
 
 use stm_common::utils::WFE;
-use stm_common::vcell::UCell;
+use stm_common::vcell::{UCell, VCell};
 
 use crate::crc32::{self, VERIFY_MAGIC};
+use crate::flash::{self, Mem32};
 use crate::gps_uart::GpsPriority;
+use crate::ubx;
 
 const CONFIG_MAGIC: u32 = 0x4b72a6ce;
 
 const CONFIG_MAX_LENGTH: usize = 2048;
 
+/// Wait for the GPS to acknowledge each UBX-CFG command, and abort
+/// provisioning if it is rejected.
+const WAIT_FOR_ACK: bool = true;
+
+/// How long to wait for an acknowledgement.  The u-blox protocol specifies that
+/// the ACK comes within one second.
+const ACK_TIMEOUT_MS: u64 = 1000;
+
+/// UBX configuration message class, messages in which are acknowledged.
+const UBX_CLASS_CFG: u8 = 0x06;
+
 const MIN_SUPPORTED_VERSION: u32 = 1;
-const MAX_SUPPORTED_VERSION: u32 = 1;
+const MAX_SUPPORTED_VERSION: u32 = 2;
+
+/// First format revision with conditional sections.
+const CONDITIONAL_VERSION: u32 = 2;
+
+/// Command code for the LMK05318b I²C write.
+const LMK_WRITE: u8 = 0x60;
+
+/// Condition kind for the board variant.
+const COND_VARIANT: u8 = 0;
+
+/// Maximum nesting of conditional sections.
+const MAX_SECTION_DEPTH: usize = 4;
 
 macro_rules!dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
 
@@ -64,15 +107,46 @@ struct ConfigBlock {
 }
 const _: () = assert!(size_of::<ConfigBlock>() == CONFIG_MAX_LENGTH);
 
+/// Slot index of the config applied at start-up, or `NO_CONFIG`.
+static APPLIED: VCell<u8> = VCell::new(NO_CONFIG);
+
+/// Value of `APPLIED` when no config was applied.
+const NO_CONFIG: u8 = 0xff;
+
 pub fn provision() {
-    let Some(c) = best_config() else {
+    let Some(i) = best_config_index() else {
         dbgln!("No config found");
         return;
     };
-    let mut data = &c.data[.. c.length as usize - 20];
+    APPLIED.write(i);
     use crate::led::BLUE;
     BLUE.set(true);
+    run_config(config_by_index(i), false);
+    BLUE.set(false);
+}
+
+/// Is there a valid config to apply?
+pub fn config_available() -> bool {best_config().is_some()}
+
+/// Re-run the LMK05318b I²C writes (command 60) from the current best config,
+/// skipping everything else.  Used after power cycling the clock generator.
+pub fn reapply_lmk() {
+    if let Some(c) = best_config() {
+        run_config(c, true);
+    }
+}
 
+/// Run the contents of a config.  If `lmk_only`, then only the LMK05318b I²C
+/// writes are run.
+fn run_config(c: &ConfigBlock, lmk_only: bool) {
+    run_section(c, &c.data[.. c.length as usize - 20], lmk_only, 0);
+}
+
+/// Run a config, or a conditional section within one, at the given nesting
+/// depth.  Packets must lie entirely within `data`.  Returns false if
+/// provisioning should stop.
+fn run_section(c: &ConfigBlock, mut data: &[u8], lmk_only: bool, depth: usize)
+               -> bool {
     while data.len() > 0 {
         // Check for a valid command packet.
         dbgln!("Next packet @ {:#?}", data.as_ptr());
@@ -81,10 +155,33 @@ pub fn provision() {
             dbgln!("Freak packet len {} total len {length}", data[3]);
             if data.len() < length {
                 dbgln!("Config command doesn't fit.");
-                break;
+                return false;
             }
             // Ok, it looks like a packet try and run it...
-            run_command_packet(&data[0..length]);
+            if !lmk_only || data[2] == LMK_WRITE {
+                run_command_packet(&data[0..length]);
+            }
+            data = &data[length ..];
+            continue;
+        }
+        // Check for a conditional section header.
+        if c.version >= CONDITIONAL_VERSION
+            && data.len() >= 6 && data[0] == 0xce && data[1] == 0xa6 {
+            let length = data[4] as usize + data[5] as usize * 256 + 6;
+            dbgln!("Conditional section total len {length}");
+            if data.len() < length {
+                dbgln!("Conditional section doesn't fit.");
+                return false;
+            }
+            if condition(data[2], data[3]) {
+                if depth >= MAX_SECTION_DEPTH {
+                    dbgln!("Conditional sections nested too deep.");
+                    return false;
+                }
+                if !run_section(c, &data[6 .. length], lmk_only, depth + 1) {
+                    return false;
+                }
+            }
             data = &data[length ..];
             continue;
         }
@@ -95,16 +192,27 @@ pub fn provision() {
             dbgln!("UBX packet len {lfield} total {length}");
             if data.len() < length {
                 dbgln!("Config u-blox doesn't fit @ {:#?}.", data.as_ptr());
-                break;
+                return false;
+            }
+            if !lmk_only && !run_ublox_command(&data[.. length]) {
+                crate::warnln!("U-Blox command failed, abort provisioning.");
+                return false;
             }
-            run_ublox_command(data.as_ptr(), length);
             data = &data[length ..];
             continue;
         }
         dbgln!("Unknown data in config @ {:#?}.", data.as_ptr());
-        break;
+        return false;
+    }
+    true
+}
+
+/// Evaluate a conditional section condition.  Unknown kinds are false.
+fn condition(kind: u8, value: u8) -> bool {
+    match kind {
+        COND_VARIANT => value == *crate::cpu::IS_PROTOTYPE.as_ref() as u8,
+        _ => false,
     }
-    BLUE.set(false);
 }
 
 static COM_BUF: UCell<crate::command::MessageBuf> = Default::default();
@@ -117,29 +225,77 @@ fn run_command_packet(data: &[u8]) {
         return;
     }
 
-    // Copy, because the command handling needs a 32-bit aligned 64-byte buffer
-    // 'cos its stupid.
     let com_buf = unsafe{COM_BUF.as_mut()};
-    unsafe {
-        core::ptr::copy_nonoverlapping(
-            data.as_ptr(), com_buf as *mut _ as *mut u8, data.len());
-    }
-
-    crate::command::command_handler(com_buf, data.len(), |_| ());
+    crate::command::copy_and_handle(com_buf, data.as_ptr(), data.len(), |_| ());
 }
 
-fn run_ublox_command(data: *const u8, length: usize) {
-    dbgln!("Run U-Blox packet @{data:#?} {length} bytes");
+/// Send a UBX command to the GPS.  CFG commands are waited on for an
+/// acknowledgement, if enabled.  Returns false only if the GPS rejects the
+/// command, or the UART fails to send it in time; bad packets are skipped and a
+/// missing acknowledgement is ignored.
+fn run_ublox_command(data: &[u8]) -> bool {
+    use crate::gps_uart::{
+        TX_MAX_LEN, dma_tx, tx_time, wait_for_tx_idle_timeout};
+    dbgln!("Run U-Blox packet @{:#?} {} bytes", data.as_ptr(), data.len());
+    if !ubx::frame_ok(data) {
+        crate::warnln!("U-Blox packet checksum bad, skip it.");
+        return true;
+    }
+    let wait_ack = WAIT_FOR_ACK && data[2] == UBX_CLASS_CFG;
+    if wait_ack {
+        crate::gps_uart::rx_snoop_start();
+    }
+    // We wait for each packet to go, so at most a USB serial packet is ahead
+    // of us.
+    let start = crate::tick::cycles();
     loop {
         let prio = GpsPriority::default();
-        let ok = crate::gps_uart::dma_tx(data, length);
+        let ok = dma_tx(data.as_ptr(), data.len());
         drop(prio);
         if ok {
             break;
         }
+        if crate::tick::cycles().wrapping_sub(start) > tx_time(TX_MAX_LEN) {
+            crate::warnln!("GPS UART TX stuck.");
+            return false;
+        }
+        WFE();
+    }
+    if !wait_for_tx_idle_timeout(tx_time(data.len())) {
+        crate::warnln!("GPS UART TX timed out.");
+        return false;
+    }
+    if !wait_ack {
+        return true;
+    }
+    match wait_for_ack(data[2], data[3]) {
+        Some(ok) => ok,
+        None => {
+            crate::infoln!("No U-Blox acknowledgement, carry on.");
+            true
+        }
+    }
+}
+
+/// Wait for the GPS to ACK or NAK the message with the given class and id.
+/// Returns None on timeout.
+fn wait_for_ack(class: u8, id: u8) -> Option<bool> {
+    let mut parser = ubx::AckParser::default();
+    let deadline = crate::tick::uptime_ms() + ACK_TIMEOUT_MS;
+    while crate::tick::uptime_ms() < deadline {
+        let mut result = None;
+        crate::gps_uart::rx_snoop(|b| {
+            if let Some(ack) = parser.push(b)
+                && ack.class == class && ack.id == id {
+                result = Some(ack.ok);
+            }
+        });
+        if result.is_some() {
+            return result;
+        }
         WFE();
     }
-    crate::gps_uart::wait_for_tx_idle();
+    None
 }
 
 fn config_by_index(i: u8) -> &'static ConfigBlock {
@@ -151,30 +307,139 @@ fn config_by_index(i: u8) -> &'static ConfigBlock {
     unsafe {&* (address as *const ConfigBlock)}
 }
 
+/// Address of the config slot being uploaded, or zero if none.
+static UPLOAD: VCell<usize> = VCell::new(0);
+
 fn best_config() -> Option<&'static ConfigBlock> {
+    best_config_index().map(config_by_index)
+}
+
+fn best_config_index() -> Option<u8> {
     let mut indexes = [0u8, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
 
     indexes.sort_unstable_by_key(config_sort_key);
 
-    for c in indexes.iter().rev().map(|&i| config_by_index(i)) {
+    for &i in indexes.iter().rev() {
+        let c = config_by_index(i);
         if c.magic != CONFIG_MAGIC {
             dbgln!("Magic wrong @ {:#?}", c as *const ConfigBlock);
             break;
         }
-        let length = c.length as usize;
-        if length < 20 || length >= CONFIG_MAX_LENGTH {
-            dbgln!("Length {length} too big @ {:#?}", c as *const ConfigBlock);
-            continue;
-        }
-        if crc32::compute(c as *const ConfigBlock as *const u8, length)
-            == VERIFY_MAGIC {
-            dbgln!("CRC good @ {:#?}", c as *const ConfigBlock);
-            return Some(c);
+        if config_ok(c) {
+            return Some(i);
         }
     }
     None
 }
 
+/// Check the length and CRC of a config.
+fn config_ok(c: &ConfigBlock) -> bool {
+    let length = c.length as usize;
+    if length < 20 || length >= CONFIG_MAX_LENGTH {
+        dbgln!("Length {length} too big @ {:#?}", c as *const ConfigBlock);
+        return false;
+    }
+    let ok = crc32::compute(c as *const ConfigBlock as *const u8, length)
+        == VERIFY_MAGIC;
+    if ok {
+        dbgln!("CRC good @ {:#?}", c as *const ConfigBlock);
+    }
+    ok
+}
+
+fn config_empty(c: &ConfigBlock) -> bool {
+    let words = unsafe {
+        &*(c as *const ConfigBlock as *const [u32; CONFIG_MAX_LENGTH / 4])};
+    words.iter().all(|&w| w == !0)
+}
+
+/// Choose the slot for a new config: a blank slot in the second bank,
+/// preferring the sector holding the current config.  If there is none, erase
+/// the other sector.
+fn upload_slot() -> Option<usize> {
+    let current = best_config().map(|c| c as *const ConfigBlock as usize);
+    let scan = if current.is_some_and(|a| a >= 0x0801e000) {
+        [12, 13, 14, 15, 8, 9, 10, 11]
+    }
+    else {
+        [8, 9, 10, 11, 12, 13, 14, 15]
+    };
+    for &i in &scan {
+        let c = config_by_index(i);
+        if config_empty(c) {
+            return Some(c as *const ConfigBlock as usize);
+        }
+    }
+    let address = config_by_index(scan[4]) as *const ConfigBlock as usize;
+    dbgln!("Erase config sector @ {address:#010x}");
+    flash::erase(address).ok()?;
+    Some(address)
+}
+
+/// Program a 32 byte chunk of an uploaded config.  Offset zero starts a new
+/// upload, choosing the slot.
+pub fn upload_chunk(offset: usize, data: &Mem32) -> flash::Result {
+    if offset % 32 != 0 || offset >= CONFIG_MAX_LENGTH {
+        return Err(flash::Failure::Range);
+    }
+    if offset == 0 {
+        UPLOAD.write(upload_slot().unwrap_or(0));
+    }
+    let base = UPLOAD.read();
+    if base == 0 {
+        return Err(flash::Failure::Busy);
+    }
+    unsafe {flash::program32(base + offset, data)}
+}
+
+/// Complete an upload, verifying the config.  Returns the slot address.
+pub fn upload_finish() -> Result<usize, ()> {
+    let base = UPLOAD.read();
+    UPLOAD.write(0);
+    if base == 0 {
+        return Err(());
+    }
+    let c = unsafe {&*(base as *const ConfigBlock)};
+    if c.magic == CONFIG_MAGIC && config_ok(c) {Ok(base)} else {Err(())}
+}
+
+/// Number of config slots.
+pub const NUM_SLOTS: u8 = 16;
+
+pub mod slot_flags {
+    pub const MAGIC : u32 = 1;
+    pub const VALID : u32 = 2;
+    pub const ACTIVE: u32 = 4;
+}
+
+/// Summarise a config slot: [index | flags << 8, version, generation].
+pub fn slot_info(i: u8) -> [u32; 3] {
+    let c = config_by_index(i);
+    let active = best_config().is_some_and(|b| core::ptr::eq(b, c));
+    let mut flags = 0;
+    if c.magic == CONFIG_MAGIC {
+        flags |= slot_flags::MAGIC;
+        if config_ok(c) {
+            flags |= slot_flags::VALID;
+        }
+    }
+    if active {
+        flags |= slot_flags::ACTIVE;
+    }
+    [i as u32 | flags << 8, c.version, c.generation]
+}
+
+/// The config applied at start-up: [slot index, version, generation, length].
+/// If no config was applied, the slot index is !0 and the rest zero.
+pub fn applied_info() -> [u32; 4] {
+    let i = APPLIED.read();
+    if i == NO_CONFIG {
+        return [!0, 0, 0, 0];
+    }
+    let c = config_by_index(i);
+    [i as u32, c.version, c.generation, c.length]
+}
+
 /// Key for sorting configs.  Configs with "greater" keys are better.
 fn config_sort_key(i: &u8) -> (bool, u32, u8) {
     let c = config_by_index(*i);
@@ -0,0 +1,155 @@
+//! Periodic tick, from the SysTick timer.
+//!
+//! The SysTick runs from the HCLK/8 clock (20MHz), at a configurable rate,
+//! 1kHz by default.  Each tick advances the uptime counter and runs any
+//! registered periodic tasks, at application priority.
+//!
+//! The uptime is kept in SysTick clock cycles rather than ticks, so that it is
+//! unaffected by changes of tick rate, and reading it accounts for the partial
+//! tick since the last reload.
+//!
+//! We also start the DWT CPU cycle counter, for short interval timing that
+//! does not depend on the SysTick interrupt being serviced.
+
+use stm_common::vcell::VCell;
+
+use crate::cpu::{CPU_FREQ, Priority};
+use crate::cpu::interrupt::PRIO_APP;
+
+/// The SysTick clock frequency.
+const SYSTICK_FREQ: u32 = CPU_FREQ / 8;
+
+/// Default tick rate, in Hz.
+const DEFAULT_RATE: u32 = 1000;
+
+/// Maximum tick rate, to bound the interrupt overhead.
+const MAX_RATE: u32 = 10000;
+
+/// Maximum number of periodic tasks.
+const MAX_TASKS: usize = 4;
+
+/// Current tick rate.
+static RATE: VCell<u32> = VCell::new(DEFAULT_RATE);
+
+/// Uptime, in SysTick clock cycles.  Only accessed at PRIO_APP.
+static UPTIME: VCell<u64> = VCell::new(0);
+
+static TASKS: [VCell<Option<fn()>>; MAX_TASKS]
+    = [const {VCell::new(None)}; MAX_TASKS];
+
+pub fn init() {
+    let scb = unsafe {&*cortex_m::peripheral::SCB::PTR};
+    // SHPR3 byte for SysTick.
+    unsafe {scb.shpr[11].write(PRIO_APP)};
+    set_rate(DEFAULT_RATE);
+
+    // Enable the DWT cycle counter.
+    let dcb = unsafe {&*cortex_m::peripheral::DCB::PTR};
+    let dwt = unsafe {&*cortex_m::peripheral::DWT::PTR};
+    unsafe {dcb.demcr.modify(|w| w | 1 << 24)}; // TRCENA
+    unsafe {dwt.ctrl.modify(|w| w | 1)};        // CYCCNTENA
+}
+
+/// The DWT CPU cycle counter.  This wraps every ≈27 seconds.
+pub fn cycles() -> u32 {
+    let dwt = unsafe {&*cortex_m::peripheral::DWT::PTR};
+    dwt.cyccnt.read()
+}
+
+/// Reload value for a tick rate, if valid.
+fn reload(rate: u32) -> Option<u32> {
+    if rate == 0 || rate > MAX_RATE {
+        return None;
+    }
+    let reload = SYSTICK_FREQ / rate - 1;
+    if reload <= 0xffffff {Some(reload)} else {None}
+}
+
+/// Set the tick rate, in Hz.  Returns false if the rate is not valid.
+pub fn set_rate(rate: u32) -> bool {
+    let Some(reload) = reload(rate) else {return false};
+    let scb  = unsafe {&*cortex_m::peripheral::SCB ::PTR};
+    let syst = unsafe {&*cortex_m::peripheral::SYST::PTR};
+    let _prio = Priority::<PRIO_APP>::default();
+    // Account for the time since the last tick, and then discard any pending
+    // tick, so that it is not counted twice.
+    UPTIME.write(UPTIME.read() + elapsed());
+    unsafe {
+        scb.icsr.write(1 << 25);        // PENDSTCLR
+        syst.csr.write(0);
+        syst.rvr.write(reload);
+        syst.cvr.write(0);
+        // Enable, with interrupt, using the external (HCLK/8) clock.
+        syst.csr.write(3);
+    }
+    RATE.write(rate);
+    true
+}
+
+pub fn get_rate() -> u32 {RATE.read()}
+
+/// SysTick clock cycles elapsed and not yet added to UPTIME.  Call at PRIO_APP,
+/// so that the tick interrupt does not run underneath us.
+fn elapsed() -> u64 {
+    let scb  = unsafe {&*cortex_m::peripheral::SCB ::PTR};
+    let syst = unsafe {&*cortex_m::peripheral::SYST::PTR};
+    if syst.csr.read() & 1 == 0 {
+        return 0;                       // Not running.
+    }
+    let reload = syst.rvr.read() as u64;
+    let current = syst.cvr.read() as u64;
+    if scb.icsr.read() & 1 << 26 == 0 { // PENDSTSET
+        reload - current
+    }
+    else {
+        // A tick is pending; take a fresh reading of the post-wrap count.
+        2 * reload + 1 - syst.cvr.read() as u64
+    }
+}
+
+/// Milliseconds since boot.
+pub fn uptime_ms() -> u64 {
+    let _prio = Priority::<PRIO_APP>::default();
+    (UPTIME.read() + elapsed()) / (SYSTICK_FREQ / 1000) as u64
+}
+
+/// Register a task to run on every tick.  Returns false if there is no room.
+pub fn register(task: fn()) -> bool {
+    let _prio = Priority::<PRIO_APP>::default();
+    let Some(slot) = TASKS.iter().find(|t| t.read().is_none())
+        else {return false};
+    slot.write(Some(task));
+    true
+}
+
+fn tick_isr() {
+    let syst = unsafe {&*cortex_m::peripheral::SYST::PTR};
+    UPTIME.write(UPTIME.read() + syst.rvr.read() as u64 + 1);
+    for task in &TASKS {
+        if let Some(task) = task.read() {
+            task();
+        }
+    }
+}
+
+impl crate::cpu::Config {
+    pub const fn tick(&mut self) -> &mut Self {
+        self.vectors.systick = tick_isr;
+        self
+    }
+}
+
+#[test]
+fn test_reload() {
+    assert_eq!(reload(1000), Some(19999));
+    assert_eq!(reload(2), Some(9999999));
+    assert_eq!(reload(1), None);
+    assert_eq!(reload(0), None);
+    assert_eq!(reload(MAX_RATE), Some(1999));
+    assert_eq!(reload(MAX_RATE + 1), None);
+}
+
+#[test]
+fn check_isr() {
+    assert!(crate::VECTORS.systick == tick_isr);
+}
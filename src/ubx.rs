@@ -0,0 +1,258 @@
+//! U-Blox UBX protocol helpers.
+//!
+//! A UBX frame is two sync bytes (b5 62), class, id, little-endian u16 payload
+//! length, the payload, and a two byte Fletcher checksum over everything after
+//! the sync bytes.
+
+/// Message class for UBX-ACK.
+const CLASS_ACK: u8 = 0x05;
+/// Message ids within UBX-ACK.
+const ID_NAK: u8 = 0x00;
+const ID_ACK: u8 = 0x01;
+
+/// UBX Fletcher checksum, over the class, id, length and payload.
+pub fn checksum(body: &[u8]) -> [u8; 2] {
+    let mut a = 0u8;
+    let mut b = 0u8;
+    for &x in body {
+        a = a.wrapping_add(x);
+        b = b.wrapping_add(a);
+    }
+    [a, b]
+}
+
+/// Check the checksum on a complete UBX frame, including the sync bytes.
+pub fn frame_ok(frame: &[u8]) -> bool {
+    let len = frame.len();
+    len >= 8 && frame[0] == 0xb5 && frame[1] == 0x62
+        && frame[4] as usize + (frame[5] as usize) * 256 + 8 == len
+        && checksum(&frame[2 .. len - 2]) == frame[len - 2 ..]
+}
+
+/// A UBX-ACK-ACK or UBX-ACK-NAK, with the class and id of the acknowledged
+/// message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ack {
+    pub ok: bool,
+    pub class: u8,
+    pub id: u8,
+}
+
+/// Byte at a time UBX frame parser, picking out acknowledgements from the
+/// receive stream.  Anything else, including NMEA, is skipped.
+#[derive(Default)]
+pub struct AckParser {
+    /// Bytes of the current frame seen so far, including the sync bytes.
+    count: usize,
+    class: u8,
+    id: u8,
+    len: usize,
+    ck: [u8; 2],
+    payload: [u8; 2],
+}
+
+impl AckParser {
+    /// Feed in the next received byte.  Returns the acknowledgement when a
+    /// complete and valid UBX-ACK frame has been received.
+    pub fn push(&mut self, b: u8) -> Option<Ack> {
+        match self.count {
+            0 => {
+                if b == 0xb5 {
+                    self.count = 1;
+                }
+                return None;
+            }
+            1 => {
+                self.count = match b {0x62 => 2, 0xb5 => 1, _ => 0};
+                self.ck = [0; 2];
+                return None;
+            }
+            _ => (),
+        }
+        let i = self.count - 2;
+        self.count += 1;
+        if i < 4 + self.len {
+            self.ck[0] = self.ck[0].wrapping_add(b);
+            self.ck[1] = self.ck[1].wrapping_add(self.ck[0]);
+        }
+        match i {
+            0 => self.class = b,
+            1 => self.id = b,
+            2 => self.len = b as usize,
+            3 => self.len += (b as usize) << 8,
+            _ if i < 4 + self.len => {
+                if i - 4 < 2 {
+                    self.payload[i - 4] = b;
+                }
+            }
+            _ if i == 4 + self.len => {
+                if b != self.ck[0] {
+                    self.count = 0;
+                }
+            }
+            _ => {
+                self.count = 0;
+                if b == self.ck[1] && self.class == CLASS_ACK && self.len == 2
+                    && (self.id == ID_ACK || self.id == ID_NAK) {
+                    return Some(Ack{ok: self.id == ID_ACK,
+                                    class: self.payload[0],
+                                    id: self.payload[1]});
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Byte at a time UBX frame parser, capturing one complete and valid frame
+/// with a given class and id, from the sync bytes through the checksum.
+/// Anything else is skipped.
+pub struct FrameCapture<const N: usize> {
+    want: [u8; 2],
+    /// Bytes of the current frame seen so far, including the sync bytes.
+    count: usize,
+    len: usize,
+    ck: [u8; 2],
+    /// Set if a matching frame was seen that was too long for the buffer.
+    pub overflow: bool,
+    /// The captured frame.
+    pub buf: [u8; N],
+}
+
+impl<const N: usize> FrameCapture<N> {
+    pub fn new(class: u8, id: u8) -> Self {
+        let mut buf = [0; N];
+        buf[0] = 0xb5;
+        buf[1] = 0x62;
+        FrameCapture{want: [class, id], count: 0, len: 0, ck: [0; 2],
+                     overflow: false, buf}
+    }
+
+    /// Feed in the next received byte.  Returns the frame length when a
+    /// complete and valid matching frame is in `buf`.
+    pub fn push(&mut self, b: u8) -> Option<usize> {
+        match self.count {
+            0 => {
+                if b == 0xb5 {
+                    self.count = 1;
+                }
+                return None;
+            }
+            1 => {
+                self.count = match b {0x62 => 2, 0xb5 => 1, _ => 0};
+                self.ck = [0; 2];
+                return None;
+            }
+            _ => (),
+        }
+        let i = self.count - 2;
+        if self.count < N {
+            self.buf[self.count] = b;
+        }
+        self.count += 1;
+        if i < 4 + self.len {
+            self.ck[0] = self.ck[0].wrapping_add(b);
+            self.ck[1] = self.ck[1].wrapping_add(self.ck[0]);
+        }
+        match i {
+            0 | 1 => if b != self.want[i] {self.count = 0},
+            2 => self.len = b as usize,
+            3 => self.len += (b as usize) << 8,
+            _ if i < 4 + self.len => (),
+            _ if i == 4 + self.len => {
+                if b != self.ck[0] {
+                    self.count = 0;
+                }
+            }
+            _ => {
+                self.count = 0;
+                if b == self.ck[1] {
+                    if self.len + 8 <= N {
+                        return Some(self.len + 8);
+                    }
+                    self.overflow = true;
+                }
+            }
+        }
+        None
+    }
+}
+
+#[test]
+fn test_checksum() {
+    assert_eq!(checksum(&[0x06, 0x08, 0x00, 0x00]), [0x0e, 0x30]);
+}
+
+#[test]
+fn test_frame_ok() {
+    // UBX-CFG-RATE, 1000ms measurement rate, 1 cycle, GPS time.
+    let mut frame = [0xb5, 0x62, 0x06, 0x08, 0x06, 0x00,
+                     0xe8, 0x03, 0x01, 0x00, 0x01, 0x00, 0x01, 0x39];
+    assert!(frame_ok(&frame));
+    // UBX-CFG-RATE poll.
+    assert!(frame_ok(&[0xb5, 0x62, 0x06, 0x08, 0x00, 0x00, 0x0e, 0x30]));
+    frame[7] ^= 1;
+    assert!(!frame_ok(&frame));
+    assert!(!frame_ok(&frame[.. 6]));
+}
+
+#[test]
+fn test_ack_parser() {
+    let mut p = AckParser::default();
+    let mut acks = Vec::new();
+    let mut feed = |p: &mut AckParser, data: &[u8]| {
+        for &b in data {
+            if let Some(a) = p.push(b) {
+                acks.push(a);
+            }
+        }
+    };
+    // Some NMEA, then an ACK-ACK for CFG-RATE.
+    feed(&mut p, b"$GNGGA,,,,,,0,00,99.99,,,,,,*56\r\n");
+    feed(&mut p, &[0xb5, 0x62, 0x05, 0x01, 0x02, 0x00, 0x06, 0x08,
+                   0x16, 0x3f]);
+    // A NAK for CFG-PRT, preceded by a stray sync byte.
+    feed(&mut p, &[0xb5, 0xb5, 0x62, 0x05, 0x00, 0x02, 0x00, 0x06, 0x00,
+                   0x0d, 0x32]);
+    // A corrupted ACK is ignored.
+    feed(&mut p, &[0xb5, 0x62, 0x05, 0x01, 0x02, 0x00, 0x06, 0x09,
+                   0x16, 0x3f]);
+    // A non-ACK message is skipped.
+    feed(&mut p, &[0xb5, 0x62, 0x06, 0x08, 0x06, 0x00,
+                   0xe8, 0x03, 0x01, 0x00, 0x01, 0x00, 0x01, 0x39]);
+    assert_eq!(acks, [Ack{ok: true, class: 6, id: 8},
+                      Ack{ok: false, class: 6, id: 0}]);
+}
+
+#[test]
+fn test_frame_capture() {
+    let mut p = FrameCapture::<16>::new(0x06, 0x08);
+    let mut frames = Vec::new();
+    let mut feed = |p: &mut FrameCapture<16>, data: &[u8]| {
+        for &b in data {
+            if let Some(len) = p.push(b) {
+                frames.push(p.buf[.. len].to_vec());
+            }
+        }
+    };
+    let rate = [0xb5, 0x62, 0x06, 0x08, 0x06, 0x00,
+                0xe8, 0x03, 0x01, 0x00, 0x01, 0x00, 0x01, 0x39];
+    // An ACK for the same class and id is skipped, as is NMEA.
+    feed(&mut p, &[0xb5, 0x62, 0x05, 0x01, 0x02, 0x00, 0x06, 0x08,
+                   0x16, 0x3f]);
+    feed(&mut p, b"$GNGGA,,,,,,0,00,99.99,,,,,,*56\r\n");
+    // A corrupted frame is ignored.
+    let mut bad = rate;
+    bad[7] ^= 1;
+    feed(&mut p, &bad);
+    feed(&mut p, &rate);
+    assert_eq!(frames, [rate.to_vec()]);
+    assert!(!p.overflow);
+
+    // Too long for the buffer.
+    let mut p = FrameCapture::<12>::new(0x06, 0x08);
+    for &b in &rate {
+        assert_eq!(p.push(b), None);
+    }
+    assert!(p.overflow);
+}
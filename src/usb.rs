@@ -9,32 +9,47 @@ use crate::cpu::interrupt;
 
 use stm_common::interrupt::enable_priority;
 use stm_common::usb;
-use stm_common::vcell::UCell;
+use stm_common::vcell::{UCell, VCell};
 
-use usb::hardware::{BD, USB_SRAM_BASE, chep_bd, chep_ref};
+use usb::hardware::{BD, CTRL_RX_BUF, USB_SRAM_BASE, chep_bd, chep_ref};
 use usb::types::{SetupHeader, SetupResult};
 
 use stm32h503::Interrupt::USB_FS as INTERRUPT;
 
 pub mod command;
+mod debug_serial;
 mod descriptors;
+pub mod halt;
+mod msos;
 pub mod serial;
+pub mod setup_log;
+pub mod sof;
+pub mod wakeup;
+mod zlp;
+
+pub use descriptors::{IDX_SERIAL_NUMBER, UsbMode, string_raw, string_utf8};
 
 #[derive_const(Default)]
 struct FreakUSB;
 
+/// Not a real end-point, just handlers for setup requests that don't belong
+/// elsewhere: the DFU trigger, endpoint halts, remote wakeup, GET_STATUS,
+/// descriptors not handled by the USB core, and the debug serial.
 #[derive_const(Default)]
-struct TriggerDFU;
+struct ControlSetup;
 
 static USB_STATE: UCell<usb::USB_State<FreakUSB>> = Default::default();
 
+/// The USB functions presented to the host.
+static USB_MODE: VCell<UsbMode> = VCell::new(UsbMode::Full);
+
 impl usb::USBMeta for FreakUSB {
     fn get_device_descriptor(&mut self) -> SetupResult {
         SetupResult::tx_data(&descriptors::DEVICE_DESC)
     }
     fn get_config_descriptor(&mut self, _: &SetupHeader) -> SetupResult {
-        // Always return CONFIG0 ....
-        SetupResult::tx_data(&descriptors::CONFIG0_DESC)
+        // We only have one configuration, but its content depends on the mode.
+        USB_MODE.read().config_descriptor()
     }
     fn get_string_descriptor(&mut self, idx: u8) -> SetupResult {
         descriptors::get_string(idx)
@@ -43,36 +58,202 @@ impl usb::USBMeta for FreakUSB {
     type EP1 = serial::FreakUSBSerial;
     type EP2 = serial::FreakUSBSerialIntr;
     type EP3 = command::CommandUSB;
-    type EP7 = TriggerDFU; // Not a real end-point, just a setup handler.
+    type EP7 = ControlSetup; // Not a real end-point, just setup handlers.
 
     const CPU_FREQ: u32 = crate::cpu::CPU_FREQ;
 }
 
-impl usb::EndpointPair for TriggerDFU {
+/// Descriptors that the USB core doesn't handle: BOS, and the MS OS 2.0
+/// descriptor set.  The device qualifier is refused explicitly.
+fn extra_descriptor(setup: &SetupHeader) -> Option<SetupResult> {
+    match (setup.request_type, setup.request, setup.value_hi) {
+        // USB 2.0 §9.6.2: a full-speed only device must respond to a request
+        // for the device qualifier with a request error.  Returning one would
+        // tell the host that we are capable of high speed.  Hosts probing it
+        // may log the stall, but that is expected.
+        (0x80, 0x06, descriptors::TYPE_DEVICE_QUALIFIER) =>
+            Some(SetupResult::error()),
+        (0x80, 0x06, msos::TYPE_BOS) => match USB_MODE.read() {
+            UsbMode::SerialOnly =>
+                Some(SetupResult::tx_data(&msos::BOS_EMPTY_DESC)),
+            _ => Some(SetupResult::tx_data(&msos::BOS_DESC)),
+        },
+        (0xc0, msos::MS_VENDOR_CODE, _)
+            if setup.index == msos::MS_OS_20_DESCRIPTOR_INDEX =>
+            match USB_MODE.read() {
+                UsbMode::Full => Some(SetupResult::tx_data(&msos::FULL_SET)),
+                UsbMode::CommandOnly =>
+                    Some(SetupResult::tx_data(&msos::COMMAND_SET)),
+                // No command interface, so nothing to bind.
+                UsbMode::SerialOnly => None,
+            },
+        _ => None,
+    }
+}
+
+/// Status bits for a device GET_STATUS: bit 0 self-powered, bit 1 remote
+/// wakeup enabled.
+const fn device_status(attributes: u8, wakeup: bool) -> u8 {
+    let self_powered = attributes & 0x40 != 0;
+    let wakeup = wakeup && attributes & 0x20 != 0;
+    self_powered as u8 | (wakeup as u8) << 1
+}
+
+/// GET_STATUS for the device, interfaces, and endpoint 0, reflecting the
+/// configuration attributes and the remote wakeup state.  The other endpoints
+/// are handled by `halt`.
+fn get_status(setup: &SetupHeader) -> Option<SetupResult> {
+    static STATUS: [[u8; 2]; 4] = [[0, 0], [1, 0], [2, 0], [3, 0]];
+    if setup.request != 0x00 || setup.value_lo != 0 || setup.value_hi != 0 {
+        return None;
+    }
+    match (setup.request_type, setup.index) {
+        (0x80, 0) => Some(SetupResult::tx_data(&STATUS[device_status(
+            descriptors::CONFIG_ATTRIBUTES, wakeup::enabled()) as usize])),
+        (0x81, i) if i < USB_MODE.read().num_interfaces() as u16 =>
+            Some(SetupResult::tx_data(&STATUS[0])),
+        (0x82, 0x00 | 0x80) => Some(SetupResult::tx_data(&STATUS[0])),
+        _ => None,
+    }
+}
+
+impl usb::EndpointPair for ControlSetup {
     fn setup_wanted(&mut self, setup: &SetupHeader) -> bool {
-        setup.index == descriptors::INTF_DFU as u16
+        halt::setup_wanted(setup) || extra_descriptor(setup).is_some()
+            || get_status(setup).is_some()
+            || wakeup::setup_wanted(setup)
+            || debug_serial::setup_wanted(setup)
+            || setup.index == USB_MODE.read().intf_dfu() as u16
     }
     fn setup_handler(&mut self, setup: &SetupHeader) -> SetupResult {
+        if halt::setup_wanted(setup) {
+            return halt::setup_handler(setup);
+        }
+        if wakeup::setup_wanted(setup) {
+            return wakeup::setup_handler(setup);
+        }
+        if debug_serial::setup_wanted(setup) {
+            return debug_serial::setup_handler(setup);
+        }
+        if let Some(result) = extra_descriptor(setup) {
+            return result;
+        }
+        if let Some(result) = get_status(setup) {
+            return result;
+        }
         match (setup.request_type, setup.request) {
             (0x21, 0x00) => unsafe {crate::cpu::trigger_dfu()},
             (0xa1, 0x03) => SetupResult::tx_data(&[0u8, 100, 0, 0, 0, 0]),
             _ => SetupResult::error(),
         }
     }
+    fn initialize() {
+        // The debug serial endpoints are not known to the USB core.
+        debug_serial::initialize();
+        halt::initialize();
+        wakeup::initialize();
+    }
 }
 
 fn usb_isr() {
+    let usb = unsafe {&*stm32h503::USB::ptr()};
+    wakeup::isr();
+    if usb.ISTR.read().SOF().bit() {
+        sof::note_sof(usb.FNR.read().FN().bits() as u32);
+        halt::sof_tick();
+        crate::gps_uart::rx_forward();
+        debug_serial::start_of_frame();
+    }
+    setup_log::snoop();
+    zlp::before_isr();
+    debug_serial::service();
     if unsafe{USB_STATE.as_mut()}.isr() {
         crate::led::BLUE.pulse(true);
     }
+    zlp::after_isr();
 }
 
 pub fn init() {
     unsafe{USB_STATE.as_mut()}.init();
+    wakeup::init();
+    crate::tick::register(serial::reset_tick);
 
     enable_priority(INTERRUPT, interrupt::PRIO_COMMS);
 }
 
+/// The data stage of a control OUT request, as a `T`.  Setup handlers request
+/// the data stage with `SetupResult::rx_data_cb`, and the callback can then
+/// use this to fetch it, rather than each picking apart the USB SRAM itself.
+/// The USB SRAM is read by word.
+pub fn ctrl_rx_data<T>() -> T {
+    const {assert!(size_of::<T>() <= 64)};
+    let mut words = [0u32; 16];
+    let src = CTRL_RX_BUF as *const u32;
+    for (i, w) in words[.. size_of::<T>().div_ceil(4)].iter_mut().enumerate() {
+        *w = unsafe {core::ptr::read_volatile(src.wrapping_add(i))};
+    }
+    unsafe {core::ptr::read_unaligned(words.as_ptr() as *const T)}
+}
+
+/// Report the state of the HSI48 USB clock, optionally switching it on or off
+/// first.  We refuse to turn it off underneath an enumerated USB device, and
+/// give up if it does not become ready within `HSI48_TIMEOUT`.  Returns [on,
+/// ready, CRS trim].
+pub fn hsi48(enable: Option<bool>) -> Result<[u8; 3], ()> {
+    /// The HSI48 should start within a few µs; allow 1ms.
+    const HSI48_TIMEOUT: u32 = crate::cpu::CPU_FREQ / 1000;
+    let crs = unsafe {&*stm32h503::CRS::ptr()};
+    let rcc = unsafe {&*stm32h503::RCC::ptr()};
+    let usb = unsafe {&*stm32h503::USB::ptr()};
+
+    match enable {
+        Some(true) => {
+            rcc.CR.modify(|_,w| w.HSI48ON().set_bit());
+            let start = crate::tick::cycles();
+            while !rcc.CR.read().HSI48RDY().bit() {
+                if crate::tick::cycles().wrapping_sub(start) > HSI48_TIMEOUT {
+                    return Err(());
+                }
+            }
+        }
+        Some(false) => {
+            if usb.DADDR.read().ADD().bits() != 0 {
+                return Err(());
+            }
+            rcc.CR.modify(|_,w| w.HSI48ON().clear_bit());
+        }
+        None => (),
+    }
+    let cr = rcc.CR.read();
+    Ok([cr.HSI48ON().bit() as u8, cr.HSI48RDY().bit() as u8,
+        crs.CR.read().TRIM().bits()])
+}
+
+pub fn get_mode() -> UsbMode {
+    USB_MODE.read()
+}
+
+/// Change the set of USB functions presented.  If USB is running, then we
+/// disconnect and reconnect, so that the host re-enumerates us.  Returns true
+/// if that happened.
+pub fn set_mode(mode: UsbMode) -> bool {
+    let usb = unsafe {&*stm32h503::USB::ptr()};
+    if mode == USB_MODE.read() {
+        return false;
+    }
+    USB_MODE.write(mode);
+    if !usb.BCDR.read().DPPU_DPD().bit() {
+        return false;                   // Not connected yet.
+    }
+    // Drop the D+ pull-up for ≈10ms so that the host notices a disconnect.
+    usb.BCDR.modify(|_,w| w.DPPU_DPD().clear_bit());
+    for _ in 0 .. crate::cpu::CPU_FREQ / 200 {
+        stm_common::utils::nothing();
+    }
+    usb.BCDR.modify(|_,w| w.DPPU_DPD().set_bit());
+    true
+}
+
 impl crate::cpu::Config {
     pub const fn usb(&mut self) -> &mut Self {
         self.isr(INTERRUPT, usb_isr)
@@ -80,33 +261,63 @@ impl crate::cpu::Config {
 }
 
 pub trait CheprWriter: usb::hardware::CheprWriter {
+    fn control  (&mut self) -> &mut Self {self.endpoint(0, 1)}
     fn serial   (&mut self) -> &mut Self {self.endpoint(1, 0)}
     fn interrupt(&mut self) -> &mut Self {self.endpoint(2, 3)}
     fn main     (&mut self) -> &mut Self {self.endpoint(3, 0)}
+    fn debug    (&mut self) -> &mut Self {self.endpoint(4, 0)}
+    fn debug_intr(&mut self) -> &mut Self {self.endpoint(5, 3)}
 }
 
 impl CheprWriter for stm32h503::usb::chepr::W {
 }
 
+/// The USB errata (CTR interrupt raised before the buffer descriptor update
+/// completes) requires an 800ns delay before reading OUT transfer results.
+pub const ERRATA_DELAY_NS: u32 = 800;
+
+/// Run the USB core's errata delay, for our own reads of OUT buffers.  Using
+/// the core's delay, rather than our own copy, means that measuring this
+/// measures the delay actually used.
+pub fn errata_delay() {
+    usb::USB_State::<FreakUSB>::errata_delay();
+}
+
 const BULK_RX_OFFSET: usize = 0x100;
 const BULK_TX_OFFSET: usize = 0x180;
 const INTR_TX_OFFSET: usize = 0x40;
+/// The main RX is double buffered, at MAIN_RX_OFFSET and MAIN_RX_OFFSET + 64.
 const MAIN_RX_OFFSET: usize = 0x200;
-const MAIN_TX_OFFSET: usize = 0x240;
+const MAIN_TX_OFFSET: usize = 0x300;
+const DBG_RX_OFFSET : usize = 0x280;
+const DBG_TX_OFFSET : usize = 0x2c0;
 
 const BULK_RX_BUF: *mut u8  = (USB_SRAM_BASE + BULK_RX_OFFSET) as *mut u8;
 const BULK_TX_BUF: *mut u32 = (USB_SRAM_BASE + BULK_TX_OFFSET) as *mut u32;
 const INTR_TX_BUF: *mut u8  = (USB_SRAM_BASE + INTR_TX_OFFSET) as *mut u8;
 const MAIN_RX_BUF: *mut u8  = (USB_SRAM_BASE + MAIN_RX_OFFSET) as *mut u8;
 const MAIN_TX_BUF: *mut u8  = (USB_SRAM_BASE + MAIN_TX_OFFSET) as *mut u8;
+const DBG_RX_BUF : *mut u8  = (USB_SRAM_BASE + DBG_RX_OFFSET ) as *mut u8;
+const DBG_TX_BUF : *mut u8  = (USB_SRAM_BASE + DBG_TX_OFFSET ) as *mut u8;
 
 fn chep_ser () -> &'static stm32h503::usb::CHEPR {chep_ref(1)}
 fn chep_intr() -> &'static stm32h503::usb::CHEPR {chep_ref(2)}
 fn chep_main() -> &'static stm32h503::usb::CHEPR {chep_ref(3)}
+fn chep_dbg () -> &'static stm32h503::usb::CHEPR {chep_ref(4)}
+fn chep_dbg_intr() -> &'static stm32h503::usb::CHEPR {chep_ref(5)}
 
 fn bd_serial()    -> &'static BD {&chep_bd()[1]}
 fn bd_interrupt() -> &'static BD {&chep_bd()[2]}
 fn bd_main()      -> &'static BD {&chep_bd()[3]}
+fn bd_debug()     -> &'static BD {&chep_bd()[4]}
+
+#[test]
+fn test_device_status() {
+    assert_eq!(device_status(descriptors::CONFIG_ATTRIBUTES, false), 0);
+    assert_eq!(device_status(descriptors::CONFIG_ATTRIBUTES, true), 2);
+    assert_eq!(device_status(0xc0, true), 1);
+    assert_eq!(device_status(0xe0, true), 3);
+}
 
 #[test]
 fn check_isr() {
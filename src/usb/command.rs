@@ -1,15 +1,52 @@
 use crate::usb::CheprWriter as _;
 use crate::usb::{MAIN_RX_BUF, MAIN_TX_BUF, bd_main, chep_main};
 use stm_common::{link_assert, usb};
+use stm_common::vcell::{UCell, VCell};
+
+use crate::cpu::Priority;
+use crate::cpu::interrupt::PRIO_COMMS;
 
 use usb::EndpointPair;
-use usb::hardware::{CheprReader, CheprWriter, chep_bd_len, copy_by_dest32};
+use usb::hardware::{
+    CheprReader, CheprWriter, chep_bd_len, chep_bd_ptr, copy_by_dest32};
 
 #[derive_const(Default)]
 pub struct CommandUSB;
 
 macro_rules!dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
 
+/// Set while a received command is being processed, until the RX is re-armed.
+static BUSY: VCell<bool> = VCell::new(false);
+
+/// The RX buffer descriptor of the message being processed, captured on
+/// reception.  The BD itself is switched to the other of the two RX buffers,
+/// so that re-arming the RX underneath the processing, as `initialize` may,
+/// cannot overwrite the message.
+static RX_BD: VCell<u32> = VCell::new(0);
+
+/// Copy of the message being processed, so that the handler is unaffected by
+/// any further USB RX.
+static MESSAGE: UCell<crate::command::MessageBuf> = Default::default();
+
+/// Set while the command handler runs.  A handler may send several responses,
+/// so the RX is only re-armed once it has finished and the last response has
+/// been collected.
+static HANDLING: VCell<bool> = VCell::new(false);
+
+/// Cycle count at the start of the command handler, for `RESPONSE_TIMEOUT`.
+static HANDLER_START: VCell<u32> = VCell::new(0);
+
+/// Set if the host stopped collecting responses; further responses from the
+/// same handler are dropped.
+static ABANDONED: VCell<bool> = VCell::new(false);
+
+/// How long a handler may wait, in total, for the host to collect responses.
+/// This is well below the watchdog timeout.
+const RESPONSE_TIMEOUT: u32 = crate::cpu::CPU_FREQ / 2;
+
+/// Is the command endpoint free to receive?  Used when clearing a halt.
+pub fn command_idle() -> bool {!BUSY.read()}
+
 pub fn init() {
     // We use the PENDSV exception to dispatch some work at lower priority.
     let scb = unsafe {&*cortex_m::peripheral::SCB::PTR};
@@ -29,10 +66,17 @@ impl EndpointPair for CommandUSB {
         }
         dbgln!("main: RX interrupt, CHEP {:#6x}", chep.bits());
 
+        // Claim the received buffer, and point the BD at the other one.  The
+        // RX is not re-armed until the response is sent.
+        let bd = bd_main().rx.read();
+        RX_BD.write(bd);
+        bd_main().rx.write(bd ^ 64);
+
         // We notify the application by triggering PendSV.  The application
         // can notify completion, either by transmitting a message or by
         // by calling the completion function.
         let scb = unsafe {&*cortex_m::peripheral::SCB::PTR};
+        BUSY.write(true);
         unsafe {scb.icsr.write(1 << 28)};
 
         chep_main().write(|w| w.main().VTRX().clear_bit());
@@ -47,47 +91,101 @@ impl EndpointPair for CommandUSB {
             dbgln!("main: Spurious TX interrupt, CHEP {:#6x}", chep.bits());
             return;
         }
-        chep_main().write(|w| w.main().rx_valid(&chep).VTTX().clear_bit());
+        chep_main().write(|w| w.main().VTTX().clear_bit());
+        if HANDLING.read() {
+            return;     // More responses may follow, don't accept a request yet.
+        }
+        rearm_rx();
         dbgln!("main: TX done CHEP {:#06x} was {:#06x}",
                     chep_main().read().bits(), chep.bits());
     }
 
     fn initialize() {
-        bd_main().rx_set::<64>(MAIN_RX_BUF);
+        // This can happen underneath processing a message, so use the buffer
+        // not holding it.
+        bd_main().rx_set::<64>(free_rx_buf());
 
-        // Main.  FIXME - this can happen underneath processing a message, leaving
-        // us in inconsistent state.  We should recover!
         let main = chep_main().read();
         chep_main().write(|w| w.main().init(&main).rx_valid(&main).tx_nak(&main));
     }
 }
 
+/// The RX buffer not holding the message most recently received.
+fn free_rx_buf() -> *mut u8 {
+    let claimed = chep_bd_ptr(RX_BD.read()) as usize;
+    if claimed == MAIN_RX_BUF as usize {MAIN_RX_BUF.wrapping_add(64)}
+    else {MAIN_RX_BUF}
+}
+
 /// PendSV ISR for handling device commands at appropriate priority.
 fn command_handler() {
     dbgln!("Command handler entry");
 
-    // Get a point to the message.  TODO - copy!
-    let message = unsafe {&*(MAIN_RX_BUF as *const crate::command::MessageBuf)};
-    crate::command::command_handler(
-        message, chep_bd_len(bd_main().rx.read()), main_tx_response);
+    let bd = RX_BD.read();
+    HANDLER_START.write(crate::tick::cycles());
+    ABANDONED.write(false);
+    HANDLING.write(true);
+    crate::command::copy_and_handle(
+        unsafe {MESSAGE.as_mut()}, chep_bd_ptr(bd), chep_bd_len(bd),
+        main_tx_response);
+
+    // If the last response has already been collected, then `tx_handler` left
+    // the RX for us to re-arm.
+    let _prio = Priority::<PRIO_COMMS>::default();
+    HANDLING.write(false);
+    if BUSY.read() && !tx_pending() {
+        rearm_rx();
+    }
+}
+
+/// Accept the next request.  If the host has halted the OUT endpoint, clearing
+/// the halt re-arms it instead.  Call at PRIO_COMMS.
+fn rearm_rx() {
+    BUSY.write(false);
+    if !super::halt::halted(0x03) {
+        let chep = chep_main().read();
+        chep_main().write(|w| w.main().rx_valid(&chep));
+    }
+}
+
+/// Is a response waiting to be collected by the host?
+fn tx_pending() -> bool {
+    chep_main().read().tx_active() || super::halt::response_held()
 }
 
 // Called at lower priority and can get interrupted!
 fn main_tx_response(message: &[u8]) {
-    let chep = chep_main().read();
     if message.len() == 0 {
         dbgln!("main_tx_response, no data, rearm");
-        chep_main().write(|w| w.main().rx_valid(&chep));
+        let _prio = Priority::<PRIO_COMMS>::default();
+        rearm_rx();
         return;
     }
+    // A handler may send several responses; wait for the host to collect any
+    // previous one before overwriting the buffer.  Give up if the host does not
+    // keep up, rather than wedging the command priority.
+    while tx_pending() {
+        if ABANDONED.read() || crate::tick::cycles().wrapping_sub(
+                HANDLER_START.read()) > RESPONSE_TIMEOUT {
+            dbgln!("main tx abandoned");
+            ABANDONED.write(true);
+            return;
+        }
+        stm_common::utils::nothing();
+    }
     // For now we don't support long messages.
     let len = message.len().min(64);
     unsafe {copy_by_dest32(message.as_ptr(), MAIN_TX_BUF, message.len())};
 
     bd_main().tx_set(MAIN_TX_BUF, len);
 
+    // If the host has halted the IN endpoint, the response is sent when the
+    // halt is cleared.
+    let _prio = Priority::<PRIO_COMMS>::default();
     let chep = chep_main().read();
-    chep_main().write(|w| w.main().tx_valid(&chep));
+    if !super::halt::hold_response() {
+        chep_main().write(|w| w.main().tx_valid(&chep));
+    }
 
     dbgln!("main tx {len} bytes, {}CHEP now {:#06x} was {:#06x}",
                 if chep.tx_active() {"INCORRECT STATE "} else {""},
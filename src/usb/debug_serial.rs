@@ -0,0 +1,91 @@
+//! Second CDC ACM serial, carrying a copy of the debug output.
+//!
+//! Endpoint 4 is bulk IN/OUT, endpoint 5 is the notification interrupt IN.
+//! Data is sent from the SOF, in packets of at most 63 bytes, so that each
+//! packet completes a transfer.  Data from the host is discarded, and the
+//! interrupt endpoint is never used.
+//!
+//! The USB core only knows about endpoints 1 to 3 (and the pseudo-endpoint 7),
+//! so the USB ISR calls `service` before the core handling, to deal with our
+//! endpoint events first.
+
+use stm_common::usb;
+
+use usb::types::{LineCoding, SetupHeader, SetupResult};
+use usb::hardware::{CheprReader, CheprWriter, copy_by_dest32};
+
+use super::{
+    DBG_RX_BUF, DBG_TX_BUF, USB_MODE, UsbMode, bd_debug, chep_dbg,
+    chep_dbg_intr};
+use super::CheprWriter as _;
+use super::descriptors::{INTF_DBG_DATA, INTF_DBG_INTR};
+
+/// Maximum bytes per packet.  One short of full, so that each packet completes
+/// a transfer on the host.
+const MAX_PACKET: usize = 63;
+
+pub fn initialize() {
+    bd_debug().rx_set::<64>(DBG_RX_BUF);
+
+    let dbg = chep_dbg().read();
+    chep_dbg().write(|w| w.debug().init(&dbg).rx_valid(&dbg).tx_nak(&dbg));
+    let intr = chep_dbg_intr().read();
+    chep_dbg_intr().write(|w| w.debug_intr().init(&intr).tx_nak(&intr));
+}
+
+/// Handle any endpoint events.  Call from the USB ISR before the core
+/// handling.
+pub fn service() {
+    let chep = chep_dbg().read();
+    if chep.VTTX().bit() {
+        chep_dbg().write(|w| w.debug().VTTX().clear_bit());
+    }
+    if chep.VTRX().bit() {
+        // Discard whatever the host sent.
+        chep_dbg().write(|w| w.debug().VTRX().clear_bit().rx_valid(&chep));
+    }
+    let intr = chep_dbg_intr().read();
+    if intr.VTTX().bit() {
+        chep_dbg_intr().write(|w| w.debug_intr().VTTX().clear_bit());
+    }
+}
+
+/// Send any pending debug output.  Call from the USB ISR on SOF.
+pub fn start_of_frame() {
+    if USB_MODE.read() != UsbMode::Full {
+        return;
+    }
+    let chep = chep_dbg().read();
+    if !chep.tx_nakking() {
+        return;                         // Busy, or not configured.
+    }
+    let mut packet = [0u8; MAX_PACKET];
+    let len = crate::debug::take_for_usb(&mut packet);
+    if len == 0 {
+        return;
+    }
+    unsafe {copy_by_dest32(packet.as_ptr(), DBG_TX_BUF, len)};
+    bd_debug().tx_set(DBG_TX_BUF, len);
+    chep_dbg().write(|w| w.debug().tx_valid(&chep));
+}
+
+pub fn setup_wanted(setup: &SetupHeader) -> bool {
+    USB_MODE.read() == UsbMode::Full && setup.request_type & 0x1f == 1
+        && (setup.index == INTF_DBG_INTR as u16
+            || setup.index == INTF_DBG_DATA as u16)
+}
+
+pub fn setup_handler(setup: &SetupHeader) -> SetupResult {
+    match (setup.request_type, setup.request) {
+        // Accept and ignore the line settings.
+        (0x21, 0x20) => SetupResult::rx_data_cb(7, || true),
+        (0xa1, 0x21) => {
+            static LINE_CODING: LineCoding = LineCoding{
+                dte_rate: crate::debug::BAUD,
+                char_format: 0, parity_type: 0, data_bits: 8};
+            SetupResult::tx_data(&LINE_CODING)
+        }
+        (0x21, 0x22) | (0x21, 0x23) => SetupResult::no_data(),
+        _ => SetupResult::error(),
+    }
+}
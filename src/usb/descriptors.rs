@@ -9,13 +9,18 @@ pub const INTF_ACM_INTR: u8 = 0;
 pub const INTF_ACM_DATA: u8 = 1;
 pub const INTF_MAIN    : u8 = 2;
 pub const INTF_DFU     : u8 = 3;
+pub const INTF_DBG_INTR: u8 = 4;
+pub const INTF_DBG_DATA: u8 = 5;
 
 type Offset = u8;
 
-pub const STRING_LIST: [&str; 8] = [
+use crate::command::BUILD_ID_STR;
+
+pub const STRING_LIST: [&str; 10] = [
     "\u{0409}", // Languages.
-    "Ralph", "GPS Freak", "Device Configuration",
+    "Ralph", "GPS Freak", BUILD_ID_STR,
     "CDC", "CDC DATA interface", "Device Control", "DFU",
+    "Debug CDC", "Debug CDC DATA interface",
 ];
 
 stm_common::define_usb_strings!{}
@@ -25,7 +30,7 @@ pub const IDX_SERIAL_NUMBER: u8 = NUM_STRINGS as u8;
 pub static DEVICE_DESC: DeviceDesc = DeviceDesc{
     length            : size_of::<DeviceDesc>() as u8,
     descriptor_type   : TYPE_DEVICE,
-    usb               : 0x200,
+    usb               : 0x210, // 2.1, for the BOS descriptor.
     device_class      : 239, // Miscellaneous device
     device_sub_class  : 2, // Unknown
     device_protocol   : 1, // Interface association
@@ -39,6 +44,9 @@ pub static DEVICE_DESC: DeviceDesc = DeviceDesc{
     num_configurations: 1,
 };
 
+/// Descriptor type for the device qualifier.
+pub const TYPE_DEVICE_QUALIFIER: u8 = 6;
+
 #[repr(C, packed)]
 #[allow(dead_code)]
 pub struct FullConfigDesc {
@@ -58,90 +66,297 @@ pub struct FullConfigDesc {
     endp4     : EndpointDesc,
     interface3: InterfaceDesc,
     dfu       : DFU_FunctionalDesc,
+    dbg_assoc : InterfaceAssociation,
+    interface4: InterfaceDesc,
+    dbg_header: CDC_Header,
+    dbg_call  : CallManagementDesc,
+    dbg_acm   : AbstractControlDesc,
+    dbg_union : UnionFunctionalDesc<1>,
+    endp5     : EndpointDesc,
+    interface5: InterfaceDesc,
+    endp6     : EndpointDesc,
+    endp7     : EndpointDesc,
 }
 
-/// Our main configuration descriptor.
-pub static CONFIG0_DESC: FullConfigDesc = FullConfigDesc{
-    config: ConfigurationDesc{
+/// Configuration attributes: bus powered (bit 6 clear), remote wakeup (bit 5).
+pub const CONFIG_ATTRIBUTES: u8 = 0xa0;
+
+/// Configuration descriptor header, shared by all our configurations.
+const fn config_desc(total_length: usize, num_interfaces: u8)
+    -> ConfigurationDesc {
+    ConfigurationDesc{
         length             : size_of::<ConfigurationDesc>() as u8,
         descriptor_type    : TYPE_CONFIGURATION,
-        total_length       : size_of::<FullConfigDesc>() as u16,
-        num_interfaces     : 4,
+        total_length       : total_length as u16,
+        num_interfaces,
         configuration_value: 1,
-        i_configuration    : string_index("Device Configuration"),
-        attributes         : 0x80,      // Bus powered.
+        // The firmware build, so that `lsusb -v` shows it.
+        i_configuration    : string_index(BUILD_ID_STR),
+        attributes         : CONFIG_ATTRIBUTES,
         max_power          : 200,       // 400mA
-    },
-    assoc: InterfaceAssociation{
+    }
+}
+
+const fn cdc_assoc(first_interface: u8, i_function: u8)
+    -> InterfaceAssociation {
+    InterfaceAssociation{
         length             : size_of::<InterfaceAssociation>() as u8,
         descriptor_type    : TYPE_INTF_ASSOC,
-        first_interface    : 0,
+        first_interface,
         interface_count    : 2,
-        function_class     : 2,          // Communications
-        function_sub_class : 2,          // Abstract (Modem [sic])
+        function_class     : 2,         // Communications
+        function_sub_class : 2,         // Abstract (Modem [sic])
         function_protocol  : 0,
-        i_function         : string_index("CDC"),
-    },
-    // 1 endpoints, Communication, Abstract, AT Commands [sic]
-    interface0: InterfaceDesc::new(
-        INTF_ACM_INTR, 1, 2, 2, 1, string_index("CDC")),
-    cdc_header: CDC_Header{
-        length             : size_of::<CDC_Header>() as u8,
-        descriptor_type    : TYPE_CS_INTERFACE,
-        sub_type           : 0,         // CDC Header Functional Descriptor
-        cdc                : 0x0110,
-    },
-    call_mgmt: CallManagementDesc{
+        i_function,
+    }
+}
+
+const CDC_ASSOC: InterfaceAssociation
+    = cdc_assoc(INTF_ACM_INTR, string_index("CDC"));
+
+const CDC_HEADER: CDC_Header = CDC_Header{
+    length             : size_of::<CDC_Header>() as u8,
+    descriptor_type    : TYPE_CS_INTERFACE,
+    sub_type           : 0,             // CDC Header Functional Descriptor
+    cdc                : 0x0110,
+};
+
+const fn call_mgmt(data_interface: u8) -> CallManagementDesc {
+    CallManagementDesc{
         length             : size_of::<CallManagementDesc>() as u8,
         descriptor_type    : TYPE_CS_INTERFACE,
         sub_type           : 1,         // Call management [sic]
         capabilities       : 3,         // Call management, data.
-        data_interface     : 1,
-    },
-    acm_ctrl: AbstractControlDesc{
-        length             : size_of::<AbstractControlDesc>() as u8,
-        descriptor_type    : TYPE_CS_INTERFACE,
-        sub_type           : 2,         // Abstract Control Mgmt Functional Desc
-        // TODO - this is not correct
-        capabilities       : 6,         // "Line coding and serial state"
-    },
-    union_desc: UnionFunctionalDesc::<1>{
+        data_interface,
+    }
+}
+
+const CALL_MGMT: CallManagementDesc = call_mgmt(INTF_ACM_DATA);
+
+const ACM_CTRL: AbstractControlDesc = AbstractControlDesc{
+    length             : size_of::<AbstractControlDesc>() as u8,
+    descriptor_type    : TYPE_CS_INTERFACE,
+    sub_type           : 2,             // Abstract Control Mgmt Functional Desc
+    // TODO - this is not correct
+    capabilities       : 6,             // "Line coding and serial state"
+};
+
+const fn union_desc(control_interface: u8, data_interface: u8)
+    -> UnionFunctionalDesc<1> {
+    UnionFunctionalDesc::<1>{
         length             : size_of::<UnionFunctionalDesc<1>>() as u8,
         descriptor_type    : TYPE_CS_INTERFACE,
         sub_type           : 6,         // Union Functional Desc,
-        control_interface  : 0,
-        sub_interface      : [1],
-    },
-    endp0                  : EndpointDesc::new(0x82, 3, 64, 4), // IN 2, Interrupt.
+        control_interface,
+        sub_interface      : [data_interface],
+    }
+}
+
+const UNION_DESC: UnionFunctionalDesc<1>
+    = union_desc(INTF_ACM_INTR, INTF_ACM_DATA);
+
+const DFU_FUNCTIONAL: DFU_FunctionalDesc = DFU_FunctionalDesc {
+    length             : size_of::<DFU_FunctionalDesc>() as u8,
+    descriptor_type    : TYPE_DFU_FUNCTIONAL,
+    attributes         : 0x0b,
+    detach_time_out    : 1000,
+    transfer_size      : 1024,
+    dfu_version        : 0x011a,
+};
+
+const fn main_intf(number: u8) -> InterfaceDesc {
+    InterfaceDesc::new(                 // Vendor specific.
+        number, 2, 0xff, 0, 0, string_index("Device Control"))
+}
+
+const fn dfu_intf(number: u8) -> InterfaceDesc {
+    InterfaceDesc::new(                 // Application specific / DFU / 1.
+        number, 0, 0xfe, 1, 1, string_index("DFU"))
+}
+
+/// Our main configuration descriptor.  This includes a second CDC ACM, with
+/// a copy of the debug output, after the DFU interface so that the DFU
+/// interface number is the same as it always has been.
+pub static CONFIG0_DESC: FullConfigDesc = FullConfigDesc{
+    config    : config_desc(size_of::<FullConfigDesc>(), 6),
+    assoc     : CDC_ASSOC,
+    // 1 endpoints, Communication, Abstract, AT Commands [sic]
+    interface0: InterfaceDesc::new(
+        INTF_ACM_INTR, 1, 2, 2, 1, string_index("CDC")),
+    cdc_header: CDC_HEADER,
+    call_mgmt : CALL_MGMT,
+    acm_ctrl  : ACM_CTRL,
+    union_desc: UNION_DESC,
+    endp0     : EndpointDesc::new(0x82, 3, 64, 4), // IN 2, Interrupt.
+    interface1: InterfaceDesc::new(
+        INTF_ACM_DATA, 2, 10, 0, 0, string_index("CDC DATA interface")),
+    endp1     : EndpointDesc::new(0x81, 2, 64, 1), // IN 1, Bulk.
+    endp2     : EndpointDesc::new(0x01, 2, 64, 1), // OUT 1, Bulk.
+    interface2: main_intf(INTF_MAIN),
+    endp3     : EndpointDesc::new(0x03, 2, 64, 1),
+    endp4     : EndpointDesc::new(0x83, 2, 64, 1),
+    interface3: dfu_intf(INTF_DFU),
+    dfu       : DFU_FUNCTIONAL,
+    dbg_assoc : cdc_assoc(INTF_DBG_INTR, string_index("Debug CDC")),
+    interface4: InterfaceDesc::new(
+        INTF_DBG_INTR, 1, 2, 2, 1, string_index("Debug CDC")),
+    dbg_header: CDC_HEADER,
+    dbg_call  : call_mgmt(INTF_DBG_DATA),
+    dbg_acm   : ACM_CTRL,
+    dbg_union : union_desc(INTF_DBG_INTR, INTF_DBG_DATA),
+    endp5     : EndpointDesc::new(0x85, 3, 64, 4), // IN 5, Interrupt.
+    interface5: InterfaceDesc::new(
+        INTF_DBG_DATA, 2, 10, 0, 0, string_index("Debug CDC DATA interface")),
+    endp6     : EndpointDesc::new(0x84, 2, 64, 1), // IN 4, Bulk.
+    endp7     : EndpointDesc::new(0x04, 2, 64, 1), // OUT 4, Bulk.
+};
+
+/// Reduced configuration with just the CDC ACM serial (and DFU).
+#[repr(C, packed)]
+#[allow(dead_code)]
+pub struct SerialConfigDesc {
+    config    : ConfigurationDesc,
+    assoc     : InterfaceAssociation,
+    interface0: InterfaceDesc,
+    cdc_header: CDC_Header,
+    call_mgmt : CallManagementDesc,
+    acm_ctrl  : AbstractControlDesc,
+    union_desc: UnionFunctionalDesc<1>,
+    endp0     : EndpointDesc,
+    interface1: InterfaceDesc,
+    endp1     : EndpointDesc,
+    endp2     : EndpointDesc,
+    interface2: InterfaceDesc,
+    dfu       : DFU_FunctionalDesc,
+}
+
+pub static SERIAL_CONFIG_DESC: SerialConfigDesc = SerialConfigDesc{
+    config    : config_desc(size_of::<SerialConfigDesc>(), 3),
+    assoc     : CDC_ASSOC,
+    interface0: InterfaceDesc::new(
+        INTF_ACM_INTR, 1, 2, 2, 1, string_index("CDC")),
+    cdc_header: CDC_HEADER,
+    call_mgmt : CALL_MGMT,
+    acm_ctrl  : ACM_CTRL,
+    union_desc: UNION_DESC,
+    endp0     : EndpointDesc::new(0x82, 3, 64, 4), // IN 2, Interrupt.
     interface1: InterfaceDesc::new(
         INTF_ACM_DATA, 2, 10, 0, 0, string_index("CDC DATA interface")),
-    endp1                  : EndpointDesc::new(0x81, 2, 64, 1), // IN 1, Bulk.
-    endp2                  : EndpointDesc::new(0x01, 2, 64, 1), // OUT 1, Bulk.
-    interface2: InterfaceDesc::new(                          // Vendor specific.
-        INTF_MAIN, 2, 0xff, 0, 0, string_index("Device Control")),
-    endp3                  : EndpointDesc::new(0x03, 2, 64, 1),
-    endp4                  : EndpointDesc::new(0x83, 2, 64, 1),
-    interface3: InterfaceDesc::new(           // Application specific / DFU / 1.
-        INTF_DFU, 0, 0xfe, 1, 1, string_index("DFU")),
-    dfu: DFU_FunctionalDesc {
-        length             : size_of::<DFU_FunctionalDesc>() as u8,
-        descriptor_type    : TYPE_DFU_FUNCTIONAL,
-        attributes         : 0x0b,
-        detach_time_out    : 1000,
-        transfer_size      : 1024,
-        dfu_version        : 0x011a,
-    },
+    endp1     : EndpointDesc::new(0x81, 2, 64, 1), // IN 1, Bulk.
+    endp2     : EndpointDesc::new(0x01, 2, 64, 1), // OUT 1, Bulk.
+    interface2: dfu_intf(2),
+    dfu       : DFU_FUNCTIONAL,
+};
+
+/// Reduced configuration with just the device command interface (and DFU).
+#[repr(C, packed)]
+#[allow(dead_code)]
+pub struct CommandConfigDesc {
+    config    : ConfigurationDesc,
+    interface0: InterfaceDesc,
+    endp0     : EndpointDesc,
+    endp1     : EndpointDesc,
+    interface1: InterfaceDesc,
+    dfu       : DFU_FunctionalDesc,
+}
+
+pub static COMMAND_CONFIG_DESC: CommandConfigDesc = CommandConfigDesc{
+    config    : config_desc(size_of::<CommandConfigDesc>(), 2),
+    interface0: main_intf(0),
+    endp0     : EndpointDesc::new(0x03, 2, 64, 1),
+    endp1     : EndpointDesc::new(0x83, 2, 64, 1),
+    interface1: dfu_intf(1),
+    dfu       : DFU_FUNCTIONAL,
 };
 
+/// Which USB functions we present to the host.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum UsbMode {
+    /// Both the CDC ACM serial and the command interface.
+    Full        = 0,
+    /// CDC ACM serial only.
+    SerialOnly  = 1,
+    /// Command interface only.
+    CommandOnly = 2,
+}
+
+impl UsbMode {
+    pub fn from_u8(mode: u8) -> Option<UsbMode> {
+        match mode {
+            0 => Some(UsbMode::Full),
+            1 => Some(UsbMode::SerialOnly),
+            2 => Some(UsbMode::CommandOnly),
+            _ => None,
+        }
+    }
+    /// The DFU interface number in this mode.
+    pub fn intf_dfu(self) -> u8 {
+        match self {
+            UsbMode::Full        => INTF_DFU,
+            UsbMode::SerialOnly  => 2,
+            UsbMode::CommandOnly => 1,
+        }
+    }
+    /// The number of interfaces in this mode.
+    pub fn num_interfaces(self) -> u8 {
+        match self {
+            UsbMode::Full        => 6,
+            UsbMode::SerialOnly  => 3,
+            UsbMode::CommandOnly => 2,
+        }
+    }
+    pub fn has_serial(self) -> bool {self != UsbMode::CommandOnly}
+
+    pub fn config_descriptor(self) -> SetupResult {
+        match self {
+            UsbMode::Full        => SetupResult::tx_data(&CONFIG0_DESC),
+            UsbMode::SerialOnly  => SetupResult::tx_data(&SERIAL_CONFIG_DESC),
+            UsbMode::CommandOnly => SetupResult::tx_data(&COMMAND_CONFIG_DESC),
+        }
+    }
+}
+
 pub fn get_string(idx: u8) -> SetupResult {
     if idx != IDX_SERIAL_NUMBER {
         return _get_descriptor(idx);
     }
-    // Special case.
-    let data = crate::command::USB_NAME.as_ref();
-    let byte_len = data[0] & 0xff;
+    // Special case.  The header holds the byte length, which counts UTF-16
+    // code units, so surrogate pairs are two units.  Clamp it to the buffer
+    // anyway.
+    let data = crate::command::usb_serial_number();
+    let byte_len = (data[0] & 0xff) as usize;
     let data = unsafe {
         core::slice::from_raw_parts(data as *const u16 as *const u8,
-                                    byte_len as usize)};
+                                    byte_len.min(size_of_val(data)))};
     SetupResult::Tx(data, None)
 }
+
+/// The raw bytes of string descriptor `idx`, including the header, exactly as
+/// `get_string` returns them.  Returns None if there is no such string.
+pub fn string_raw(idx: u8) -> Option<&'static [u8]> {
+    if idx > IDX_SERIAL_NUMBER {
+        return None;
+    }
+    let SetupResult::Tx(data, _) = get_string(idx) else {return None};
+    Some(data)
+}
+
+/// Decode string descriptor `idx`, exactly as `get_string` returns it, into
+/// UTF-8.  Returns the number of bytes written, or None if there is no such
+/// string.  Output is truncated, on a character boundary, if `out` is short.
+pub fn string_utf8(idx: u8, out: &mut [u8]) -> Option<usize> {
+    let data = string_raw(idx)?;
+    // Skip the two byte descriptor header.
+    let units = data.chunks_exact(2).skip(1).map(
+        |b| u16::from_le_bytes([b[0], b[1]]));
+    let mut len = 0;
+    for c in char::decode_utf16(units) {
+        let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+        if len + c.len_utf8() > out.len() {
+            break;
+        }
+        len += c.encode_utf8(&mut out[len..]).len();
+    }
+    Some(len)
+}
@@ -0,0 +1,313 @@
+//! Endpoint halt handling, and STALL control for testing host error recovery.
+//!
+//! The standard SET_FEATURE / CLEAR_FEATURE(ENDPOINT_HALT) and GET_STATUS
+//! endpoint requests are supported for the serial endpoints (bulk OUT 01, bulk
+//! IN 81 and interrupt IN 82) and the command endpoints (bulk OUT 03 and IN
+//! 83).  Clearing a halt resets the data toggle and restores the endpoint to the
+//! state it would have been in without the halt.  The command endpoints respect
+//! a halt: a response is held back, rather than being sent, until the halt on
+//! 83 is cleared, and the command OUT endpoint is only re-armed if no command
+//! is being processed.
+//!
+//! A stall may also be forced on one of those endpoints by command.  The host
+//! should respond with a CLEAR_FEATURE(ENDPOINT_HALT).  In case it doesn't, we
+//! restore the endpoint ourselves after a timeout, so that a test can't wedge
+//! the device.  The stall is not cleared after a single transaction, as the
+//! USB peripheral does not report sending a STALL handshake, so a host that
+//! retries without clearing the halt sees STALL until the timeout.  The
+//! command endpoints are not eligible for a forced stall, as that would block
+//! the command channel itself.
+
+use stm_common::usb::types::{SetupHeader, SetupResult};
+use stm_common::vcell::{UCell, VCell};
+
+use crate::cpu::Priority;
+use crate::cpu::interrupt::PRIO_COMMS;
+use crate::usb::CheprWriter as _;
+
+use super::{chep_intr, chep_main, chep_ser};
+
+/// STAT value for a stalled endpoint.
+const STAT_STALL: u8 = 1;
+/// STAT values for an endpoint ready to receive, or not ready to transmit.
+const STAT_VALID: u8 = 3;
+const STAT_NAK  : u8 = 2;
+
+/// Feature selector for ENDPOINT_HALT.
+const ENDPOINT_HALT: u16 = 0;
+
+/// Frames (ms) before an un-cleared stall is removed.
+const STALL_TIMEOUT: u16 = 1000;
+
+/// Endpoint address currently stalled, or zero if none.
+static STALLED: VCell<u8> = VCell::new(0);
+/// The STAT value to restore when the stall is removed.
+static SAVED_STAT: VCell<u8> = VCell::new(0);
+/// Frames remaining until the stall times out.
+static FRAMES: VCell<u16> = VCell::new(0);
+
+/// Endpoints halted by the host, as a bit mask by `index`.
+static HALTED: VCell<u8> = VCell::new(0);
+/// The STAT value to restore when a host halt is cleared, by `index`.
+static RESTORE: UCell<[u8; 5]> = UCell::new([0; 5]);
+
+/// Index of a halt eligible endpoint, for `HALTED` and `RESTORE`.
+fn index(address: u8) -> usize {
+    match address {0x01 => 0, 0x81 => 1, 0x82 => 2, 0x03 => 3, _ => 4}
+}
+
+/// Has the host halted an endpoint?
+pub fn halted(address: u8) -> bool {HALTED.read() & 1 << index(address) != 0}
+
+/// While the host has halted 83, queue a response to be sent when the halt is
+/// cleared.  Returns false if not halted, and the caller should send it.  Call
+/// at PRIO_COMMS.
+pub fn hold_response() -> bool {
+    if !halted(0x83) {
+        return false;
+    }
+    unsafe {RESTORE.as_mut()}[index(0x83)] = STAT_VALID;
+    true
+}
+
+/// Is a response held back by a halt on 83?
+pub fn response_held() -> bool {
+    halted(0x83) && RESTORE.as_ref()[index(0x83)] == STAT_VALID
+}
+
+/// Endpoints supporting the halt feature.
+fn halt_eligible(address: u8) -> bool {
+    matches!(address, 0x01 | 0x81 | 0x82 | 0x03 | 0x83)
+}
+
+/// Endpoints that may be stalled by command.
+fn stall_eligible(address: u8) -> bool {
+    matches!(address, 0x01 | 0x81 | 0x82)
+}
+
+/// Read the STAT field for an endpoint.
+fn get_stat(address: u8) -> u8 {
+    match address {
+        0x01 => chep_ser ().read().STATRX().bits(),
+        0x81 => chep_ser ().read().STATTX().bits(),
+        0x03 => chep_main().read().STATRX().bits(),
+        0x83 => chep_main().read().STATTX().bits(),
+        _    => chep_intr().read().STATTX().bits(),
+    }
+}
+
+/// Write the STAT field for an endpoint, leaving the rest unchanged.
+fn set_stat(address: u8, stat: u8) {
+    match address {
+        0x01 => {
+            let c = chep_ser().read();
+            chep_ser().write(|w| w.serial().stat_rx(&c, stat));
+        }
+        0x81 => {
+            let c = chep_ser().read();
+            chep_ser().write(|w| w.serial().stat_tx(&c, stat));
+        }
+        0x03 => {
+            let c = chep_main().read();
+            chep_main().write(|w| w.main().stat_rx(&c, stat));
+        }
+        0x83 => {
+            let c = chep_main().read();
+            chep_main().write(|w| w.main().stat_tx(&c, stat));
+        }
+        _ => {
+            let c = chep_intr().read();
+            chep_intr().write(|w| w.interrupt().stat_tx(&c, stat));
+        }
+    }
+}
+
+/// Reset the data toggle for an endpoint.
+fn reset_toggle(address: u8) {
+    // The DTOG bits toggle when written with one, so write back the current
+    // value.
+    match address {
+        0x01 => {
+            let c = chep_ser().read();
+            chep_ser().write(|w| w.serial().DTOGRX().bit(c.DTOGRX().bit()));
+        }
+        0x81 => {
+            let c = chep_ser().read();
+            chep_ser().write(|w| w.serial().DTOGTX().bit(c.DTOGTX().bit()));
+        }
+        0x03 => {
+            let c = chep_main().read();
+            chep_main().write(|w| w.main().DTOGRX().bit(c.DTOGRX().bit()));
+        }
+        0x83 => {
+            let c = chep_main().read();
+            chep_main().write(|w| w.main().DTOGTX().bit(c.DTOGTX().bit()));
+        }
+        _ => {
+            let c = chep_intr().read();
+            chep_intr().write(|w| w.interrupt().DTOGTX().bit(c.DTOGTX().bit()));
+        }
+    }
+}
+
+/// Standard endpoint requests that we handle.
+#[derive(Debug, PartialEq)]
+enum HaltRequest {
+    GetStatus(u8),
+    SetHalt(u8),
+    ClearHalt(u8),
+}
+
+/// Decode an endpoint halt related setup request.
+fn decode(request_type: u8, request: u8, value: u16, index: u16)
+          -> Option<HaltRequest> {
+    let address = index as u8;
+    if index > 0xff || !halt_eligible(address) {
+        return None;
+    }
+    match (request_type, request, value) {
+        (0x82, 0x00, 0) => Some(HaltRequest::GetStatus(address)),
+        (0x02, 0x01, ENDPOINT_HALT) => Some(HaltRequest::ClearHalt(address)),
+        (0x02, 0x03, ENDPOINT_HALT) => Some(HaltRequest::SetHalt(address)),
+        _ => None,
+    }
+}
+
+fn decode_setup(setup: &SetupHeader) -> Option<HaltRequest> {
+    let value = setup.value_lo as u16 | (setup.value_hi as u16) << 8;
+    decode(setup.request_type, setup.request, value, setup.index)
+}
+
+pub fn setup_wanted(setup: &SetupHeader) -> bool {
+    decode_setup(setup).is_some()
+}
+
+pub fn setup_handler(setup: &SetupHeader) -> SetupResult {
+    let Some(request) = decode_setup(setup)
+        else {return SetupResult::error()};
+    match request {
+        HaltRequest::GetStatus(address) => SetupResult::tx_data(
+            if get_stat(address) == STAT_STALL {&[1u8, 0]} else {&[0u8, 0]}),
+        HaltRequest::SetHalt(address) => {
+            set_halt(address);
+            SetupResult::no_data()
+        }
+        HaltRequest::ClearHalt(address) => {
+            clear_halt(address);
+            SetupResult::no_data()
+        }
+    }
+}
+
+/// Halt an endpoint at the host's request, saving the state to restore.
+fn set_halt(address: u8) {
+    if !halted(address) {
+        let saved = if STALLED.read() == address {
+            // The host takes over a forced stall.
+            STALLED.write(0);
+            SAVED_STAT.read()
+        }
+        else {get_stat(address)};
+        unsafe {RESTORE.as_mut()}[index(address)] = saved;
+        HALTED.write(HALTED.read() | 1 << index(address));
+    }
+    set_stat(address, STAT_STALL);
+}
+
+/// Clear a halt, whether forced or set by the host.
+fn clear_halt(address: u8) {
+    if STALLED.read() == address {
+        unstall();
+    }
+    else if halted(address) {
+        HALTED.write(HALTED.read() & !(1 << index(address)));
+        let stat = if address != 0x03 {RESTORE.as_ref()[index(address)]}
+            else if super::command::command_idle() {STAT_VALID}
+            else {STAT_NAK};
+        set_stat(address, stat);
+    }
+    else if get_stat(address) == STAT_STALL {
+        let rx_ready = address == 0x01
+            || address == 0x03 && super::command::command_idle();
+        set_stat(address, if rx_ready {STAT_VALID} else {STAT_NAK});
+    }
+    reset_toggle(address);
+}
+
+/// The endpoints are re-initialized on reset or configuration, which clears
+/// any halt.
+pub fn initialize() {
+    HALTED.write(0);
+    STALLED.write(0);
+}
+
+/// Stall an endpoint, until the host clears it or the timeout expires.
+/// Returns false if the endpoint is not eligible, or another is stalled.
+pub fn stall(address: u8) -> bool {
+    if !stall_eligible(address) {
+        return false;
+    }
+    let _prio = Priority::<PRIO_COMMS>::default();
+    if STALLED.read() != 0 {
+        return false;
+    }
+    SAVED_STAT.write(get_stat(address));
+    FRAMES.write(STALL_TIMEOUT);
+    STALLED.write(address);
+    set_stat(address, STAT_STALL);
+    true
+}
+
+/// Remove a forced stall, restoring the previous endpoint state.
+fn unstall() {
+    let address = STALLED.read();
+    if address == 0 {
+        return;
+    }
+    STALLED.write(0);
+    if get_stat(address) == STAT_STALL {
+        set_stat(address, SAVED_STAT.read());
+    }
+}
+
+/// Count down the stall timeout.  Call from the USB ISR on each SOF.
+pub fn sof_tick() {
+    if STALLED.read() == 0 {
+        return;
+    }
+    let frames = FRAMES.read().saturating_sub(1);
+    FRAMES.write(frames);
+    if frames == 0 {
+        unstall();
+    }
+}
+
+#[test]
+fn test_decode() {
+    use HaltRequest::*;
+    assert_eq!(decode(0x82, 0x00, 0, 0x81), Some(GetStatus(0x81)));
+    assert_eq!(decode(0x02, 0x01, 0, 0x01), Some(ClearHalt(0x01)));
+    assert_eq!(decode(0x02, 0x03, 0, 0x82), Some(SetHalt(0x82)));
+    // Wrong feature selector.
+    assert_eq!(decode(0x02, 0x01, 1, 0x01), None);
+    assert_eq!(decode(0x02, 0x01, 0, 0x03), Some(ClearHalt(0x03)));
+    assert_eq!(decode(0x82, 0x00, 0, 0x83), Some(GetStatus(0x83)));
+    // Bogus endpoints are not handled.
+    assert_eq!(decode(0x02, 0x01, 0, 0x02), None);
+    assert_eq!(decode(0x82, 0x00, 0, 0x84), None);
+    assert_eq!(decode(0x82, 0x00, 0, 0x181), None);
+    // Interface requests are not ours.
+    assert_eq!(decode(0x21, 0x20, 0, 0x01), None);
+    assert_eq!(decode(0x81, 0x00, 0, 0x01), None);
+}
+
+#[test]
+fn test_index() {
+    let mut seen = 0u8;
+    for address in [0x01, 0x81, 0x82, 0x03, 0x83] {
+        assert!(halt_eligible(address));
+        assert!(index(address) < 5);
+        seen |= 1 << index(address);
+    }
+    assert_eq!(seen, 0x1f);
+}
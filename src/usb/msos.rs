@@ -0,0 +1,150 @@
+//! Microsoft OS 2.0 descriptors, so that Windows binds WinUSB to the command
+//! interface without an INF file.
+//!
+//! Windows reads the BOS descriptor, finds the MS OS 2.0 platform capability,
+//! and then fetches the descriptor set with a vendor request using the vendor
+//! code given in the capability.  The descriptor set gives the command
+//! interface a compatible ID of "WINUSB", and a device interface GUID for
+//! applications to find it by.
+
+use super::descriptors::INTF_MAIN;
+
+/// Vendor request code for fetching the MS OS 2.0 descriptor set.
+pub const MS_VENDOR_CODE: u8 = 0x4d;
+
+/// wIndex for the descriptor set request.
+pub const MS_OS_20_DESCRIPTOR_INDEX: u16 = 7;
+
+/// Descriptor type for the BOS.
+pub const TYPE_BOS: u8 = 15;
+
+/// Windows 8.1, the minimum version for MS OS 2.0 descriptors.
+const WINDOWS_VERSION: u32 = 0x06030000;
+
+/// The device interface GUID for the command interface.
+const INTERFACE_GUID: &str = "{5a6c2f1e-8b3d-4f47-9c1a-3e7b5d2c9a41}";
+
+const PROPERTY_NAME: &str = "DeviceInterfaceGUIDs";
+
+/// Property name and data lengths, as UTF-16 with terminators.  The data is a
+/// REG_MULTI_SZ, so has a second terminator.
+const NAME_LEN: usize = PROPERTY_NAME.len() * 2 + 2;
+const DATA_LEN: usize = INTERFACE_GUID.len() * 2 + 4;
+
+const REGISTRY_LEN: usize = 10 + NAME_LEN + DATA_LEN;
+const FUNCTION_LEN: usize = 8 + 20 + REGISTRY_LEN;
+const CONFIG_LEN  : usize = 8 + FUNCTION_LEN;
+pub const SET_LEN : usize = 10 + CONFIG_LEN;
+
+const BOS_LEN: usize = 5 + 28;
+
+/// The MS OS 2.0 platform capability UUID,
+/// {D8DD60DF-4589-4CC7-9CD2-659D9E648A9F}, in wire order.
+const PLATFORM_UUID: [u8; 16] = [
+    0xdf, 0x60, 0xdd, 0xd8, 0x89, 0x45, 0xc7, 0x4c,
+    0x9c, 0xd2, 0x65, 0x9d, 0x9e, 0x64, 0x8a, 0x9f];
+
+/// Accumulate a descriptor blob at compile time.
+struct Blob<const N: usize> {
+    data: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Blob<N> {
+    const fn new() -> Self {Blob{data: [0; N], len: 0}}
+    const fn u8(mut self, b: u8) -> Self {
+        self.data[self.len] = b;
+        self.len += 1;
+        self
+    }
+    const fn u16(self, v: u16) -> Self {
+        self.u8(v as u8).u8((v >> 8) as u8)
+    }
+    const fn u32(self, v: u32) -> Self {
+        self.u16(v as u16).u16((v >> 16) as u16)
+    }
+    const fn bytes(mut self, b: &[u8]) -> Self {
+        let mut i = 0;
+        while i < b.len() {
+            self = self.u8(b[i]);
+            i += 1;
+        }
+        self
+    }
+    /// ASCII string as UTF-16, with no terminator.
+    const fn utf16(mut self, s: &str) -> Self {
+        let b = s.as_bytes();
+        let mut i = 0;
+        while i < b.len() {
+            self = self.u16(b[i] as u16);
+            i += 1;
+        }
+        self
+    }
+    const fn done(self) -> [u8; N] {
+        assert!(self.len == N);
+        self.data
+    }
+}
+
+pub static BOS_DESC: [u8; BOS_LEN] = Blob::new()
+    // BOS header.
+    .u8(5).u8(TYPE_BOS).u16(BOS_LEN as u16).u8(1)
+    // Platform capability.
+    .u8(28).u8(16).u8(5).u8(0).bytes(&PLATFORM_UUID)
+    .u32(WINDOWS_VERSION).u16(SET_LEN as u16).u8(MS_VENDOR_CODE).u8(0)
+    .done();
+
+/// The BOS without the platform capability, for serial only mode, where there
+/// is no command interface for WinUSB to bind to.
+pub static BOS_EMPTY_DESC: [u8; 5] = Blob::new()
+    .u8(5).u8(TYPE_BOS).u16(5).u8(0)
+    .done();
+
+/// The descriptor set, for the command interface with the given number.
+const fn descriptor_set(interface: u8) -> [u8; SET_LEN] {
+    Blob::new()
+        // Set header.
+        .u16(10).u16(0).u32(WINDOWS_VERSION).u16(SET_LEN as u16)
+        // Configuration subset header.
+        .u16(8).u16(1).u8(0).u8(0).u16(CONFIG_LEN as u16)
+        // Function subset header.
+        .u16(8).u16(2).u8(interface).u8(0).u16(FUNCTION_LEN as u16)
+        // Compatible ID.
+        .u16(20).u16(3).bytes(b"WINUSB\0\0").bytes(&[0; 8])
+        // Registry property, REG_MULTI_SZ.
+        .u16(REGISTRY_LEN as u16).u16(4).u16(7)
+        .u16(NAME_LEN as u16).utf16(PROPERTY_NAME).u16(0)
+        .u16(DATA_LEN as u16).utf16(INTERFACE_GUID).u16(0).u16(0)
+        .done()
+}
+
+pub static FULL_SET: [u8; SET_LEN] = descriptor_set(INTF_MAIN);
+/// In command only mode, the command interface is first.
+pub static COMMAND_SET: [u8; SET_LEN] = descriptor_set(0);
+
+#[test]
+fn test_lengths() {
+    let get16 = |d: &[u8], i: usize| u16::from_le_bytes([d[i], d[i+1]]) as usize;
+    let set = &FULL_SET;
+    assert_eq!(get16(set, 8), set.len());
+    // Walk the descriptors, checking each length, and that the subset
+    // lengths cover the rest of the set.
+    assert_eq!(get16(set, 10 + 6), set.len() - 10);
+    assert_eq!(get16(set, 18 + 6), set.len() - 18);
+    assert_eq!(set[18 + 4], INTF_MAIN);
+    let mut i = 0;
+    let mut types = Vec::new();
+    while i < set.len() {
+        types.push(get16(set, i + 2));
+        i += get16(set, i);
+    }
+    assert_eq!(i, set.len());
+    assert_eq!(types, [0, 1, 2, 3, 4]);
+    assert_eq!(set.len(), 178);
+
+    assert_eq!(BOS_DESC.len(), get16(&BOS_DESC, 2));
+    assert_eq!(get16(&BOS_DESC, 5 + 24), set.len());
+    assert_eq!(BOS_EMPTY_DESC.len(), get16(&BOS_EMPTY_DESC, 2));
+    assert_eq!(BOS_EMPTY_DESC[4], 0);
+}
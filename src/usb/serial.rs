@@ -10,10 +10,13 @@ use super::descriptors::{INTF_ACM_DATA, INTF_ACM_INTR};
 
 use super::USB_STATE;
 
+use crate::cpu::Priority;
+use crate::cpu::interrupt::PRIO_COMMS;
+
 use usb::EndpointPair;
 use usb::types::{LineCoding, SetupHeader, SetupResult};
 use usb::hardware::{
-    CTRL_RX_BUF, CheprR, CheprReader, CheprWriter,
+    CheprR, CheprReader, CheprWriter,
     chep_bd_len, chep_bd_ptr, chep_bd_tx, copy_by_dest32};
 
 macro_rules!srx_dbgln  {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
@@ -22,12 +25,43 @@ macro_rules!fast_dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
 macro_rules!intr_dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
 macro_rules!ctrl_dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
 
+/// Count of bytes dropped by `serial_tx_byte` because the buffer was full.
+static TX_DROPS: VCell<u32> = VCell::new(0);
+
 /// Operating systems appear to think that changing baud rates on serial ports
 /// at random is fine.  It is not.  So we ignore the CDC ACM baud rate and do
 /// our own thing.  But we still fake baud rate responses just to keep random
 /// OSes happy.
 static FAKE_BAUD: VCell<u32> = VCell::new(9600);
 
+/// If set, the CDC ACM baud rate is applied to the GPS UART after all.  Off by
+/// default, see `FAKE_BAUD`.
+static FOLLOW_HOST_BAUD: VCell<bool> = VCell::new(false);
+
+/// A CDC SEND_BREAK shorter than this (in ms) just sends a UART break
+/// character.  Longer breaks hold the GPS in reset for the duration.
+const BREAK_RESET_MIN: u16 = 10;
+
+/// SEND_BREAK duration meaning "until cleared by a zero duration".
+const BREAK_HOLD: u16 = 0xffff;
+
+/// Uptime (ms) at which a timed GPS reset, from a break or DTR, ends.  Zero if
+/// none, `u64::MAX` if held until ended.
+static RESET_UNTIL: VCell<u64> = VCell::new(0);
+
+/// SET_CONTROL_LINE_STATE DTR bit.  (RTS, bit 1, is ignored.)
+const LINE_DTR: u8 = 1;
+
+/// Duration (ms) of the GPS reset pulse on DTR assertion.
+const DTR_RESET_MS: u16 = 10;
+
+/// If set, asserting DTR resets the GPS.  Off by default, as many programs
+/// assert DTR on open.
+static DTR_RESET: VCell<bool> = VCell::new(false);
+
+/// The last control line state from the host.
+static LINE_STATE: VCell<u8> = VCell::new(0);
+
 /// Status of processing received CDC ACM serial data.
 #[derive(PartialEq)]
 enum RxProcessing {
@@ -61,6 +95,22 @@ pub fn serial_tx_byte(byte: u8) {
     unsafe{USB_STATE.as_mut()}.ep1.serial_tx_byte(byte);
 }
 
+/// Count of bytes dropped by serial_tx_byte() because it was full.
+pub fn serial_tx_drops() -> u32 {TX_DROPS.read()}
+
+/// Number of bytes that serial_tx_byte() can take without dropping data, or
+/// overwriting a buffer still in flight.
+pub fn serial_tx_space() -> usize {
+    let tx_len = USB_STATE.as_ref().ep1.tx_len;
+    if chep_ser().read().tx_active() {
+        // Completing this buffer would arm it behind the active one.
+        63usize.saturating_sub(tx_len)
+    }
+    else {
+        64usize.saturating_sub(tx_len)
+    }
+}
+
 impl usb::EndpointPair for FreakUSBSerial {
     fn start_of_frame(&mut self) {
         // If serial TX is idle, then push through any pending data.
@@ -140,7 +190,10 @@ impl usb::EndpointPair for FreakUSBSerial {
         // I believe that the requests should come for INTF_ACM_INTR, but we
         // also support the DATA interface because I can't see it in the spec
         // and I have no confidence people will agree with me.
-        h.index == INTF_ACM_INTR as u16 || h.index == INTF_ACM_DATA as u16
+        // Only interface requests; endpoint requests with the same index are
+        // for the endpoint halt handling.
+        super::get_mode().has_serial() && h.request_type & 0x1f == 1 &&
+            (h.index == INTF_ACM_INTR as u16 || h.index == INTF_ACM_DATA as u16)
     }
 
     fn setup_handler(&mut self, setup: &SetupHeader) -> SetupResult {
@@ -151,6 +204,8 @@ impl usb::EndpointPair for FreakUSBSerial {
 
             // We could flush buffers on a transition from line-down to line-up.
             (0x21, 0x22) => set_control_line_state(setup.value_lo),
+            (0x21, 0x23) => send_break(
+                setup.value_lo as u16 | (setup.value_hi as u16) << 8),
             _ => SetupResult::error(),
         }
     }
@@ -197,6 +252,7 @@ impl FreakUSBSerial {
     pub fn serial_tx_byte(&mut self, byte: u8) {
         fast_dbgln!("serial_tx_byte {byte:02x}");
         if self.tx_len >= 64 {
+            TX_DROPS.write(TX_DROPS.read().wrapping_add(1));
             return;                     // We're full.  Drop it.
         }
         self.tx_part = (self.tx_part >> 8) + ((byte as u32) << 24);
@@ -234,11 +290,69 @@ impl FreakUSBSerial {
     }
 }
 
-fn set_control_line_state(_value: u8) -> SetupResult {
+/// CDC SET_CONTROL_LINE_STATE.  The low byte of the value has DTR in bit 0
+/// and RTS in bit 1; the other bits are reserved.  If enabled by
+/// `set_dtr_reset`, a rising edge on DTR pulses the GPS reset.
+fn set_control_line_state(value: u8) -> SetupResult {
+    let previous = LINE_STATE.read();
+    LINE_STATE.write(value);
+    if DTR_RESET.read() && value & !previous & LINE_DTR != 0 {
+        start_reset(DTR_RESET_MS);
+    }
     usb_tx_interrupt();
     SetupResult::no_data()
 }
 
+/// Enable or disable GPS reset on DTR assertion.
+pub fn set_dtr_reset(enable: bool) {
+    DTR_RESET.write(enable);
+}
+
+pub fn get_dtr_reset() -> bool {DTR_RESET.read()}
+
+/// CDC SEND_BREAK.  The value is the duration in ms: zero ends a break,
+/// 0xffff holds the break until ended, and anything else is timed.  Short
+/// breaks send a UART break character, longer ones reset the GPS.
+fn send_break(duration: u16) -> SetupResult {
+    ctrl_dbgln!("USB Send Break, {duration}ms");
+    if duration == 0 {
+        if RESET_UNTIL.read() != 0 {
+            RESET_UNTIL.write(0);
+            crate::gps_uart::set_reset(false);
+        }
+    }
+    else if duration < BREAK_RESET_MIN {
+        crate::gps_uart::send_break();
+    }
+    else {
+        start_reset(duration);
+    }
+    SetupResult::no_data()
+}
+
+/// Hold the GPS in reset for a number of ms, or indefinitely for BREAK_HOLD.
+/// A reset already held indefinitely is left alone.  Call at PRIO_COMMS.
+fn start_reset(ms: u16) {
+    if RESET_UNTIL.read() != u64::MAX {
+        RESET_UNTIL.write(if ms == BREAK_HOLD {u64::MAX}
+                          else {crate::tick::uptime_ms() + ms as u64});
+    }
+    crate::gps_uart::set_reset(true);
+}
+
+/// End a timed reset once it is due.  Run from the tick, so that it does not
+/// depend on the host sending SOFs.
+pub fn reset_tick() {
+    let now = crate::tick::uptime_ms();
+    let _prio = Priority::<PRIO_COMMS>::default();
+    let until = RESET_UNTIL.read();
+    if until == 0 || until == u64::MAX || now < until {
+        return;
+    }
+    RESET_UNTIL.write(0);
+    crate::gps_uart::set_reset(false);
+}
+
 fn usb_tx_interrupt() {
     intr_dbgln!("Sending USB interrupt");
     // Just send a canned response, because USB sucks.  We don't care if one
@@ -263,16 +377,26 @@ fn usb_tx_interrupt() {
 }
 
 fn set_line_coding() -> bool {
-    let line_coding: LineCoding = unsafe {
-        core::mem::transmute_copy (
-            &* (CTRL_RX_BUF as *const (u32, u32))
-        )
-    };
+    let line_coding: LineCoding = super::ctrl_rx_data();
     ctrl_dbgln!("USB Set Line Coding, Baud = {}", line_coding.dte_rate);
+    if FOLLOW_HOST_BAUD.read()
+        && !crate::gps_uart::set_baud_rate(line_coding.dte_rate) {
+        return false;
+    }
     FAKE_BAUD.write(line_coding.dte_rate);
     true
 }
 
+/// The baud rate last set by the host via CDC ACM.
+pub fn host_baud() -> u32 {FAKE_BAUD.read()}
+
+/// Enable or disable applying the host's CDC ACM baud rate to the GPS UART.
+pub fn set_follow_host_baud(enable: bool) {
+    FOLLOW_HOST_BAUD.write(enable);
+}
+
+pub fn get_follow_host_baud() -> bool {FOLLOW_HOST_BAUD.read()}
+
 fn get_line_coding() -> SetupResult {
     ctrl_dbgln!("USB Get Line Coding");
     static LINE_CODING: UCell<LineCoding> = Default::default();
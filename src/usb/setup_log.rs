@@ -0,0 +1,70 @@
+//! Diagnostic capture of USB control SETUP packets.
+//!
+//! When enabled, the USB ISR snoops each SETUP packet arriving on the control
+//! endpoint before the control handling sees it, and records it in a small
+//! ring buffer.  When disabled, the cost is a single flag check.
+
+use stm_common::usb;
+use stm_common::vcell::{UCell, VCell};
+
+use usb::hardware::{chep_bd, chep_bd_ptr, chep_ref};
+
+use crate::cpu::Priority;
+use crate::cpu::interrupt::PRIO_COMMS;
+
+/// Number of SETUP packets retained.
+pub const LOG_SIZE: usize = 7;
+
+static ENABLED: VCell<bool> = VCell::new(false);
+
+struct Log {
+    /// Total number of packets captured since enabled.
+    count: usize,
+    /// Raw SETUP packets, indexed by count modulo LOG_SIZE.
+    setups: [[u8; 8]; LOG_SIZE],
+}
+
+static LOG: UCell<Log> = UCell::new(Log{count: 0, setups: [[0; 8]; LOG_SIZE]});
+
+/// Enable or disable capture.  Enabling clears the log.
+pub fn enable(enable: bool) {
+    let _prio = Priority::<PRIO_COMMS>::default();
+    if enable {
+        unsafe {LOG.as_mut()}.count = 0;
+    }
+    ENABLED.write(enable);
+}
+
+pub fn enabled() -> bool {ENABLED.read()}
+
+/// Check the control endpoint for a SETUP, and log it.  Call from the USB ISR
+/// before the normal processing.
+pub fn snoop() {
+    if !ENABLED.read() {
+        return;
+    }
+    let chep = chep_ref(0).read();
+    if !chep.VTRX().bit() || !chep.SETUP().bit() {
+        return;
+    }
+    // Observe the USB errata delay before reading the buffer.
+    super::errata_delay();
+    let src = chep_bd_ptr(chep_bd()[0].rx.read()) as *const u32;
+    let words = unsafe {[src.read_volatile(), src.add(1).read_volatile()]};
+    let log = unsafe {LOG.as_mut()};
+    let slot = &mut log.setups[log.count % LOG_SIZE];
+    slot[..4].copy_from_slice(&words[0].to_le_bytes());
+    slot[4..].copy_from_slice(&words[1].to_le_bytes());
+    log.count += 1;
+}
+
+/// Copy out the logged packets, oldest first.  Returns the number copied.
+pub fn get(out: &mut [[u8; 8]; LOG_SIZE]) -> usize {
+    let _prio = Priority::<PRIO_COMMS>::default();
+    let log = LOG.as_ref();
+    let num = log.count.min(LOG_SIZE);
+    for (i, o) in out[..num].iter_mut().enumerate() {
+        *o = log.setups[(log.count - num + i) % LOG_SIZE];
+    }
+    num
+}
@@ -0,0 +1,78 @@
+//! USB start-of-frame interval measurement.
+//!
+//! The host sends a SOF every 1ms, so timing consecutive SOFs against the CPU
+//! cycle counter checks our core clock against the host's.  Intervals where
+//! the frame number does not advance by exactly one (missed or delayed
+//! interrupts, suspend, bus reset) are discarded.
+
+use stm_common::vcell::UCell;
+
+use crate::cpu::Priority;
+use crate::cpu::interrupt::PRIO_COMMS;
+
+/// Number of SOF intervals in a measurement window, 1 second.
+const WINDOW: u32 = 1000;
+
+#[derive(Clone, Copy)]
+#[derive_const(Default)]
+struct Stats {
+    /// Number of intervals.
+    count: u32,
+    /// Total of the intervals, in CPU cycles.
+    total: u32,
+    /// Shortest interval.
+    min: u32,
+    /// Longest interval.
+    max: u32,
+}
+
+struct State {
+    /// Cycle counter at the last SOF.
+    last_cycles: u32,
+    /// Frame number at the last SOF, or !0 if none.
+    last_frame: u32,
+    /// The window being accumulated.
+    current: Stats,
+    /// The last complete window.
+    complete: Stats,
+}
+
+static STATE: UCell<State> = UCell::new(State{
+    last_cycles: 0, last_frame: !0,
+    current: Default::default(), complete: Default::default()});
+
+/// Record a SOF.  Call from the USB ISR.  The cycle counter is started by
+/// `tick::init`.
+pub fn note_sof(frame: u32) {
+    let cycles = crate::tick::cycles();
+    let state = unsafe {STATE.as_mut()};
+    let consecutive
+        = state.last_frame < 0x800 && frame == (state.last_frame + 1) & 0x7ff;
+    let interval = cycles.wrapping_sub(state.last_cycles);
+    state.last_cycles = cycles;
+    state.last_frame = frame;
+    if !consecutive {
+        return;
+    }
+    let current = &mut state.current;
+    if current.count == 0 {
+        current.min = interval;
+        current.max = interval;
+    }
+    current.count += 1;
+    current.total = current.total.wrapping_add(interval);
+    current.min = current.min.min(interval);
+    current.max = current.max.max(interval);
+    if current.count >= WINDOW {
+        state.complete = *current;
+        *current = Stats::default();
+    }
+}
+
+/// Return the last complete window: [count, total cycles, min, max].  The
+/// count is zero if no window has completed.
+pub fn get_stats() -> [u32; 4] {
+    let _prio = Priority::<PRIO_COMMS>::default();
+    let s = STATE.as_ref().complete;
+    [s.count, s.total, s.min, s.max]
+}
@@ -0,0 +1,102 @@
+//! USB remote wakeup.
+//!
+//! The configuration descriptors advertise remote wakeup.  If the host enables
+//! it with SET_FEATURE(DEVICE_REMOTE_WAKEUP) before suspending the bus, then
+//! `request` signals resume to wake the host, e.g., on loss of clock lock.  The
+//! host may disable it again with CLEAR_FEATURE, and a bus reset disables it.
+//!
+//! We only signal resume after the suspend interrupt, and until the next SOF.
+//! The resume signalling is timed by counting the expected-SOF interrupts,
+//! which keep coming once a millisecond while the bus is idle, so that it is
+//! ended from the USB ISR rather than by spinning.
+
+use stm_common::usb::types::{SetupHeader, SetupResult};
+use stm_common::vcell::VCell;
+
+use crate::cpu::Priority;
+use crate::cpu::interrupt::PRIO_COMMS;
+
+/// Feature selector for DEVICE_REMOTE_WAKEUP.
+const DEVICE_REMOTE_WAKEUP: u16 = 1;
+
+/// Duration of the resume signalling, in ESOFs, which must be 1 to 15ms.  The
+/// first ESOF may come early, so this gives 4 to 5ms.
+const RESUME_MS: u8 = 5;
+
+/// Has the host enabled remote wakeup?
+static ENABLED: VCell<bool> = VCell::new(false);
+
+/// Has the bus been suspended, and not yet resumed?
+static SUSPENDED: VCell<bool> = VCell::new(false);
+
+/// ESOFs remaining of the resume signalling, or zero if not resuming.
+static RESUMING: VCell<u8> = VCell::new(0);
+
+macro_rules!dbgln {($($tt:tt)*) => {if false {crate::dbgln!($($tt)*)}};}
+
+pub fn setup_wanted(setup: &SetupHeader) -> bool {
+    let value = setup.value_lo as u16 | (setup.value_hi as u16) << 8;
+    // Device recipient SET_FEATURE and CLEAR_FEATURE.
+    setup.request_type == 0x00 && matches!(setup.request, 0x01 | 0x03)
+        && value == DEVICE_REMOTE_WAKEUP
+}
+
+pub fn setup_handler(setup: &SetupHeader) -> SetupResult {
+    ENABLED.write(setup.request == 0x03);
+    dbgln!("Remote wakeup enabled {}", ENABLED.read());
+    SetupResult::no_data()
+}
+
+/// Has the host enabled remote wakeup?
+pub fn enabled() -> bool {ENABLED.read()}
+
+/// Bus reset, the host must enable remote wakeup afresh.
+pub fn initialize() {
+    ENABLED.write(false);
+    SUSPENDED.write(false);
+}
+
+/// Enable the suspend interrupt.  Call after the USB core is initialized.
+pub fn init() {
+    let usb = unsafe {&*stm32h503::USB::ptr()};
+    let _prio = Priority::<PRIO_COMMS>::default();
+    usb.CNTR.modify(|_,w| w.SUSPM().set_bit());
+}
+
+/// Track suspend and resume, and time the resume signalling.  Call from the
+/// USB ISR before the normal processing.
+pub fn isr() {
+    let usb = unsafe {&*stm32h503::USB::ptr()};
+    let istr = usb.ISTR.read();
+    if istr.SUSP().bit() {
+        dbgln!("Suspend");
+        SUSPENDED.write(true);
+        usb.ISTR.write(|w| w.bits(!0).SUSP().clear_bit());
+    }
+    if istr.SOF().bit() {
+        SUSPENDED.write(false);
+    }
+    if istr.ESOF().bit() && RESUMING.read() != 0 {
+        usb.ISTR.write(|w| w.bits(!0).ESOF().clear_bit());
+        let remaining = RESUMING.read() - 1;
+        RESUMING.write(remaining);
+        if remaining == 0 {
+            usb.CNTR.modify(|_,w| w.L2RES().clear_bit().ESOFM().clear_bit());
+        }
+    }
+}
+
+/// Wake the host, if it is suspended and has enabled remote wakeup.  The
+/// resume signalling is ended from the USB ISR.
+pub fn request() {
+    let usb = unsafe {&*stm32h503::USB::ptr()};
+    let _prio = Priority::<PRIO_COMMS>::default();
+    if !ENABLED.read() || !SUSPENDED.read() || RESUMING.read() != 0 {
+        return;
+    }
+    dbgln!("Remote wakeup");
+    usb.ISTR.write(|w| w.bits(!0).ESOF().clear_bit());
+    RESUMING.write(RESUME_MS);
+    usb.CNTR.modify(
+        |_,w| w.SUSPEN().clear_bit().L2RES().set_bit().ESOFM().set_bit());
+}
@@ -0,0 +1,97 @@
+//! Zero length packet termination of control IN transfers.
+//!
+//! A control IN data stage that is shorter than the wLength requested must end
+//! with a short packet.  When the data is an exact multiple of the 64 byte
+//! packet size, that final packet has to be a zero length packet, else the host
+//! waits for more data and the transfer times out.  The control handling in
+//! the USB core does not always send it, so we follow EP0 from the USB ISR, and
+//! send the ZLP ourselves when the core leaves the TX idle after a full packet.
+
+use stm_common::usb;
+use stm_common::vcell::UCell;
+
+use crate::usb::{CheprWriter as _};
+
+use usb::hardware::{
+    CheprReader, CheprWriter, chep_bd, chep_bd_len, chep_bd_ptr, chep_ref};
+
+struct State {
+    /// wLength of the current control IN request, zero if none.
+    length: u32,
+    /// Bytes sent in the data stage so far.
+    sent: u32,
+    /// Size of the last packet sent in the data stage.
+    last: u32,
+    /// A TX completion was seen in this ISR invocation.
+    tx_done: bool,
+    /// We have queued a ZLP that the USB core knows nothing about.
+    zlp: bool,
+}
+
+static STATE: UCell<State> = UCell::new(State{
+    length: 0, sent: 0, last: 0, tx_done: false, zlp: false});
+
+/// Does a data stage of `sent` bytes, ending with a packet of `last` bytes,
+/// need a terminating ZLP for a request of `length` bytes?
+fn zlp_needed(length: u32, sent: u32, last: u32) -> bool {
+    last == 64 && sent < length
+}
+
+/// Note SETUP and TX completion on EP0.  Call from the USB ISR before the
+/// normal processing.
+pub fn before_isr() {
+    let state = unsafe {STATE.as_mut()};
+    state.tx_done = false;
+    let chep = chep_ref(0).read();
+    if chep.VTRX().bit() && chep.SETUP().bit() {
+        // Observe the USB errata delay before reading the buffer.
+        super::errata_delay();
+        let src = chep_bd_ptr(chep_bd()[0].rx.read()) as *const u32;
+        let words = unsafe {[src.read_volatile(), src.add(1).read_volatile()]};
+        // bmRequestType is the first byte, wLength the last two.
+        state.length = if words[0] & 0x80 != 0 {words[1] >> 16} else {0};
+        state.sent = 0;
+        state.last = 0;
+        state.zlp = false;
+    }
+    if !chep.VTTX().bit() {
+        return;
+    }
+    if state.zlp {
+        // Our ZLP has gone; the core is already waiting for the status stage.
+        state.zlp = false;
+        chep_ref(0).write(|w| w.control().VTTX().clear_bit());
+        return;
+    }
+    state.last = chep_bd_len(chep_bd()[0].tx.read()) as u32;
+    state.sent += state.last;
+    state.tx_done = true;
+}
+
+/// Queue a ZLP if the USB core finished a data stage on a full packet.  Call
+/// from the USB ISR after the normal processing.
+pub fn after_isr() {
+    let state = unsafe {STATE.as_mut()};
+    if !state.tx_done || !zlp_needed(state.length, state.sent, state.last) {
+        return;
+    }
+    let chep = chep_ref(0).read();
+    if !chep.tx_nakking() {
+        return;                         // More data queued, or stalled.
+    }
+    // Keep the buffer address, with a zero count.
+    let bd = &chep_bd()[0].tx;
+    bd.write(bd.read() & 0xffff);
+    chep_ref(0).write(|w| w.control().tx_valid(&chep));
+    state.length = 0;
+    state.zlp = true;
+}
+
+#[test]
+fn test_zlp_needed() {
+    assert!( zlp_needed(255, 64, 64));
+    assert!( zlp_needed(255, 128, 64));
+    assert!(!zlp_needed(64, 64, 64));
+    assert!(!zlp_needed(255, 70, 6));
+    assert!(!zlp_needed(0, 64, 64));
+}
@@ -1,10 +1,16 @@
 use core::ptr::{read_volatile, write_volatile};
 
+/// The largest access width, 4, 2 or 1 bytes, compatible with the bitwise OR
+/// of the addresses and length.
+pub fn access_width(mix: usize) -> usize {
+    if mix & 3 == 0 {4} else if mix & 1 == 0 {2} else {1}
+}
+
 /// Like memcpy(), but guarentee using the largest of u32, u16 or u8 compatible
 /// with the alignment and length.
 pub unsafe fn vcopy_aligned(dest: *mut u8, src: *const u8, length: usize) {
-    let mix = dest as usize | src as usize | length;
-    if mix & 3 == 0 {
+    let width = access_width(dest as usize | src as usize | length);
+    if width == 4 {
         let dest = dest as *mut u32;
         let src = src as *mut u32;
         for i in (0..length).step_by(4) {
@@ -12,7 +18,7 @@ pub unsafe fn vcopy_aligned(dest: *mut u8, src: *const u8, length: usize) {
                                    read_volatile(src.wrapping_byte_add(i)))};
         }
     }
-    else if mix & 1 == 0 {
+    else if width == 2 {
         let dest = dest as *mut u16;
         let src = src as *mut u16;
         for i in (0..length).step_by(2) {
@@ -27,3 +33,46 @@ pub unsafe fn vcopy_aligned(dest: *mut u8, src: *const u8, length: usize) {
         }
     }
 }
+
+/// Fill memory with a repeating 32-bit pattern, using the largest of u32, u16
+/// or u8 compatible with the alignment and length.  The pattern is aligned to
+/// the address: a byte at address `a` receives `pattern >> 8 * (a & 3)`.
+pub unsafe fn vfill_aligned(dest: *mut u8, pattern: u32, length: usize) {
+    let width = access_width(dest as usize | length);
+    for i in (0 .. length).step_by(width) {
+        let p = dest.wrapping_add(i);
+        let v = pattern >> 8 * (p as usize & 3);
+        match width {
+            4 => unsafe {write_volatile(p as *mut u32, v)},
+            2 => unsafe {write_volatile(p as *mut u16, v as u16)},
+            _ => unsafe {write_volatile(p, v as u8)},
+        }
+    }
+}
+
+#[test]
+fn test_access_width() {
+    assert_eq!(access_width(0x20000000 | 64), 4);
+    assert_eq!(access_width(0x20000002 | 64), 2);
+    assert_eq!(access_width(0x20000000 | 6), 2);
+    assert_eq!(access_width(0x20000001 | 64), 1);
+    assert_eq!(access_width(0x20000000 | 7), 1);
+}
+
+#[test]
+fn test_vfill() {
+    let pattern = 0x44332211;
+    for start in 0 .. 4 {
+        for length in 0 .. 12 {
+            let mut buffer = [0u32; 5];
+            let bytes = &mut buffer as *mut u32 as *mut u8;
+            unsafe {vfill_aligned(bytes.wrapping_add(start), pattern, length)};
+            let bytes: [u8; 20] = unsafe {core::mem::transmute(buffer)};
+            for (i, &b) in bytes.iter().enumerate() {
+                let expect = if i >= start && i < start + length {
+                    (i % 4 + 1) as u8 * 0x11} else {0};
+                assert_eq!(b, expect, "{start} {length} {i}");
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Independent watchdog.
+//!
+//! The IWDG runs from the 32kHz LSI, and resets the CPU unless refreshed
+//! within `TIMEOUT_MS`.  The refresh is done from the main loop, which runs
+//! below every interrupt priority, so a hung interrupt handler (or a command
+//! stuck waiting on I²C or USB) starves it and causes a reset.  The main loop
+//! is woken at least every SysTick, which is much faster than the timeout.
+//!
+//! Refreshing from the SysTick would not help with long commands, as the tick
+//! runs at the same priority as the command handler.  Instead, every command
+//! that can block is bounded well below `TIMEOUT_MS` (e.g., GPS waits of at
+//! most 1.5s, peek dumps abort after a timeout).  GPS UART TX waits are
+//! bounded by the time to send at the current baud rate, see
+//! `gps_uart::tx_time`, so a stuck UART or DMA gives an error rather than
+//! blocking the main loop indefinitely.
+//!
+//! The watchdog is started after start-up provisioning, as sending a long
+//! config to the GPS may legitimately take a while.  Once started, it cannot
+//! be stopped except by a reset.
+//!
+//! Before entering the ROM bootloader for DFU, the watchdog is started with a
+//! much longer timeout, `DFU_TIMEOUT_MS`.  The bootloader does not refresh it,
+//! so an abandoned DFU session ends in a reset back to the application.
+
+use stm_common::vcell::VCell;
+
+/// Watchdog timeout in milliseconds.
+pub const TIMEOUT_MS: u32 = 2000;
+
+/// LSI clock frequency.
+const LSI_FREQ: u32 = 32000;
+
+/// Prescaler setting: PR = 3 divides by 32, giving 1ms per count.
+const PRESCALE: u8 = 3;
+const COUNT_FREQ: u32 = LSI_FREQ / (4 << PRESCALE);
+
+/// Reload value.  The counter is 12 bits.
+const RELOAD: u16 = reload(TIMEOUT_MS, COUNT_FREQ);
+
+/// Timeout for a ROM bootloader DFU session, in milliseconds.
+pub const DFU_TIMEOUT_MS: u32 = 30000;
+
+/// Prescaler setting for DFU: PR = 6 divides by 256, giving 8ms per count.
+const DFU_PRESCALE: u8 = 6;
+const DFU_COUNT_FREQ: u32 = LSI_FREQ / (4 << DFU_PRESCALE);
+const DFU_RELOAD: u16 = reload(DFU_TIMEOUT_MS, DFU_COUNT_FREQ);
+
+const fn reload(timeout_ms: u32, count_freq: u32) -> u16 {
+    let reload = timeout_ms * count_freq / 1000 - 1;
+    assert!(reload <= 0xfff);
+    reload as u16
+}
+
+const KEY_START  : u16 = 0xcccc;
+const KEY_ACCESS : u16 = 0x5555;
+const KEY_REFRESH: u16 = 0xaaaa;
+
+/// Whether the last reset was caused by the IWDG.
+static WATCHDOG_RESET: VCell<bool> = VCell::new(false);
+
+/// Note whether the last reset was the watchdog.  Call before anything clears
+/// the RCC reset flags.
+pub fn note_reset_cause() {
+    let rcc = unsafe {&*stm32h503::RCC::ptr()};
+    WATCHDOG_RESET.write(rcc.RSR.read().IWDGRSTF().bit());
+}
+
+/// Start the watchdog.
+pub fn init() {start(PRESCALE, RELOAD)}
+
+/// Start the watchdog with the DFU session timeout.  Call just before entering
+/// the ROM bootloader.
+pub fn init_dfu() {start(DFU_PRESCALE, DFU_RELOAD)}
+
+fn start(prescale: u8, reload: u16) {
+    let iwdg = unsafe {&*stm32h503::IWDG::ptr()};
+    // Starting the IWDG also starts the LSI.
+    iwdg.KR.write(|w| w.KEY().bits(KEY_START));
+    iwdg.KR.write(|w| w.KEY().bits(KEY_ACCESS));
+    iwdg.PR.write(|w| w.PR().bits(prescale));
+    iwdg.RLR.write(|w| w.RL().bits(reload));
+    // Wait for the register updates to reach the LSI domain.  Only the update
+    // flags, PVU, RVU, WVU and EWU: ONF reads as 1 once the IWDG is running.
+    while iwdg.SR.read().bits() & 0xf != 0 {}
+    refresh();
+}
+
+/// Reload the watchdog counter.
+pub fn refresh() {
+    let iwdg = unsafe {&*stm32h503::IWDG::ptr()};
+    iwdg.KR.write(|w| w.KEY().bits(KEY_REFRESH));
+}
+
+/// Was the last reset caused by the watchdog?
+pub fn was_watchdog_reset() -> bool {WATCHDOG_RESET.read()}
+
+#[test]
+fn test_reload() {
+    assert_eq!(COUNT_FREQ, 1000);
+    assert_eq!(RELOAD as u32, TIMEOUT_MS - 1);
+    assert_eq!(DFU_COUNT_FREQ, 125);
+    assert_eq!((DFU_RELOAD as u32 + 1) * 8, DFU_TIMEOUT_MS);
+}